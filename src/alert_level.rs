@@ -0,0 +1,100 @@
+use std::time::Duration;
+use crate::battery_monitor::BatteryInfo;
+
+/// How urgently the battery needs charging
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum AlertLevel {
+	Normal,
+	Warning,
+	Critical,
+}
+
+/// Where the alert levels start. A level is reached by either the charge or the time left
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AlertThresholds {
+	pub warning_percent: i32,
+	pub critical_percent: i32,
+	pub warning_time: Duration,
+	pub critical_time: Duration,
+}
+
+/// The alert level for a battery sample. Only a battery that's losing charge raises an alert,
+/// so a weak charger still can
+pub fn alert_level(battery_info: &BatteryInfo, thresholds: &AlertThresholds) -> AlertLevel {
+	let draining = !battery_info.is_charging || battery_info.discharge_rate_percent > 0;
+	if !draining {
+		return AlertLevel::Normal;
+	}
+	// Charging time estimates come from a negative rate, and aren't time left
+	let time_left = battery_info.time_remaining().filter(|_| battery_info.discharge_rate_percent > 0);
+	let reached = |percent: i32, time: Duration| {
+		battery_info.percentage <= percent || time_left.is_some_and(|left| left <= time)
+	};
+	if reached(thresholds.critical_percent, thresholds.critical_time) {
+		AlertLevel::Critical
+	}
+	else if reached(thresholds.warning_percent, thresholds.warning_time) {
+		AlertLevel::Warning
+	}
+	else {
+		AlertLevel::Normal
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use starship_battery::State;
+
+	const THRESHOLDS: AlertThresholds = AlertThresholds {
+		warning_percent: 20,
+		critical_percent: 10,
+		warning_time: Duration::from_secs(30 * 60),
+		critical_time: Duration::from_secs(15 * 60),
+	};
+
+	fn level(percentage: i32, discharge_rate_percent: i32, is_charging: bool) -> AlertLevel {
+		let battery_info = BatteryInfo {
+			percentage,
+			discharge_rate_percent,
+			power_draw_watts: 0.0,
+			instant_power_draw_watts: 0.0,
+			is_charging,
+			state: if is_charging { State::Charging } else { State::Discharging },
+			health_percent: 100,
+			cycle_count: None,
+		};
+		alert_level(&battery_info, &THRESHOLDS)
+	}
+
+	#[test]
+	fn levels_follow_the_charge() {
+		assert_eq!(level(21, 0, false), AlertLevel::Normal);
+		assert_eq!(level(20, 0, false), AlertLevel::Warning);
+		assert_eq!(level(11, 0, false), AlertLevel::Warning);
+		// At or below both levels it's the more urgent one
+		assert_eq!(level(10, 0, false), AlertLevel::Critical);
+		assert_eq!(level(0, 0, false), AlertLevel::Critical);
+	}
+
+	#[test]
+	fn little_time_left_escalates_before_the_charge_does() {
+		// 15 minutes left is critical even though 20% is only a warning by charge
+		assert_eq!(level(20, 80, false), AlertLevel::Critical);
+		// 30 minutes left is a warning at any charge
+		assert_eq!(level(50, 100, false), AlertLevel::Warning);
+		assert_eq!(level(50, 10, false), AlertLevel::Normal);
+		// The charge still counts when there's plenty of time left
+		assert_eq!(level(10, 1, false), AlertLevel::Critical);
+	}
+
+	#[test]
+	fn charging_clears_the_alert_unless_still_draining() {
+		assert_eq!(level(5, 0, true), AlertLevel::Normal);
+		// The time to full isn't time left
+		assert_eq!(level(5, -400, true), AlertLevel::Normal);
+		// A weak charger that can't keep up still alerts
+		assert_eq!(level(5, 2, true), AlertLevel::Critical);
+		assert_eq!(level(50, 100, true), AlertLevel::Warning);
+	}
+}
@@ -0,0 +1,211 @@
+use std::path::{Path, PathBuf};
+
+/// Registers the app to start when the user logs in
+pub trait Autostart {
+	fn is_enabled(&self) -> Result<bool, String>;
+	fn enable(&self, exe: &Path) -> Result<(), String>;
+	fn disable(&self) -> Result<(), String>;
+
+	fn set_enabled(&self, enabled: bool) -> Result<(), String> {
+		if enabled {
+			let exe = std::env::current_exe().map_err(|e| format!("Couldn't find our executable: {:?}", e))?;
+			self.enable(&exe)
+		}
+		else {
+			self.disable()
+		}
+	}
+}
+
+/// The autostart mechanism for the platform we're running on
+pub fn platform_autostart() -> Result<Box<dyn Autostart>, String> {
+	#[cfg(target_os = "windows")]
+	return Ok(Box::new(RunKeyAutostart));
+
+	#[cfg(not(target_os = "windows"))]
+	return XdgAutostart::from_env().map(|a| Box::new(a) as Box<dyn Autostart>);
+}
+
+/// Windows autostart through a value under the user's Run registry key
+#[cfg(target_os = "windows")]
+pub struct RunKeyAutostart;
+
+#[cfg(target_os = "windows")]
+mod run_key {
+	use std::path::Path;
+	use windows_sys::Win32::Foundation::{ERROR_FILE_NOT_FOUND, ERROR_SUCCESS};
+	use windows_sys::Win32::System::Registry::{
+		RegCloseKey, RegDeleteValueW, RegOpenKeyExW, RegQueryValueExW, RegSetValueExW,
+		HKEY, HKEY_CURRENT_USER, KEY_QUERY_VALUE, KEY_SET_VALUE, REG_SAM_FLAGS, REG_SZ,
+	};
+	use super::{Autostart, RunKeyAutostart};
+
+	const RUN_KEY: &str = "Software\\Microsoft\\Windows\\CurrentVersion\\Run";
+	const VALUE_NAME: &str = "windows-tray-battery-percent";
+
+	fn wide(s: &str) -> Vec<u16> {
+		s.encode_utf16().chain(std::iter::once(0)).collect()
+	}
+
+	/// Open the Run key, closing it again when dropped
+	struct RunKey(HKEY);
+
+	impl RunKey {
+		fn open(access: REG_SAM_FLAGS) -> Result<Self, String> {
+			let mut hkey: HKEY = std::ptr::null_mut();
+			let result = unsafe { RegOpenKeyExW(HKEY_CURRENT_USER, wide(RUN_KEY).as_ptr(), 0, access, &mut hkey) };
+			if result != ERROR_SUCCESS {
+				return Err(format!("Failed to open Run key: error {}", result));
+			}
+			Ok(Self(hkey))
+		}
+	}
+
+	impl Drop for RunKey {
+		fn drop(&mut self) {
+			unsafe { RegCloseKey(self.0); }
+		}
+	}
+
+	impl Autostart for RunKeyAutostart {
+		fn is_enabled(&self) -> Result<bool, String> {
+			let key = RunKey::open(KEY_QUERY_VALUE)?;
+			let result = unsafe {
+				RegQueryValueExW(key.0, wide(VALUE_NAME).as_ptr(), std::ptr::null(), std::ptr::null_mut(), std::ptr::null_mut(), std::ptr::null_mut())
+			};
+			match result {
+				ERROR_SUCCESS => Ok(true),
+				ERROR_FILE_NOT_FOUND => Ok(false),
+				e => Err(format!("Failed to read Run key: error {}", e)),
+			}
+		}
+
+		fn enable(&self, exe: &Path) -> Result<(), String> {
+			let key = RunKey::open(KEY_SET_VALUE)?;
+			let command = wide(&format!("\"{}\"", exe.display()));
+			let result = unsafe {
+				RegSetValueExW(key.0, wide(VALUE_NAME).as_ptr(), 0, REG_SZ, command.as_ptr() as *const u8, (command.len() * 2) as u32)
+			};
+			if result != ERROR_SUCCESS {
+				return Err(format!("Failed to write Run key: error {}", result));
+			}
+			Ok(())
+		}
+
+		fn disable(&self) -> Result<(), String> {
+			let key = RunKey::open(KEY_SET_VALUE)?;
+			match unsafe { RegDeleteValueW(key.0, wide(VALUE_NAME).as_ptr()) } {
+				ERROR_SUCCESS | ERROR_FILE_NOT_FOUND => Ok(()),
+				e => Err(format!("Failed to remove Run key value: error {}", e)),
+			}
+		}
+	}
+}
+
+/// Linux autostart through a desktop entry in `$XDG_CONFIG_HOME/autostart`
+#[cfg(not(target_os = "windows"))]
+pub struct XdgAutostart {
+	desktop_file: PathBuf,
+}
+
+#[cfg(not(target_os = "windows"))]
+impl XdgAutostart {
+	const DESKTOP_FILE_NAME: &'static str = "windows-tray-battery-percent.desktop";
+
+	pub fn from_env() -> Result<Self, String> {
+		let config_home = std::env::var_os("XDG_CONFIG_HOME").map(PathBuf::from)
+			.or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))
+			.ok_or_else(|| "Neither XDG_CONFIG_HOME nor HOME is set".to_string())?;
+		Ok(Self::with_config_home(&config_home))
+	}
+
+	pub fn with_config_home(config_home: &Path) -> Self {
+		Self { desktop_file: config_home.join("autostart").join(Self::DESKTOP_FILE_NAME) }
+	}
+
+	pub fn desktop_file(&self) -> &Path {
+		&self.desktop_file
+	}
+}
+
+#[cfg(not(target_os = "windows"))]
+impl Autostart for XdgAutostart {
+	fn is_enabled(&self) -> Result<bool, String> {
+		Ok(self.desktop_file.exists())
+	}
+
+	fn enable(&self, exe: &Path) -> Result<(), String> {
+		if let Some(dir) = self.desktop_file.parent() {
+			std::fs::create_dir_all(dir).map_err(|e| format!("Failed to create {:?}: {:?}", dir, e))?;
+		}
+		std::fs::write(&self.desktop_file, desktop_entry(exe))
+			.map_err(|e| format!("Failed to write {:?}: {:?}", self.desktop_file, e))
+	}
+
+	fn disable(&self) -> Result<(), String> {
+		match std::fs::remove_file(&self.desktop_file) {
+			Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+				Err(format!("Failed to remove {:?}: {:?}", self.desktop_file, e))
+			}
+			_ => Ok(()),
+		}
+	}
+}
+
+#[cfg(not(target_os = "windows"))]
+fn desktop_entry(exe: &Path) -> String {
+	// Exec arguments are quoted, with the characters the spec reserves escaped by a backslash.
+	// The value is also a string, where a backslash is itself escaped, and % starts a field code
+	let mut exec = String::new();
+	for c in exe.display().to_string().chars() {
+		match c {
+			'"' | '`' | '$' => exec.push_str("\\\\"),
+			'\\' => exec.push_str("\\\\\\"),
+			'%' => exec.push('%'),
+			_ => {}
+		}
+		exec.push(c);
+	}
+
+	format!(
+		"[Desktop Entry]\n\
+		Type=Application\n\
+		Name=Battery Percentage\n\
+		Comment=Battery percentage tray icon\n\
+		Exec=\"{}\"\n\
+		Terminal=false\n\
+		X-GNOME-Autostart-enabled=true\n",
+		exec,
+	)
+}
+
+#[cfg(all(test, not(target_os = "windows")))]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn xdg_enable_and_disable() {
+		let config_home = std::env::temp_dir().join(format!("autostart-test-{}", std::process::id()));
+		let autostart = XdgAutostart::with_config_home(&config_home);
+		assert_eq!(autostart.is_enabled(), Ok(false));
+
+		autostart.set_enabled(true).unwrap();
+		assert_eq!(autostart.is_enabled(), Ok(true));
+		let entry = std::fs::read_to_string(autostart.desktop_file()).unwrap();
+		assert!(entry.starts_with("[Desktop Entry]\n"));
+		assert!(entry.contains("\nExec=\""));
+
+		autostart.disable().unwrap();
+		assert_eq!(autostart.is_enabled(), Ok(false));
+		// Disabling twice is fine
+		autostart.disable().unwrap();
+		std::fs::remove_dir_all(&config_home).unwrap();
+	}
+
+	#[test]
+	fn exec_is_quoted_and_escaped() {
+		let entry = desktop_entry(Path::new("/opt/my apps/100% \"battery\"/$bin\\tray"));
+		let exec = entry.lines().find(|line| line.starts_with("Exec=")).unwrap();
+		assert_eq!(exec, r#"Exec="/opt/my apps/100%% \\"battery\\"/\\$bin\\\\tray""#);
+	}
+}
@@ -0,0 +1,87 @@
+use starship_battery::State;
+use crate::battery_monitor::BatteryInfo;
+use crate::config::NamedSetting;
+
+pub const DEFAULT_LOW_BATTERY_PERCENT: i32 = 15;
+
+/// Changes in the battery worth reacting to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BatteryEvent {
+	PluggedIn,
+	Unplugged,
+	/// Dropped below the low battery level while unplugged
+	LowBattery,
+	/// Plugged in, but the charger can't keep up and the battery is still draining
+	WeakCharger,
+	ChargeComplete,
+}
+
+impl NamedSetting for BatteryEvent {
+	const NAMES: &'static [(Self, &'static str)] = &[
+		(BatteryEvent::PluggedIn, "plugged_in"),
+		(BatteryEvent::Unplugged, "unplugged"),
+		(BatteryEvent::LowBattery, "low_battery"),
+		(BatteryEvent::WeakCharger, "weak_charger"),
+		(BatteryEvent::ChargeComplete, "charge_complete"),
+	];
+}
+
+/// Events for the change from the previous sample to the current one. Each fires once when its
+/// condition starts to hold, and not again until it has stopped holding. The first sample only
+/// sets the starting state, so nothing fires just because the app started
+pub fn detect_events(previous: Option<&BatteryInfo>, current: &BatteryInfo, low_battery_percent: i32) -> Vec<BatteryEvent> {
+	let Some(previous) = previous else {
+		return Vec::new();
+	};
+	BatteryEvent::NAMES.iter()
+		.map(|(event, _)| *event)
+		.filter(|event| {
+			let holds = |info: &BatteryInfo| condition_holds(*event, info, low_battery_percent);
+			holds(current) && !holds(previous)
+		})
+		.collect()
+}
+
+fn condition_holds(event: BatteryEvent, info: &BatteryInfo, low_battery_percent: i32) -> bool {
+	match event {
+		BatteryEvent::LowBattery => !info.is_charging && info.percentage < low_battery_percent,
+		BatteryEvent::WeakCharger => info.is_charging && info.discharge_rate_percent > 0,
+		BatteryEvent::ChargeComplete => info.is_charging && (info.state == State::Full || info.percentage >= 100),
+		BatteryEvent::PluggedIn => info.is_charging,
+		BatteryEvent::Unplugged => !info.is_charging,
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn battery(percentage: i32, is_charging: bool) -> BatteryInfo {
+		BatteryInfo {
+			percentage,
+			discharge_rate_percent: if is_charging { -10 } else { 10 },
+			power_draw_watts: 0.0,
+			instant_power_draw_watts: 0.0,
+			is_charging,
+			state: if is_charging { State::Charging } else { State::Discharging },
+			health_percent: 100,
+			cycle_count: None,
+		}
+	}
+
+	#[test]
+	fn first_sample_fires_nothing() {
+		assert_eq!(detect_events(None, &battery(5, false), DEFAULT_LOW_BATTERY_PERCENT), vec![]);
+		assert_eq!(detect_events(None, &battery(100, true), DEFAULT_LOW_BATTERY_PERCENT), vec![]);
+	}
+
+	#[test]
+	fn fires_when_a_condition_starts_to_hold() {
+		let low = DEFAULT_LOW_BATTERY_PERCENT;
+		assert_eq!(detect_events(Some(&battery(16, false)), &battery(14, false), low), vec![BatteryEvent::LowBattery]);
+		assert_eq!(detect_events(Some(&battery(14, false)), &battery(13, false), low), vec![]);
+		assert_eq!(detect_events(Some(&battery(14, false)), &battery(14, true), low), vec![BatteryEvent::PluggedIn]);
+		assert_eq!(detect_events(Some(&battery(99, true)), &battery(100, true), low), vec![BatteryEvent::ChargeComplete]);
+		assert_eq!(detect_events(Some(&battery(100, true)), &battery(100, false), low), vec![BatteryEvent::Unplugged]);
+	}
+}
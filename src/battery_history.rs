@@ -0,0 +1,54 @@
+use std::collections::VecDeque;
+use std::time::{Duration, SystemTime};
+use crate::battery_monitor::BatteryInfo;
+
+// How far back samples are kept in memory
+const HISTORY_DURATION: Duration = Duration::from_secs(24 * 60 * 60);
+
+// A sample stands in for the buckets after it until it's this old, so graphs don't have holes
+// where their columns are closer together than the samples
+const BUCKET_SAMPLE_MAX_AGE: Duration = Duration::from_secs(15 * 60);
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct BatterySample {
+	pub time: SystemTime,
+	pub info: BatteryInfo,
+}
+
+/// Recent battery samples, oldest first
+pub struct BatteryHistory {
+	samples: VecDeque<BatterySample>,
+}
+
+impl BatteryHistory {
+	pub fn new() -> Self {
+		Self { samples: VecDeque::new() }
+	}
+
+	pub fn push(&mut self, time: SystemTime, info: BatteryInfo) {
+		self.samples.push_back(BatterySample { time, info });
+		while self.samples.front().is_some_and(|s| time.duration_since(s.time).unwrap_or_default() > HISTORY_DURATION) {
+			self.samples.pop_front();
+		}
+	}
+
+	pub fn samples(&self) -> impl DoubleEndedIterator<Item = &BatterySample> {
+		self.samples.iter()
+	}
+
+	/// Split the range ending at `end` into equal buckets, each holding the latest sample at or
+	/// before its end, or None if there's no recent enough sample
+	pub fn buckets(&self, end: SystemTime, range: Duration, count: usize) -> Vec<Option<BatteryInfo>> {
+		let mut samples = self.samples.iter().peekable();
+		let mut latest = None;
+		(1..=count).map(|i| {
+			let bucket_end = end - range + range.mul_f64(i as f64 / count as f64);
+			while let Some(sample) = samples.next_if(|s| s.time <= bucket_end) {
+				latest = Some(sample);
+			}
+			latest
+				.filter(|s| bucket_end.duration_since(s.time).unwrap_or_default() <= BUCKET_SAMPLE_MAX_AGE)
+				.map(|s| s.info.clone())
+		}).collect()
+	}
+}
@@ -1,26 +1,153 @@
 use starship_battery::{Manager, State};
+use crate::battery_events::{self, BatteryEvent};
 use crate::debug_util::dmsg;
-use std::time::{Duration, Instant};
+use std::time::{Duration, Instant, SystemTime};
 
-#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct BatteryInfo {
 	pub percentage: i32,
 	pub discharge_rate_percent: i32,
+	/// Smoothed power being drawn from the battery in watts, negative while charging. Kept
+	/// unrounded, as low power machines can draw well under a watt
+	pub power_draw_watts: f32,
+	/// Power drawn between the last two samples in watts, negative while charging
+	pub instant_power_draw_watts: f32,
 	pub is_charging: bool,
+	/// State as reported by the battery, which can lag behind is_charging
+	pub state: State,
+	/// Full charge capacity as a percentage of the design capacity
+	pub health_percent: i32,
+	pub cycle_count: Option<u32>,
+}
+
+impl BatteryInfo {
+	/// Estimated time until empty while losing charge, or until full while gaining it, from the
+	/// discharge rate. None while the rate is zero
+	pub fn time_remaining(&self) -> Option<Duration> {
+		let hours = match self.discharge_rate_percent {
+			0 => return None,
+			rate if rate > 0 => self.percentage as f32 / rate as f32,
+			rate => (100 - self.percentage).max(0) as f32 / -rate as f32,
+		};
+		Some(Duration::from_secs_f32(hours * 3600.0))
+	}
+}
+
+/// Watts as reported to people and scripts, to a tenth so sub-watt draw doesn't read as zero
+pub fn round_watts(watts: f32) -> f64 {
+	(watts as f64 * 10.0).round() / 10.0
+}
+
+/// What one battery reports about itself, for showing alongside the combined BatteryInfo
+#[derive(Debug, Clone, PartialEq)]
+pub struct BatteryDetails {
+	pub vendor: Option<String>,
+	pub model: Option<String>,
+	pub technology: String,
+	pub state: State,
+	pub percentage: i32,
+	pub energy_wh: f32,
+	pub energy_full_wh: f32,
+	pub energy_full_design_wh: f32,
+	/// Rate energy is flowing in or out, as reported by the battery
+	pub energy_rate_watts: f32,
+	pub voltage: f32,
+	pub temperature_celsius: Option<f32>,
+	pub health_percent: i32,
+	pub cycle_count: Option<u32>,
+}
+
+/// Battery drain over a period the system spent asleep
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SleepDrain {
+	/// Wall-clock time of the last sample before sleeping
+	pub started: SystemTime,
+	/// Wall-clock time between the samples either side of sleep
+	pub duration: Duration,
+	pub soc_before: f32,
+	pub soc_after: f32,
+}
+
+impl SleepDrain {
+	/// Battery percentage lost while asleep, negative if it charged
+	pub fn drain_percent(&self) -> f32 {
+		(self.soc_before - self.soc_after) * 100.0
+	}
+
+	pub fn drain_rate_percent_per_hour(&self) -> f32 {
+		let hours = self.duration.as_secs_f32() / 3600.0;
+		if hours > 0.0 { self.drain_percent() / hours } else { 0.0 }
+	}
 }
 
 const SOC_HISTORY_LENGTH: usize = 5;
 
+// How far the wall clock can run ahead of the monotonic clock between samples before we
+// assume the system was asleep. Linux's monotonic clock stops during suspend
+const SUSPEND_CLOCK_DIVERGENCE: Duration = Duration::from_secs(30);
+
+// Longest gap between samples we expect while awake. Windows' monotonic clock keeps running
+// during suspend, so a gap much longer than any poll interval also means we slept
+const SUSPEND_SAMPLE_GAP: Duration = Duration::from_secs(15 * 60);
+
+/// Longest time allowed between samples, well short of the gap that's taken to mean we slept
+pub const MAX_SAMPLE_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
+// Time constant of the exponential smoothing applied to the power draw
+const POWER_SMOOTHING_SECONDS: f32 = 60.0;
+
+/// Whether the time between two samples looks like the system slept, judging by how far the
+/// monotonic and wall clocks moved. Only a fallback for when the OS doesn't report resuming,
+/// as a wall clock change looks the same as a sleep
+pub fn looks_like_sleep(monotonic: Duration, wall: Duration) -> bool {
+	wall.saturating_sub(monotonic) >= SUSPEND_CLOCK_DIVERGENCE || monotonic >= SUSPEND_SAMPLE_GAP
+}
+
+/// Power from the energy counter moving since an earlier reading, in watts and negative while
+/// charging. None until the counter has moved
+fn measured_power(since_joules: f32, since: Instant, energy_joules: f32, now: Instant) -> Option<f32> {
+	let secs = now.saturating_duration_since(since).as_secs_f32();
+	(since_joules != energy_joules && secs > 0.0).then(|| (since_joules - energy_joules) / secs)
+}
+
+/// One step of the exponential moving average applied to the power draw, `elapsed` after the
+/// previous value
+fn smooth_power(previous: Option<f32>, instant_watts: f32, elapsed: Duration) -> f32 {
+	match previous {
+		None => instant_watts,
+		Some(previous) => {
+			let alpha = 1.0 - (-elapsed.as_secs_f32() / POWER_SMOOTHING_SECONDS).exp();
+			previous + alpha * (instant_watts - previous)
+		}
+	}
+}
+
 #[derive(Debug, Clone, Copy)]
 struct PreviousBatteryState {
 	soc: f32,
+	energy_joules: f32,
 	timestamp: Instant,
+	wall_time: SystemTime,
 }
 
 pub struct BatteryMonitor {
 	manager: Manager,
 	previous_states: [Option<PreviousBatteryState>; SOC_HISTORY_LENGTH],
 	previous_is_charging: bool,
+	last_sample: Option<PreviousBatteryState>,
+	// The last sample where the energy counter moved. It only changes in steps, so the power is
+	// measured over the time since the last step rather than since the last sample
+	last_energy_change: Option<PreviousBatteryState>,
+	smoothed_power_watts: Option<f32>,
+	resume_pending: bool,
+	resume_events: bool,
+	last_sleep_drain: Option<SleepDrain>,
+	samples_taken: u64,
+	sample_errors: u64,
+	last_info: Option<BatteryInfo>,
+	low_battery_percent: i32,
+	collect_events: bool,
+	pending_events: Vec<BatteryEvent>,
 }
 
 impl BatteryMonitor {
@@ -35,10 +162,101 @@ impl BatteryMonitor {
 			manager,
 			previous_states: [None; SOC_HISTORY_LENGTH],
 			previous_is_charging: false,
+			last_sample: None,
+			last_energy_change: None,
+			smoothed_power_watts: None,
+			resume_pending: false,
+			resume_events: false,
+			last_sleep_drain: None,
+			samples_taken: 0,
+			sample_errors: 0,
+			last_info: None,
+			low_battery_percent: battery_events::DEFAULT_LOW_BATTERY_PERCENT,
+			collect_events: false,
+			pending_events: Vec::new(),
 		})
 	}
 
+	/// Tell the monitor the OS reported a resume from sleep, so the next sample starts a fresh history
+	pub fn notify_resumed(&mut self) {
+		self.resume_pending = true;
+	}
+
+	/// Whether every resume will be reported through notify_resumed. Without that, sleep is
+	/// guessed from the clocks
+	pub fn set_resume_events(&mut self, resume_events: bool) {
+		self.resume_events = resume_events;
+	}
+
+	/// Battery drain during the most recent sleep we noticed, if it hasn't been taken already
+	pub fn take_sleep_drain(&mut self) -> Option<SleepDrain> {
+		self.last_sleep_drain.take()
+	}
+
+	pub fn set_low_battery_percent(&mut self, low_battery_percent: i32) {
+		self.low_battery_percent = low_battery_percent;
+	}
+
+	/// Whether to keep events for take_events. Off by default, so callers that never take them
+	/// don't pile them up
+	pub fn set_collect_events(&mut self, collect_events: bool) {
+		self.collect_events = collect_events;
+		if !collect_events {
+			self.pending_events.clear();
+		}
+	}
+
+	/// Events seen since they were last taken, oldest first
+	pub fn take_events(&mut self) -> Vec<BatteryEvent> {
+		std::mem::take(&mut self.pending_events)
+	}
+
+	/// Number of successful and failed calls to get_battery_info
+	pub fn sample_counts(&self) -> (u64, u64) {
+		(self.samples_taken, self.sample_errors)
+	}
+
+	/// Details of every battery in the system. Unlike get_battery_info this doesn't touch the
+	/// sample history
+	pub fn battery_details(&self) -> Result<Vec<BatteryDetails>, String> {
+		let batteries = self.manager.batteries().map_err(|e| format!("Failed to retrieve batteries: {:?}", e))?;
+		batteries.map(|bat| {
+			let bat = bat.map_err(|e| format!("Failed to get battery info: {:?}", e))?;
+			Ok(BatteryDetails {
+				vendor: bat.vendor().map(|v| v.trim().to_string()),
+				model: bat.model().map(|m| m.trim().to_string()),
+				technology: bat.technology().to_string(),
+				state: bat.state(),
+				percentage: (bat.state_of_charge().value * 100.0).round() as i32,
+				energy_wh: bat.energy().value / 3600.0,
+				energy_full_wh: bat.energy_full().value / 3600.0,
+				energy_full_design_wh: bat.energy_full_design().value / 3600.0,
+				energy_rate_watts: bat.energy_rate().value,
+				voltage: bat.voltage().value,
+				temperature_celsius: bat.temperature().map(|t| t.value - 273.15),
+				health_percent: (bat.state_of_health().value * 100.0).round() as i32,
+				cycle_count: bat.cycle_count(),
+			})
+		}).collect()
+	}
+
 	pub fn get_battery_info(&mut self) -> Result<BatteryInfo, String> {
+		let result = self.read_battery_info();
+		match &result {
+			Ok(info) => {
+				self.samples_taken += 1;
+				if self.collect_events {
+					let events = battery_events::detect_events(self.last_info.as_ref(), info, self.low_battery_percent);
+					self.pending_events.extend(events);
+				}
+				self.last_info = Some(info.clone());
+			}
+			Err(_) => self.sample_errors += 1,
+		}
+		result
+	}
+
+	fn read_battery_info(&mut self) -> Result<BatteryInfo, String> {
 		match self.manager.batteries() {
 			Err(e) => { Err(format!("Failed to retrieve batteries: {:?}", e)) }
 			Ok(mut batteries) => {
@@ -51,6 +269,7 @@ impl BatteryMonitor {
 						// Calculate percentage from the reported soc, which we trust
 						let soc = bat.state_of_charge().value;
 						let percentage = (soc * 100.0).round() as i32;
+						let energy_joules = bat.energy().value;
 
 						// If we just switched between charging or discharging we have to dump our prev data
 						// as calculating the rate from that will almost certainly be wrong
@@ -59,10 +278,43 @@ impl BatteryMonitor {
 							if is_charging != self.previous_is_charging {
 								self.previous_states = [None; SOC_HISTORY_LENGTH];
 								self.previous_is_charging = is_charging;
+								self.last_energy_change = None;
+								self.smoothed_power_watts = None;
 							}
 						}
 
 						let timestamp = Instant::now();
+						let wall_time = SystemTime::now();
+
+						// Samples from before a sleep would produce a bogus rate, so start a new history
+						// segment and record the drain while asleep separately
+						{
+							let slept = self.resume_pending || (!self.resume_events && self.last_sample.is_some_and(|s| {
+								looks_like_sleep(timestamp.duration_since(s.timestamp), wall_time.duration_since(s.wall_time).unwrap_or_default())
+							}));
+
+							if slept {
+								// Charging on either side of the sleep means it wasn't all spent on battery,
+								// so it says nothing about the drain
+								let charged = self.last_info.as_ref().is_some_and(|info| info.is_charging)
+									|| matches!(bat.state(), State::Charging) || bat.time_to_empty().is_none();
+								if let Some(before) = self.last_sample.filter(|_| !charged) {
+									let sleep_drain = SleepDrain {
+										started: before.wall_time,
+										duration: wall_time.duration_since(before.wall_time).unwrap_or_default(),
+										soc_before: before.soc,
+										soc_after: soc,
+									};
+									dmsg!("Resumed from sleep: {:?}, {:.1}%/hour", sleep_drain, sleep_drain.drain_rate_percent_per_hour());
+									self.last_sleep_drain = Some(sleep_drain);
+								}
+								self.previous_states = [None; SOC_HISTORY_LENGTH];
+								self.last_sample = None;
+								self.last_energy_change = None;
+								self.smoothed_power_watts = None;
+								self.resume_pending = false;
+							}
+						}
 
 						// Calculate rate of discharge in battery-soc/hour. We calculate from the soc and
 						// not from time_to_empty or energy_rate as sometimes those numbers seem wrong
@@ -84,6 +336,42 @@ impl BatteryMonitor {
 							}
 						};
 
+						// Calculate power draw in watts from the energy change since the counter last moved. This is
+						// comparable across machines unlike the soc rate. The reported energy_rate is used as a
+						// cross-check, and as a fallback when the energy counter hasn't moved yet
+						let instant_power_watts: f32 = {
+							let reported_watts = match bat.state() {
+								State::Charging => -bat.energy_rate().value.abs(),
+								_ => bat.energy_rate().value.abs(),
+							};
+
+							let measured_watts = self.last_energy_change
+								.and_then(|s| measured_power(s.energy_joules, s.timestamp, energy_joules, timestamp));
+
+							match measured_watts {
+								None => reported_watts,
+								Some(w) => {
+									if (w - reported_watts).abs() > reported_watts.abs().max(1.0) {
+										dmsg!("Measured power {:.2}W disagrees with reported {:.2}W", w, reported_watts);
+									}
+									w
+								}
+							}
+						};
+
+						// Smooth the instantaneous reading with an exponential moving average
+						let power_watts = {
+							let elapsed = self.last_sample.map_or(Duration::ZERO, |s| timestamp.duration_since(s.timestamp));
+							let smoothed = smooth_power(self.smoothed_power_watts, instant_power_watts, elapsed);
+							self.smoothed_power_watts = Some(smoothed);
+							smoothed
+						};
+						let sample = PreviousBatteryState {soc, energy_joules, timestamp, wall_time};
+						if self.last_energy_change.is_none_or(|s| s.energy_joules != energy_joules) {
+							self.last_energy_change = Some(sample);
+						}
+						self.last_sample = Some(sample);
+
 						// Sometimes the battery state doesn't switch to charging even when plugged in
 						// Possibly when the BMS is choosing to just not charge the battery because it's full
 						// So we add a couple of other checks
@@ -99,13 +387,64 @@ impl BatteryMonitor {
 							self.previous_states.copy_within(0..l, 1);
 
 							// Insert new state at the front
-							self.previous_states[0] = Some(PreviousBatteryState {soc, timestamp});
+							self.previous_states[0] = Some(PreviousBatteryState {soc, energy_joules, timestamp, wall_time});
 						}
 
-						Ok(BatteryInfo{percentage, discharge_rate_percent, is_charging})
+						Ok(BatteryInfo{
+							percentage,
+							discharge_rate_percent,
+							power_draw_watts: power_watts,
+							instant_power_draw_watts: instant_power_watts,
+							is_charging,
+							state: bat.state(),
+							health_percent: (bat.state_of_health().value * 100.0).round() as i32,
+							cycle_count: bat.cycle_count(),
+						})
 					}
 				}
 			}
 		}
 	}
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn clock_divergence_looks_like_sleep() {
+		let minute = Duration::from_secs(60);
+		assert!(!looks_like_sleep(Duration::from_secs(10), Duration::from_secs(10)));
+		assert!(!looks_like_sleep(Duration::from_secs(10), Duration::from_secs(10) + SUSPEND_CLOCK_DIVERGENCE - Duration::from_secs(1)));
+		assert!(looks_like_sleep(Duration::from_secs(10), Duration::from_secs(10) + SUSPEND_CLOCK_DIVERGENCE));
+		// The wall clock going back isn't sleep
+		assert!(!looks_like_sleep(Duration::from_secs(10), Duration::ZERO));
+		// A gap no poll interval would leave, when the monotonic clock kept running
+		assert!(!looks_like_sleep(SUSPEND_SAMPLE_GAP - minute, SUSPEND_SAMPLE_GAP - minute));
+		assert!(looks_like_sleep(SUSPEND_SAMPLE_GAP, SUSPEND_SAMPLE_GAP));
+	}
+
+	#[test]
+	fn measures_power_from_the_energy_counter() {
+		let start = Instant::now();
+		let later = start + Duration::from_secs(360);
+		// 3600 J in 6 minutes is 10 W
+		assert_eq!(measured_power(100_000.0, start, 96_400.0, later), Some(10.0));
+		assert_eq!(measured_power(96_400.0, start, 100_000.0, later), Some(-10.0));
+		// Sub-watt draw isn't lost to rounding
+		assert_eq!(measured_power(100_000.0, start, 99_820.0, later), Some(0.5));
+		assert_eq!(measured_power(100_000.0, start, 100_000.0, later), None);
+		assert_eq!(measured_power(100_000.0, start, 96_400.0, start), None);
+	}
+
+	#[test]
+	fn smooths_power_over_a_minute() {
+		assert_eq!(smooth_power(None, 12.0, Duration::from_secs(5)), 12.0);
+		assert_eq!(smooth_power(Some(10.0), 20.0, Duration::ZERO), 10.0);
+		// One time constant covers 1 - 1/e of the step
+		let smoothed = smooth_power(Some(10.0), 20.0, Duration::from_secs_f32(POWER_SMOOTHING_SECONDS));
+		assert!((smoothed - (20.0 - 10.0 / std::f32::consts::E)).abs() < 1e-4);
+		// A long gap all but forgets the old value
+		assert!((smooth_power(Some(10.0), 20.0, Duration::from_secs(3600)) - 20.0).abs() < 1e-4);
+	}
+}
@@ -0,0 +1,41 @@
+use crate::config;
+use crate::shell;
+use crate::debug_util::dmsg;
+
+/// Generate a battery report and open it. On Windows this is the system report from powercfg,
+/// elsewhere it's our own text report of the current status and sleep history
+pub fn open_battery_report(status: Option<String>, sleep_report: String) -> Result<(), String> {
+	let dir = config::app_dir().ok_or_else(|| "Couldn't find a report directory".to_string())?;
+	std::fs::create_dir_all(&dir).map_err(|e| format!("Failed to create {:?}: {:?}", dir, e))?;
+
+	#[cfg(target_os = "windows")]
+	{
+		// powercfg takes a few seconds, so run it off the event loop thread
+		let path = dir.join("battery-report.html");
+		std::thread::spawn(move || {
+			if let Err(_e) = run_powercfg(&path).and_then(|()| shell::open_path(&path)) {
+				dmsg!("Failed to create battery report: {}", _e);
+			}
+		});
+		Ok(())
+	}
+
+	#[cfg(not(target_os = "windows"))]
+	{
+		let path = dir.join("battery-report.txt");
+		let report = format!("{}\n\n{}", status.unwrap_or_else(|| "No battery found".to_string()), sleep_report);
+		std::fs::write(&path, report).map_err(|e| format!("Failed to write {:?}: {:?}", path, e))?;
+		shell::open_path(&path)
+	}
+}
+
+#[cfg(target_os = "windows")]
+fn run_powercfg(path: &std::path::Path) -> Result<(), String> {
+	let mut command = std::process::Command::new("powercfg");
+	command.arg("/batteryreport").arg("/output").arg(path);
+	shell::hide_console_window(&mut command);
+	let status = command
+		.status()
+		.map_err(|e| format!("Failed to run powercfg: {:?}", e))?;
+	if status.success() { Ok(()) } else { Err(format!("powercfg failed: {}", status)) }
+}
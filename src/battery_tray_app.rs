@@ -1,69 +1,332 @@
 use std::time::{Duration, Instant};
 use winit::application::ApplicationHandler;
 use winit::event_loop::{ActiveEventLoop, ControlFlow};
-use winit::event::WindowEvent;
+use winit::event::{ElementState, WindowEvent};
+use winit::keyboard::{Key, NamedKey};
 use winit::window::WindowId;
 use crate::battery_monitor::BatteryMonitor;
-use crate::icon_builder::IconBuilder;
+use crate::icon_builder::{IconBuilder, IconDisplay};
+use crate::icon_rotation::IconRotation;
 use crate::battery_tray_icon::BatteryTrayIcon;
+use crate::config::Config;
+use crate::sleep_log::SleepLog;
+use crate::power_events::{PowerEvent, PowerEventSource};
+use crate::sample_scheduler::SampleScheduler;
+use crate::metrics_exporter::{MetricsExporter, MetricsSnapshot};
+use crate::mqtt_publisher::MqttPublisher;
+use crate::charge_control::{self, ChargeControlMode, ChargeController};
+use crate::control::{self, ControlRequest};
+use crate::history_window::HistoryWindow;
+use crate::tray_click::{Click, ClickAction, ClickTracker};
+use serde_json::Value;
+use crate::tray_menu::{self, MenuAction};
+use crate::config::NamedSetting;
+use crate::{autostart, battery_report, config, shell, sleep_log};
 use crate::debug_util::dmsg;
 use crate::UserEvent;
 
-const UPDATE_SLEEP_SECONDS: u64 = 10;
+// Power events tend to arrive in bursts, so wait briefly and take a single sample
+const POWER_EVENT_DELAY_MILLISECONDS: u64 = 500;
 
 pub struct BatteryTrayApp {
 	pub tray_icon: BatteryTrayIcon,
+	power_events: PowerEventSource,
+	scheduler: SampleScheduler,
+	next_sample: Instant,
+	metrics: Option<MetricsExporter>,
+	charge_controller: ChargeController,
+	history_window: Option<HistoryWindow>,
+	click_tracker: ClickTracker,
+	rotation: IconRotation,
+	config: Config,
 }
 
 impl BatteryTrayApp {
-	pub fn new(battery_monitor: BatteryMonitor, icon_builder : IconBuilder) -> Self {
+	pub fn new(battery_monitor: BatteryMonitor, icon_builder : IconBuilder, power_events: PowerEventSource, config: Config) -> Self {
+		let mut scheduler = SampleScheduler::new(power_events.is_event_driven());
+		scheduler.set_fixed_interval(config.update_interval_seconds.map(Duration::from_secs));
+		let mut tray_icon = BatteryTrayIcon::new(battery_monitor, icon_builder, SleepLog::load(), &config);
+		tray_icon.battery_monitor_mut().set_resume_events(power_events.reports_resume());
+
+		// The metrics port and MQTT settings are only read at startup, changing them takes a restart
+		let metrics = config.metrics_port.and_then(|port| {
+			MetricsExporter::start(port).map_err(|_e| { dmsg!("Failed to start metrics exporter: {}", _e); }).ok()
+		});
+		let mqtt_publisher = config.mqtt_settings().and_then(|settings| {
+			MqttPublisher::start(settings).map_err(|_e| { dmsg!("Failed to start MQTT publisher: {}", _e); }).ok()
+		});
+		tray_icon.set_mqtt_publisher(mqtt_publisher);
+
+		let mut charge_controller = ChargeController::new();
+		if let Err(_e) = charge_controller.set_thresholds(
+			config.charge_start_percent,
+			config.charge_stop_percent,
+			Duration::from_secs(config.charge_cooldown_seconds),
+		) {
+			dmsg!("Charge control disabled: {}", _e);
+		}
+
+		let mut click_tracker = ClickTracker::new();
+		click_tracker.set_wait_for_double_click(config.double_click_action != ClickAction::None);
+
+		let mut rotation = IconRotation::new(Instant::now());
+		rotation.set_views(&config.rotate_views, Duration::from_secs(config.rotate_seconds), Instant::now());
+		tray_icon.set_rotation_frame(rotation.frame(Instant::now()));
+
 		Self {
-			tray_icon: BatteryTrayIcon::new(battery_monitor, icon_builder),
+			tray_icon,
+			power_events,
+			scheduler,
+			next_sample: Instant::now(),
+			metrics,
+			charge_controller,
+			history_window: None,
+			click_tracker,
+			rotation,
+			config,
 		}
 	}
 
-	fn check_battery(&mut self, event_loop: &ActiveEventLoop) {
-		if let Err(_e) = self.tray_icon.sync_tray_icon() {
-			dmsg!("Failed to update tray icon: {}", _e);
+	fn handle_menu_action(&mut self, action: MenuAction) {
+		match action {
+			MenuAction::Quit => {
+				dmsg!("Quit selected, exiting...");
+				std::process::exit(0);
+			}
+			MenuAction::OpenLogFolder => {
+				let result = config::app_dir()
+					.ok_or_else(|| "Couldn't find the log folder".to_string())
+					.and_then(|dir| {
+						std::fs::create_dir_all(&dir).map_err(|e| format!("Failed to create {:?}: {:?}", dir, e))?;
+						shell::open_path(&dir)
+					});
+				if let Err(_e) = result {
+					dmsg!("Failed to open log folder: {}", _e);
+				}
+			}
+			MenuAction::ToggleAutostart => {
+				let result = autostart::platform_autostart()
+					.and_then(|a| a.is_enabled().and_then(|enabled| a.set_enabled(!enabled)));
+				if let Err(_e) = result {
+					dmsg!("Failed to change start at login: {}", _e);
+				}
+				if let Some(menu) = self.tray_icon.menu() {
+					menu.sync_autostart();
+				}
+			}
+			MenuAction::BatteryReport => self.open_battery_report(),
+			// Everything else changes a setting, which we save and apply straight away
+			_ if action.apply(&mut self.config) => {
+				if let Err(_e) = self.config.save() {
+					dmsg!("Failed to save config: {}", _e);
+				}
+				self.apply_config();
+			}
+			_ => {
+				dmsg!("Unhandled menu action {:?}", action);
+			}
 		}
-		
-		// Make sure we check again soon
-		event_loop.set_control_flow(ControlFlow::WaitUntil(Instant::now() + Duration::from_secs(UPDATE_SLEEP_SECONDS)));
+	}
+
+	fn handle_control_request(&mut self, request: ControlRequest) -> Result<Value, String> {
+		match request {
+			ControlRequest::GetStatus => Ok(self.tray_icon.battery_info().map(control::battery_info_json).unwrap_or(Value::Null)),
+			ControlRequest::GetHistory => Ok(control::history_json(self.tray_icon.history())),
+			ControlRequest::ReloadConfig => {
+				self.config = Config::load();
+				self.apply_config();
+				Ok(Value::Null)
+			}
+			// Same as picking the theme from the menu, so it's saved too
+			ControlRequest::SetTheme(theme) => {
+				self.handle_menu_action(MenuAction::SetTheme(theme));
+				Ok(Value::Null)
+			}
+			ControlRequest::ShowReport => {
+				self.open_battery_report();
+				Ok(Value::Null)
+			}
+		}
+	}
+
+	fn apply_config(&mut self) {
+		let fixed_interval = self.config.update_interval_seconds.map(Duration::from_secs);
+		if self.scheduler.fixed_interval() != fixed_interval {
+			self.scheduler.set_fixed_interval(fixed_interval);
+			self.next_sample = Instant::now();
+		}
+		self.tray_icon.apply_config(&self.config);
+		self.rotation.set_views(&self.config.rotate_views, Duration::from_secs(self.config.rotate_seconds), Instant::now());
+		self.tray_icon.set_rotation_frame(self.rotation.frame(Instant::now()));
+		self.click_tracker.set_wait_for_double_click(self.config.double_click_action != ClickAction::None);
+		if let Err(_e) = self.charge_controller.set_thresholds(
+			self.config.charge_start_percent,
+			self.config.charge_stop_percent,
+			Duration::from_secs(self.config.charge_cooldown_seconds),
+		) {
+			dmsg!("Charge control disabled: {}", _e);
+		}
+	}
+
+	fn handle_click(&mut self, event_loop: &ActiveEventLoop, click: Click) {
+		let action = self.config.click_action(click.kind);
+		dmsg!("{:?} -> {:?}", click, action);
+		match action {
+			ClickAction::None => {}
+			ClickAction::ShowHistory => self.toggle_history_window(event_loop, click.position),
+			ClickAction::CycleLayout => self.handle_menu_action(MenuAction::SetLayout(self.config.layout.next())),
+			ClickAction::PowerSettings => {
+				if let Err(_e) = shell::open_power_settings() {
+					dmsg!("Failed to open power settings: {}", _e);
+				}
+			}
+			ClickAction::ToggleTimeRemaining => {
+				let display = if self.config.display == IconDisplay::TimeRemaining { IconDisplay::Percentage } else { IconDisplay::TimeRemaining };
+				self.handle_menu_action(MenuAction::SetDisplay(display));
+			}
+			ClickAction::Refresh => self.next_sample = Instant::now(),
+		}
+	}
+
+	fn toggle_history_window(&mut self, event_loop: &ActiveEventLoop, anchor: winit::dpi::PhysicalPosition<f64>) {
+		if self.history_window.take().is_some() {
+			return;
+		}
+		match HistoryWindow::open(event_loop, anchor) {
+			Ok(window) => self.history_window = Some(window),
+			Err(_e) => { dmsg!("{}", _e); }
+		}
+	}
+
+	fn open_battery_report(&self) {
+		let status = self.tray_icon.battery_info().map(tray_menu::format_status);
+		let sleep_report = sleep_log::format_sleep_report(self.tray_icon.sleep_log(), self.config.sleep_drain_warning_percent_per_hour);
+		if let Err(_e) = battery_report::open_battery_report(status, sleep_report) {
+			dmsg!("Failed to open battery report: {}", _e);
+		}
+	}
+
+	fn check_battery(&mut self) {
+		let battery_info = match self.tray_icon.sync_tray_icon() {
+			Ok(()) => self.tray_icon.battery_info(),
+			Err(_e) => {
+				dmsg!("Failed to update tray icon: {}", _e);
+				None
+			}
+		};
+
+		if let Some(history_window) = &self.history_window {
+			history_window.request_redraw();
+		}
+
+		if let Some(metrics) = &self.metrics {
+			let (samples_taken, sample_errors) = self.tray_icon.battery_monitor().sample_counts();
+			metrics.update(MetricsSnapshot { battery_info: battery_info.cloned(), samples_taken, sample_errors });
+		}
+
+		if let (Some(info), true) = (battery_info, self.config.charge_control != ChargeControlMode::Off) {
+			if let Some(decision) = self.charge_controller.decide(info, Instant::now()) {
+				charge_control::carry_out(&decision, info, &self.config, self.tray_icon.mqtt_publisher());
+			}
+		}
+
+		// Pick when to check again based on what the battery is doing
+		let decision = self.scheduler.decide(battery_info);
+		dmsg!("Next sample in {:?} ({:?})", decision.interval, decision.reason);
+		self.next_sample = Instant::now() + decision.interval;
+	}
+
+	fn handle_power_event(&mut self, event: PowerEvent) {
+		match event {
+			// Take a sample right away so the sleep drain is measured from an up to date charge
+			PowerEvent::Suspending => self.check_battery(),
+			PowerEvent::Resumed => self.tray_icon.battery_monitor_mut().notify_resumed(),
+			PowerEvent::Changed => {}
+		}
+
+		// Bring the next sample forward, unless one is already due sooner
+		let soon = Instant::now() + Duration::from_millis(POWER_EVENT_DELAY_MILLISECONDS);
+		self.next_sample = self.next_sample.min(soon);
 	}
 }
 
 impl ApplicationHandler<UserEvent> for BatteryTrayApp {
 	fn resumed(&mut self, event_loop: &ActiveEventLoop) {
 		dmsg!("Resume event");
-		self.check_battery(event_loop);
+		self.check_battery();
 	}
 
-	fn window_event(&mut self, event_loop: &ActiveEventLoop, _window_id: WindowId, _event: WindowEvent) {
-		dmsg!("Window event: {:?}", _event);
-		self.check_battery(event_loop);
+	fn window_event(&mut self, event_loop: &ActiveEventLoop, window_id: WindowId, event: WindowEvent) {
+		dmsg!("Window event: {:?}", event);
+
+		let Some(history_window) = self.history_window.as_mut().filter(|w| w.id() == window_id) else {
+			return;
+		};
+		match event {
+			WindowEvent::RedrawRequested => {
+				if let Err(_e) = history_window.draw(self.tray_icon.history()) {
+					dmsg!("{}", _e);
+				}
+			}
+			WindowEvent::Resized(_) => history_window.request_redraw(),
+			WindowEvent::CloseRequested => self.history_window = None,
+			WindowEvent::KeyboardInput { event, .. } if event.state == ElementState::Pressed && event.logical_key == Key::Named(NamedKey::Escape) => {
+				self.history_window = None;
+			}
+			_ => {}
+		}
 	}
 
 	fn new_events(&mut self, event_loop: &ActiveEventLoop, _cause: winit::event::StartCause) {
 		dmsg!("New event: {:?}", _cause);
-		self.check_battery(event_loop);
 	}
 
 	fn user_event(&mut self, event_loop: &ActiveEventLoop, event: UserEvent) {
 		dmsg!("User event: {:?}", event);
-		self.check_battery(event_loop);
 
 		match event {
-			UserEvent::TrayIconEvent(_tray_event) => { }
+			UserEvent::TrayIconEvent(tray_event) => {
+				if let Some(click) = self.click_tracker.handle_event(&tray_event, Instant::now()) {
+					self.handle_click(event_loop, click);
+				}
+			}
 			UserEvent::MenuEvent(menu_event) => {
-				// Handle quit menu item
-				match menu_event.id.0.as_str() {
-					"quit" => {
-						dmsg!("Quit selected, exiting...");
-						std::process::exit(0);
-					}
-					_ => {}
+				if let Some(action) = MenuAction::from_id(menu_event.id.0.as_str()) {
+					self.handle_menu_action(action);
 				}
+				else {
+					dmsg!("Unknown menu item {:?}", menu_event.id);
+				}
+			}
+			UserEvent::PowerEvent(power_event) => {
+				self.handle_power_event(power_event);
+			}
+			UserEvent::ControlRequest(request, reply) => {
+				let _ = reply.send(self.handle_control_request(request));
 			}
 		}
 	}
+
+	fn about_to_wait(&mut self, event_loop: &ActiveEventLoop) {
+		// A single click waits to see if it becomes a double click
+		if let Some(click) = self.click_tracker.take_due(Instant::now()) {
+			self.handle_click(event_loop, click);
+		}
+
+		// Battery sampling only happens on its own schedule, not on unrelated UI events
+		if Instant::now() >= self.next_sample {
+			self.check_battery();
+		}
+
+		// The rotation and animations redraw the last sample, so they run on their own timers
+		if let Some(frame) = self.rotation.tick(Instant::now()) {
+			self.tray_icon.set_rotation_frame(Some(frame));
+		}
+		self.tray_icon.tick_animation(Instant::now());
+
+		let wake_at = [self.click_tracker.deadline(), self.rotation.deadline(), self.tray_icon.animation_deadline()].into_iter()
+			.flatten()
+			.fold(self.next_sample, Instant::min);
+		event_loop.set_control_flow(ControlFlow::WaitUntil(wake_at));
+	}
 }
@@ -1,91 +1,345 @@
+use std::path::PathBuf;
+use std::time::{Duration, Instant, SystemTime};
+use image::RgbaImage;
 use tray_icon::{TrayIcon, TrayIconBuilder, Icon};
-use tray_icon::menu::{Menu, MenuItem};
 
+use crate::battery_history::BatteryHistory;
 use crate::battery_monitor::{BatteryMonitor, BatteryInfo};
-use crate::icon_builder::IconBuilder;
+use crate::config::Config;
+use crate::alert_level;
+use crate::icon_animation::{self, AnimationClock};
+use crate::icon_builder::{GlyphSet, IconAnimation, IconBuilder};
+use crate::glyph_pack;
+use crate::icon_rotation::RotationFrame;
+use crate::hooks;
+use crate::mqtt_publisher::MqttPublisher;
+use crate::sleep_log::{self, SleepLog};
+use crate::tray_menu::{self, TrayMenu};
+use crate::shell;
 use crate::debug_util::dmsg;
 
+const TOOLTIP_TITLE: &str = "Battery Percentage";
+
 pub struct BatteryTrayIcon {
 	tray_icon: Option<TrayIcon>,
+	menu: Option<TrayMenu>,
 	battery_monitor: BatteryMonitor,
 	icon_builder: IconBuilder,
 	cached_battery_info: Option<BatteryInfo>,
+	history: BatteryHistory,
+	mqtt_publisher: Option<MqttPublisher>,
+	sleep_log: SleepLog,
+	config: Config,
+	rotation_frame: Option<RotationFrame>,
+	animation_frames: Vec<RgbaImage>,
+	animation_clock: AnimationClock,
+	// The glyph pack the icon builder was last given, None for the built in glyphs
+	glyph_pack: Option<PathBuf>,
+	// Why the glyph pack couldn't be used, shown in the tooltip until it's changed
+	glyph_pack_error: Option<String>,
 }
 
-fn create_tray_icon(icon: Icon) -> Result<TrayIcon, String> {
-	// Create a simple menu
-	let menu = Menu::new();
-	let quit_item = MenuItem::with_id("quit", "Quit", true, None);
-	menu.append(&quit_item).map_err(|e| format!("Failed to add menu item: {:?}", e))?;
-	
+fn create_tray_icon(icon: Icon, tooltip: &str, menu: &TrayMenu) -> Result<TrayIcon, String> {
 	// Create the tray icon
 	let tray_icon = TrayIconBuilder::new()
 		.with_icon(icon)
-		.with_menu(Box::new(menu))
-		.with_tooltip("Battery Percentage")
+		.with_menu(menu.context_menu())
+		.with_tooltip(tooltip)
 		.build()
 		.map_err(|e| format!("Failed to create tray icon: {:?}", e))?;
 	
 	Ok(tray_icon)
 }
 
+fn icon_from_image(image: RgbaImage) -> Result<Icon, String> {
+	let (w, h) = (image.width(), image.height());
+	Icon::from_rgba(image.into_raw(), w, h).map_err(|e| format!("Failed to create icon: {:?}", e))
+}
+
 impl BatteryTrayIcon {
-	pub fn new(battery_monitor: BatteryMonitor, icon_builder: IconBuilder) -> Self {
-		Self {
+	pub fn new(battery_monitor: BatteryMonitor, icon_builder: IconBuilder, sleep_log: SleepLog, config: &Config) -> Self {
+		let mut tray_icon = Self {
 			tray_icon: None,
+			menu: None,
 			battery_monitor,
 			icon_builder,
 			cached_battery_info: None, // None to force initial update
+			history: BatteryHistory::new(),
+			mqtt_publisher: None,
+			sleep_log,
+			config: config.clone(),
+			rotation_frame: None,
+			animation_frames: Vec::new(),
+			animation_clock: AnimationClock::new(Instant::now()),
+			glyph_pack: None,
+			glyph_pack_error: None,
+		};
+		tray_icon.apply_config(config);
+		tray_icon
+	}
+
+	/// Apply changed settings and redraw the icon to match
+	pub fn apply_config(&mut self, config: &Config) {
+		self.config = config.clone();
+		self.icon_builder.set_theme(config.theme);
+		self.icon_builder.set_layout(config.layout);
+		self.icon_builder.set_display(self.rotation_frame.map_or(config.display, |frame| frame.view));
+		self.icon_builder.set_time_format(config.time_format);
+		if config.glyph_pack != self.glyph_pack {
+			self.load_glyph_pack(config.glyph_pack.clone());
+		}
+		self.icon_builder.set_outline_style(config.outline_style);
+		self.icon_builder.set_outline_color(config.outline_color);
+		self.icon_builder.set_outline_thickness(config.outline_thickness);
+		self.icon_builder.set_overlay_mode(config.overlay_mode);
+		self.icon_builder.set_power_draw_ceiling_watts(config.power_draw_ceiling_watts);
+		self.battery_monitor.set_low_battery_percent(config.low_battery_percent);
+		// Events are only used to run hooks
+		self.battery_monitor.set_collect_events(!config.hooks.is_empty());
+
+		if let Some(menu) = &self.menu {
+			menu.sync_config(config);
+			menu.set_sleep_drain(&self.sleep_drain_text());
+		}
+		self.redraw();
+	}
+
+	// Falls back to the built in glyphs if the pack can't be used, and says so
+	fn load_glyph_pack(&mut self, path: Option<PathBuf>) {
+		let result = match &path {
+			None => Ok(GlyphSet::embedded()),
+			Some(path) => glyph_pack::load_glyph_pack(path),
+		};
+		self.glyph_pack_error = result.as_ref().err().cloned();
+		self.icon_builder.set_glyphs(result.unwrap_or_else(|_| GlyphSet::embedded()));
+		self.glyph_pack = path;
+
+		self.update_tooltip();
+		if let Some(error) = &self.glyph_pack_error {
+			dmsg!("{}, using the built in glyphs", error);
+			if self.config.notifications {
+				if let Err(_e) = shell::notify("Couldn't load the glyph pack", &format!("{}, using the built in glyphs", error)) {
+					dmsg!("Failed to show notification: {}", _e);
+				}
+			}
+		}
+	}
+
+	/// Show a frame of the icon rotation in place of the configured display, or go back to the
+	/// configured display with None
+	pub fn set_rotation_frame(&mut self, frame: Option<RotationFrame>) {
+		if frame == self.rotation_frame {
+			return;
+		}
+		self.rotation_frame = frame;
+		self.icon_builder.set_display(frame.map_or(self.config.display, |frame| frame.view));
+		self.icon_builder.set_transition(frame.and_then(|frame| frame.transition));
+		self.redraw();
+	}
+
+	fn redraw(&mut self) {
+		if let Some(battery_info) = self.cached_battery_info.clone() {
+			if let Err(_e) = self.update_icon(&battery_info) {
+				dmsg!("Failed to redraw tray icon: {}", _e);
+			}
 		}
 	}
 
+	/// Show the next animation frame, if one is due
+	pub fn tick_animation(&mut self, now: Instant) {
+		let Some(index) = self.animation_clock.tick(now) else {
+			return;
+		};
+		if let (Some(t), Some(frame)) = (&self.tray_icon, self.animation_frames.get(index)) {
+			let result = icon_from_image(frame.clone()).and_then(|icon| t.set_icon(Some(icon)).map_err(|e| format!("{:?}", e)));
+			if let Err(_e) = result {
+				dmsg!("Failed to show animation frame: {}", _e);
+			}
+		}
+	}
+
+	/// When the next animation frame is due, while animating
+	pub fn animation_deadline(&self) -> Option<Instant> {
+		self.animation_clock.deadline()
+	}
+
+	fn sleep_drain_text(&self) -> String {
+		match self.sleep_log.last() {
+			None => "Last sleep: none recorded".to_string(),
+			Some(last) => sleep_log::describe_sleep_drain(last, self.config.sleep_drain_warning_percent_per_hour),
+		}
+	}
+
+	fn tooltip(&self) -> String {
+		let mut tooltip = TOOLTIP_TITLE.to_string();
+		if self.sleep_log.last().is_some() {
+			tooltip += &format!("\n{}", self.sleep_drain_text());
+		}
+		if let Some(error) = &self.glyph_pack_error {
+			tooltip += &format!("\n{}, using the built in glyphs", error);
+		}
+		tooltip
+	}
+
+	fn update_tooltip(&self) {
+		if let Some(t) = &self.tray_icon {
+			if let Err(_e) = t.set_tooltip(Some(self.tooltip())) {
+				dmsg!("Failed to update tooltip: {:?}", _e);
+			}
+		}
+	}
+
+	fn record_sleep_drain(&mut self) {
+		let Some(sleep_drain) = self.battery_monitor.take_sleep_drain() else {
+			return;
+		};
+		self.sleep_log.push(sleep_drain);
+
+		let text = self.sleep_drain_text();
+		if let Some(menu) = &self.menu {
+			menu.set_sleep_drain(&text);
+		}
+		self.update_tooltip();
+
+		let warning = self.config.sleep_drain_warning_percent_per_hour;
+		if self.config.notifications && sleep_log::is_high_drain(&sleep_drain, warning) {
+			if let Err(_e) = shell::notify("High battery drain during sleep", &text) {
+				dmsg!("Failed to show notification: {}", _e);
+			}
+		}
+	}
+
+	fn run_hooks(&mut self, battery_info: &BatteryInfo) {
+		for event in self.battery_monitor.take_events() {
+			dmsg!("Battery event: {:?}", event);
+			if let Some(command) = self.config.hook(event) {
+				let timeout = Duration::from_secs(self.config.hook_timeout_seconds);
+				if let Err(_e) = hooks::run_hook(event, command, battery_info, timeout) {
+					dmsg!("{}", _e);
+				}
+			}
+		}
+	}
+
+	/// Publish battery state changes over MQTT from now on
+	pub fn set_mqtt_publisher(&mut self, mqtt_publisher: Option<MqttPublisher>) {
+		self.mqtt_publisher = mqtt_publisher;
+	}
+
+	pub fn mqtt_publisher(&self) -> Option<&MqttPublisher> {
+		self.mqtt_publisher.as_ref()
+	}
+
+	pub fn battery_monitor(&self) -> &BatteryMonitor {
+		&self.battery_monitor
+	}
+
+	pub fn battery_monitor_mut(&mut self) -> &mut BatteryMonitor {
+		&mut self.battery_monitor
+	}
+
+	pub fn menu(&self) -> Option<&TrayMenu> {
+		self.menu.as_ref()
+	}
+
+	pub fn sleep_log(&self) -> &SleepLog {
+		&self.sleep_log
+	}
+
+	/// The battery info from the last successful sample
+	pub fn battery_info(&self) -> Option<&BatteryInfo> {
+		self.cached_battery_info.as_ref()
+	}
+
+	pub fn history(&self) -> &BatteryHistory {
+		&self.history
+	}
+
 	pub fn sync_tray_icon(&mut self) -> Result<(), String> {
 		// Get current battery info (percentage and charging status)
 		let battery_info = self.battery_monitor.get_battery_info()?;
 		dmsg!("{:?}", &battery_info);
+		self.history.push(SystemTime::now(), battery_info.clone());
+		self.record_sleep_drain();
+		self.run_hooks(&battery_info);
 
 		// Only update tray icon if battery state changed
 		if Some(&battery_info) == self.cached_battery_info.as_ref() {
 			Ok(())
 		}
 		else {
+			if let Some(mqtt_publisher) = &self.mqtt_publisher {
+				mqtt_publisher.publish(&battery_info);
+			}
 			self.cached_battery_info = Some(battery_info.clone());
+			self.update_icon(&battery_info)
+		}
+	}
 
-			// Create new icon image
-			let Ok(icon_image) = self.icon_builder.create_percentage_icon(battery_info.percentage, battery_info.discharge_rate_percent, battery_info.is_charging) else {
-				return Err(format!("Couldn't build icon"));
-			};
-			let icon = {
-				let (w, h) = (icon_image.width(), icon_image.height());
-				Icon::from_rgba(icon_image.into_raw(), w, h).map_err(|e| format!("Failed to create icon: {:?}", e))?
-			};
-
-			// Create or update tray icon
-			match &self.tray_icon {
-				None => {
-					match create_tray_icon(icon) {
-						Ok(t) => {
-							self.tray_icon = Some(t);
-							Ok(())
-						}
-						Err(e) => {
-							Err(e)
-						}
-					}
-				}
-				Some(t) => {
-					if let Err(e) = t.set_icon(Some(icon)) {
-						Err(format!("Failed to update tray icon: {:?}", e))
-					}
-					else {
-						dmsg!("Updated tray icon to {}%{}",
-							battery_info.percentage,
-							if battery_info.is_charging { " (charging)" } else { "" }
-						);
+	fn update_icon(&mut self, battery_info: &BatteryInfo) -> Result<(), String> {
+		let alert_style = self.config.alert_style(alert_level::alert_level(battery_info, &self.config.alert_thresholds()));
+		self.icon_builder.set_alert_style(alert_style);
+
+		// Animations wait until a rotation has finished sliding to the next view
+		let animation = match self.rotation_frame {
+			Some(RotationFrame { transition: Some(_), .. }) => IconAnimation::None,
+			_ => icon_animation::choose_animation(battery_info, alert_style, self.config.animations),
+		};
+		self.animation_frames = match animation {
+			IconAnimation::None => Vec::new(),
+			animation => self.icon_builder.create_animation_frames(animation, battery_info.percentage, battery_info.time_remaining(), battery_info.discharge_rate_percent, battery_info.power_draw_watts, battery_info.is_charging)
+				.map_err(|e| format!("Couldn't build animation frames: {:?}", e))?,
+		};
+		self.animation_clock.restart(self.animation_frames.len(), icon_animation::frame_time(animation), Instant::now());
+
+		// Create new icon image
+		let icon_image = match self.animation_frames.first() {
+			Some(frame) => frame.clone(),
+			None => {
+				let Ok(icon_image) = self.icon_builder.create_icon(battery_info.percentage, battery_info.time_remaining(), battery_info.discharge_rate_percent, battery_info.power_draw_watts, battery_info.is_charging) else {
+					return Err(format!("Couldn't build icon"));
+				};
+				icon_image
+			}
+		};
+		let icon = icon_from_image(icon_image)?;
+
+		// The menu is created along with the tray icon, after the event loop has started
+		if self.menu.is_none() {
+			let menu = TrayMenu::new(&self.config)?;
+			menu.set_sleep_drain(&self.sleep_drain_text());
+			self.menu = Some(menu);
+		}
+		if let Some(menu) = &self.menu {
+			menu.set_status(&tray_menu::format_status(battery_info));
+		}
+
+		// Create or update tray icon
+		match (&self.tray_icon, &self.menu) {
+			(None, Some(menu)) => {
+				match create_tray_icon(icon, &self.tooltip(), menu) {
+					Ok(t) => {
+						self.tray_icon = Some(t);
 						Ok(())
 					}
+					Err(e) => {
+						Err(e)
+					}
+				}
+			}
+			(Some(t), _) => {
+				if let Err(e) = t.set_icon(Some(icon)) {
+					Err(format!("Failed to update tray icon: {:?}", e))
+				}
+				else {
+					dmsg!("Updated tray icon to {}%{}",
+						battery_info.percentage,
+						if battery_info.is_charging { " (charging)" } else { "" }
+					);
+					Ok(())
 				}
 			}
+			(None, None) => Ok(()),
 		}
 	}
 }
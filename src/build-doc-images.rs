@@ -8,7 +8,7 @@ pub enum UserEvent {
 }
 
 fn build_image(icon_builder: &IconBuilder, percent: i32, discharge_rate_percent: i32, is_charging: bool) {
-	let icon = icon_builder.create_percentage_icon(percent, discharge_rate_percent, is_charging).unwrap();
+	let icon = icon_builder.create_percentage_icon(percent, discharge_rate_percent, 0.0, is_charging).unwrap();
 	let file_name = format!(
 		"doc-images/icon_p{}_dr{}_c{}.png",
 		percent,
@@ -0,0 +1,369 @@
+use std::io::{Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::time::{Duration, Instant, SystemTime};
+use crate::battery_monitor::BatteryInfo;
+use crate::config::{self, Config, NamedSetting};
+use crate::control;
+use crate::mqtt_publisher::MqttPublisher;
+use crate::shell;
+use crate::sleep_log;
+use crate::debug_util::dmsg;
+
+const CHARGE_CONTROL_LOG_FILE_NAME: &str = "charge-control.log";
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChargeControlMode {
+	Off,
+	On,
+	/// Decide and log as usual, but don't run any actions
+	DryRun,
+}
+
+impl NamedSetting for ChargeControlMode {
+	const NAMES: &'static [(Self, &'static str)] = &[
+		(ChargeControlMode::Off, "off"),
+		(ChargeControlMode::On, "on"),
+		(ChargeControlMode::DryRun, "dry_run"),
+	];
+}
+
+/// What to do to switch charging on or off, written in the config as `command:<command line>`,
+/// `webhook:<http url>` or `mqtt:<topic> <payload>`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ChargeAction {
+	Command(String),
+	Webhook(String),
+	Mqtt { topic: String, payload: String },
+}
+
+impl ChargeAction {
+	pub fn parse(text: &str) -> Result<Self, String> {
+		match text.split_once(':') {
+			Some(("command", command)) => Ok(ChargeAction::Command(command.trim().to_string())),
+			Some(("webhook", url)) => Ok(ChargeAction::Webhook(url.trim().to_string())),
+			Some(("mqtt", message)) => {
+				let (topic, payload) = message.trim().split_once(' ').unwrap_or((message.trim(), ""));
+				Ok(ChargeAction::Mqtt { topic: topic.to_string(), payload: payload.trim().to_string() })
+			}
+			_ => Err(format!("Expected command:, webhook: or mqtt:, got {}", text)),
+		}
+	}
+
+	pub fn to_text(&self) -> String {
+		match self {
+			ChargeAction::Command(command) => format!("command:{}", command),
+			ChargeAction::Webhook(url) => format!("webhook:{}", url),
+			ChargeAction::Mqtt { topic, payload } => format!("mqtt:{} {}", topic, payload),
+		}
+	}
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChargeCommand {
+	StartCharging,
+	StopCharging,
+}
+
+/// A decision to switch charging, with the reason it was made
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChargeDecision {
+	pub command: ChargeCommand,
+	pub reason: String,
+}
+
+/// Keeps the battery between two thresholds by switching the charger on below the start
+/// threshold and off above the stop threshold. The gap between them is the hysteresis, and
+/// after any decision no other is made until the cooldown has passed, so a charger that's
+/// slow to respond isn't switched repeatedly
+pub struct ChargeController {
+	/// None until valid thresholds are set, and no decisions are made until then
+	thresholds: Option<(i32, i32)>,
+	cooldown: Duration,
+	last_decision_at: Option<Instant>,
+}
+
+impl ChargeController {
+	pub fn new() -> Self {
+		Self { thresholds: None, cooldown: Duration::ZERO, last_decision_at: None }
+	}
+
+	/// Invalid thresholds are rejected and stop any decisions, rather than switching the
+	/// charger back and forth
+	pub fn set_thresholds(&mut self, start_percent: i32, stop_percent: i32, cooldown: Duration) -> Result<(), String> {
+		self.cooldown = cooldown;
+		self.thresholds = None;
+		check_charge_thresholds(start_percent, stop_percent)?;
+		self.thresholds = Some((start_percent, stop_percent));
+		Ok(())
+	}
+
+	pub fn decide(&mut self, battery_info: &BatteryInfo, now: Instant) -> Option<ChargeDecision> {
+		let (start_percent, stop_percent) = self.thresholds?;
+		if self.last_decision_at.is_some_and(|at| now.duration_since(at) < self.cooldown) {
+			return None;
+		}
+
+		let percentage = battery_info.percentage;
+		let decision = if percentage <= start_percent && !battery_info.is_charging {
+			ChargeDecision {
+				command: ChargeCommand::StartCharging,
+				reason: format!("{}% is at or below {}% and not plugged in", percentage, start_percent),
+			}
+		}
+		else if percentage >= stop_percent && battery_info.is_charging {
+			ChargeDecision {
+				command: ChargeCommand::StopCharging,
+				reason: format!("{}% is at or above {}% and plugged in", percentage, stop_percent),
+			}
+		}
+		else {
+			return None;
+		};
+
+		self.last_decision_at = Some(now);
+		Some(decision)
+	}
+}
+
+/// The thresholds need a gap between them, or the charger would be switched on and off at one level
+pub fn check_charge_thresholds(start_percent: i32, stop_percent: i32) -> Result<(), String> {
+	if start_percent < stop_percent {
+		Ok(())
+	}
+	else {
+		Err(format!("Charge start percent {} must be below the stop percent {}", start_percent, stop_percent))
+	}
+}
+
+/// Log a decision and, unless this is a dry run, run the configured action for it
+pub fn carry_out(decision: &ChargeDecision, battery_info: &BatteryInfo, config: &Config, mqtt_publisher: Option<&MqttPublisher>) {
+	let outcome = decision_outcome(decision.command, config, |action| run_action(action, decision.command, battery_info, mqtt_publisher));
+	log_decision(&format_decision(decision, &outcome, SystemTime::now()));
+}
+
+// What became of a decision, for the log. `run` carries out the action unless this is a dry run
+fn decision_outcome<F: FnOnce(&ChargeAction) -> Result<(), String>>(command: ChargeCommand, config: &Config, run: F) -> String {
+	let dry_run = config.charge_control == ChargeControlMode::DryRun;
+	let action = match command {
+		ChargeCommand::StartCharging => config.charge_start_action.as_ref(),
+		ChargeCommand::StopCharging => config.charge_stop_action.as_ref(),
+	};
+
+	match (action, dry_run) {
+		(None, _) => "no action configured".to_string(),
+		(Some(action), true) => format!("dry run, would {}", action.to_text()),
+		(Some(action), false) => match run(action) {
+			Ok(()) => action.to_text(),
+			Err(e) => format!("{} failed: {}", action.to_text(), e),
+		},
+	}
+}
+
+pub fn format_decision(decision: &ChargeDecision, outcome: &str, time: SystemTime) -> String {
+	let command = match decision.command {
+		ChargeCommand::StartCharging => "start charging",
+		ChargeCommand::StopCharging => "stop charging",
+	};
+	format!("{}  {}: {} ({})\n", sleep_log::format_utc(time), command, decision.reason, outcome)
+}
+
+fn log_decision(line: &str) {
+	dmsg!("Charge control: {}", line.trim_end());
+	let result = config::app_dir()
+		.ok_or_else(|| "Couldn't find a log directory".to_string())
+		.and_then(|dir| {
+			std::fs::create_dir_all(&dir).map_err(|e| format!("Failed to create {:?}: {:?}", dir, e))?;
+			let path = dir.join(CHARGE_CONTROL_LOG_FILE_NAME);
+			std::fs::OpenOptions::new().create(true).append(true).open(&path)
+				.and_then(|mut file| file.write_all(line.as_bytes()))
+				.map_err(|e| format!("Failed to write {:?}: {:?}", path, e))
+		});
+	if let Err(_e) = result {
+		dmsg!("Failed to log charge control decision: {}", _e);
+	}
+}
+
+fn run_action(action: &ChargeAction, command: ChargeCommand, battery_info: &BatteryInfo, mqtt_publisher: Option<&MqttPublisher>) -> Result<(), String> {
+	let command_name = match command {
+		ChargeCommand::StartCharging => "start",
+		ChargeCommand::StopCharging => "stop",
+	};
+	match action {
+		ChargeAction::Command(command_line) => {
+			let mut command = shell::shell_command(command_line);
+			command.env("BATTERY_PERCENT", battery_info.percentage.to_string());
+			command.env("CHARGE_COMMAND", command_name);
+			shell::spawn_detached(command)
+		}
+		ChargeAction::Webhook(url) => {
+			let url = url.clone();
+			let body = serde_json::json!({ "command": command_name, "battery": control::battery_info_json(battery_info) }).to_string();
+			// Plugs can be slow to answer, so don't hold up the event loop
+			std::thread::spawn(move || {
+				if let Err(_e) = post_webhook(&url, &body) {
+					dmsg!("Charge control webhook failed: {}", _e);
+				}
+			});
+			Ok(())
+		}
+		ChargeAction::Mqtt { topic, payload } => {
+			let mqtt_publisher = mqtt_publisher.ok_or_else(|| "No MQTT broker configured".to_string())?;
+			if mqtt_publisher.publish_message(topic, payload) {
+				Ok(())
+			}
+			else {
+				Err("Not connected to the MQTT broker".to_string())
+			}
+		}
+	}
+}
+
+// Plain HTTP is enough for smart plugs on the local network, which rarely offer TLS anyway
+fn post_webhook(url: &str, body: &str) -> Result<(), String> {
+	let rest = url.strip_prefix("http://").ok_or_else(|| format!("Only http:// webhooks are supported, got {}", url))?;
+	let (host, path) = match rest.find('/') {
+		Some(i) => (&rest[..i], &rest[i..]),
+		None => (rest, "/"),
+	};
+	let address = if host.contains(':') { host.to_string() } else { format!("{}:80", host) };
+
+	// Resolved first, as connect_timeout takes a single address
+	let socket_address = address.to_socket_addrs().map_err(|e| format!("Failed to resolve {}: {:?}", address, e))?
+		.next().ok_or_else(|| format!("No address found for {}", address))?;
+	let mut stream = TcpStream::connect_timeout(&socket_address, WEBHOOK_TIMEOUT).map_err(|e| format!("Failed to connect to {}: {:?}", address, e))?;
+	stream.set_read_timeout(Some(WEBHOOK_TIMEOUT)).map_err(|e| format!("{:?}", e))?;
+	stream.set_write_timeout(Some(WEBHOOK_TIMEOUT)).map_err(|e| format!("{:?}", e))?;
+	let request = format!(
+		"POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+		path, host, body.len(), body,
+	);
+	stream.write_all(request.as_bytes()).map_err(|e| format!("Failed to send request: {:?}", e))?;
+
+	let mut response = Vec::new();
+	stream.read_to_end(&mut response).map_err(|e| format!("Failed to read response: {:?}", e))?;
+	let response = String::from_utf8_lossy(&response);
+	let status = response.split_whitespace().nth(1).unwrap_or("");
+	if status.starts_with('2') { Ok(()) } else { Err(format!("Webhook returned {}", status)) }
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use starship_battery::State;
+
+	const COOLDOWN: Duration = Duration::from_secs(600);
+
+	fn battery(percentage: i32, is_charging: bool) -> BatteryInfo {
+		BatteryInfo {
+			percentage,
+			discharge_rate_percent: 0,
+			power_draw_watts: 0.0,
+			instant_power_draw_watts: 0.0,
+			is_charging,
+			state: if is_charging { State::Charging } else { State::Discharging },
+			health_percent: 100,
+			cycle_count: None,
+		}
+	}
+
+	fn controller() -> ChargeController {
+		let mut controller = ChargeController::new();
+		controller.set_thresholds(20, 80, COOLDOWN).unwrap();
+		controller
+	}
+
+	fn command(decision: Option<ChargeDecision>) -> Option<ChargeCommand> {
+		decision.map(|decision| decision.command)
+	}
+
+	#[test]
+	fn switches_only_outside_the_thresholds() {
+		let now = Instant::now();
+		let mut controller = controller();
+		// Between the thresholds nothing happens, whichever way the battery is going
+		assert_eq!(command(controller.decide(&battery(50, false), now)), None);
+		assert_eq!(command(controller.decide(&battery(50, true), now)), None);
+		assert_eq!(command(controller.decide(&battery(79, true), now)), None);
+		assert_eq!(command(controller.decide(&battery(21, false), now)), None);
+		// Already doing the right thing
+		assert_eq!(command(controller.decide(&battery(20, true), now)), None);
+		assert_eq!(command(controller.decide(&battery(80, false), now)), None);
+
+		assert_eq!(command(controller.decide(&battery(20, false), now)), Some(ChargeCommand::StartCharging));
+		let mut controller = self::controller();
+		assert_eq!(command(controller.decide(&battery(80, true), now)), Some(ChargeCommand::StopCharging));
+	}
+
+	#[test]
+	fn waits_for_the_cooldown() {
+		let start = Instant::now();
+		let mut controller = controller();
+		assert!(controller.decide(&battery(15, false), start).is_some());
+		assert!(controller.decide(&battery(15, false), start + COOLDOWN - Duration::from_secs(1)).is_none());
+		assert!(controller.decide(&battery(85, true), start + COOLDOWN / 2).is_none());
+		assert_eq!(command(controller.decide(&battery(85, true), start + COOLDOWN)), Some(ChargeCommand::StopCharging));
+	}
+
+	#[test]
+	fn rejects_thresholds_without_a_gap() {
+		let mut controller = controller();
+		assert!(controller.set_thresholds(80, 80, COOLDOWN).is_err());
+		assert!(controller.set_thresholds(90, 20, COOLDOWN).is_err());
+		assert!(controller.decide(&battery(5, false), Instant::now()).is_none());
+
+		let mut config = Config::default();
+		assert!(config.set("charge_start_percent", "90").is_err());
+		assert_eq!(config.charge_start_percent, 20);
+		let config = Config::parse("charge_start_percent = 85\ncharge_stop_percent = 95\n");
+		assert_eq!((config.charge_start_percent, config.charge_stop_percent), (85, 95));
+	}
+
+	#[test]
+	fn dry_run_doesnt_run_the_action() {
+		let mut config = Config::default();
+		config.set("charge_control", "dry_run").unwrap();
+		config.set("charge_start_action", "command:plug on").unwrap();
+		let outcome = decision_outcome(ChargeCommand::StartCharging, &config, |_| panic!("ran the action"));
+		assert_eq!(outcome, "dry run, would command:plug on");
+		let outcome = decision_outcome(ChargeCommand::StopCharging, &config, |_| panic!("ran the action"));
+		assert_eq!(outcome, "no action configured");
+
+		config.set("charge_control", "on").unwrap();
+		let outcome = decision_outcome(ChargeCommand::StartCharging, &config, |_| Err("Not connected".to_string()));
+		assert_eq!(outcome, "command:plug on failed: Not connected");
+	}
+
+	// Answers one request with the given status line, and hands back what was sent
+	fn serve_once(status: &'static str) -> (String, std::thread::JoinHandle<String>) {
+		let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+		let url = format!("http://{}/plug", listener.local_addr().unwrap());
+		let thread = std::thread::spawn(move || {
+			let (mut stream, _) = listener.accept().unwrap();
+			let mut request = Vec::new();
+			let mut buf = [0u8; 1024];
+			while !String::from_utf8_lossy(&request).ends_with("{\"on\":true}") {
+				let read = stream.read(&mut buf).unwrap();
+				request.extend_from_slice(&buf[..read]);
+			}
+			write!(stream, "HTTP/1.1 {}\r\nContent-Length: 0\r\n\r\n", status).unwrap();
+			String::from_utf8(request).unwrap()
+		});
+		(url, thread)
+	}
+
+	#[test]
+	fn posts_webhooks() {
+		let (url, server) = serve_once("204 No Content");
+		assert_eq!(post_webhook(&url, "{\"on\":true}"), Ok(()));
+		let request = server.join().unwrap();
+		assert!(request.starts_with("POST /plug HTTP/1.1\r\n"));
+		assert!(request.contains("Content-Length: 11\r\n"));
+
+		let (url, server) = serve_once("500 Internal Server Error");
+		assert_eq!(post_webhook(&url, "{\"on\":true}"), Err("Webhook returned 500".to_string()));
+		server.join().unwrap();
+
+		assert!(post_webhook("https://example.com/", "").is_err());
+	}
+}
@@ -0,0 +1,146 @@
+use std::path::PathBuf;
+use crate::autostart;
+use crate::config::{Config, NamedSetting};
+use crate::control::ControlRequest;
+use crate::icon_render;
+use crate::single_instance;
+use crate::status_bar::{self, BarFormat};
+use crate::tui;
+use crate::sleep_log::{self, SleepLog};
+
+const USAGE: &str = "\
+Usage: windows-tray-battery-percent [COMMAND]
+
+With no command the tray icon is started, unless it's already running.
+
+Commands:
+  sleep-report    Show battery drain during recent sleeps
+  autostart [on|off]
+                  Start the tray icon at login, or show whether it does
+  show-report     Ask the running tray icon to open the battery report
+  reload-config   Ask the running tray icon to reload its config file
+  status          Show the running tray icon's battery status as JSON
+  send JSON       Send a control request to the running tray icon and show the result
+  bar i3bar|waybar|polybar
+                  Keep printing the battery status for a status bar
+  tui [--demo]    Show a live battery dashboard in the terminal, or one for a
+                  simulated battery
+  render MANIFEST Write the icons listed in a JSON manifest as ICO and SVG files
+  help            Show this message
+";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Command {
+	Tray,
+	SleepReport,
+	/// Turn start at login on or off, or None to report the current setting
+	Autostart(Option<bool>),
+	/// Pass a request on to the running tray icon
+	Forward(ControlRequest),
+	/// Pass a line of JSON on to the running tray icon
+	Send(String),
+	/// Stream the battery status for a status bar
+	Bar(BarFormat),
+	/// Show the terminal dashboard, for a simulated battery if true
+	Tui(bool),
+	/// Render the icons listed in a manifest file
+	Render(PathBuf),
+	Help,
+}
+
+pub fn parse_args<I: IntoIterator<Item = String>>(args: I) -> Result<Command, String> {
+	let mut args = args.into_iter();
+	let command = match args.next().as_deref() {
+		None => Command::Tray,
+		Some("sleep-report") => Command::SleepReport,
+		Some("autostart") => match args.next().as_deref() {
+			None => Command::Autostart(None),
+			Some("on") => Command::Autostart(Some(true)),
+			Some("off") => Command::Autostart(Some(false)),
+			Some(other) => return Err(format!("Expected on or off, got {}\n\n{}", other, USAGE)),
+		},
+		Some("show-report") => Command::Forward(ControlRequest::ShowReport),
+		Some("reload-config") => Command::Forward(ControlRequest::ReloadConfig),
+		Some("status") => Command::Forward(ControlRequest::GetStatus),
+		Some("send") => match args.next() {
+			Some(json) => Command::Send(json),
+			None => return Err(format!("Expected a JSON request\n\n{}", USAGE)),
+		},
+		Some("bar") => match args.next().as_deref().map(BarFormat::from_name) {
+			Some(Some(format)) => Command::Bar(format),
+			_ => return Err(format!("Expected i3bar, waybar or polybar\n\n{}", USAGE)),
+		},
+		Some("tui") => match args.next().as_deref() {
+			None => Command::Tui(false),
+			Some("--demo") => Command::Tui(true),
+			Some(other) => return Err(format!("Unexpected argument {}\n\n{}", other, USAGE)),
+		},
+		Some("render") => match args.next() {
+			Some(manifest) => Command::Render(PathBuf::from(manifest)),
+			None => return Err(format!("Expected a manifest file\n\n{}", USAGE)),
+		},
+		Some("help" | "--help" | "-h") => Command::Help,
+		Some(other) => return Err(format!("Unknown command {}\n\n{}", other, USAGE)),
+	};
+
+	if let Some(extra) = args.next() {
+		return Err(format!("Unexpected argument {}\n\n{}", extra, USAGE));
+	}
+	Ok(command)
+}
+
+/// Run a command that doesn't start the tray icon
+pub fn run(command: Command) -> Result<(), String> {
+	attach_console();
+
+	match command {
+		Command::Tray => {}
+		Command::Help => print!("{}", USAGE),
+		Command::SleepReport => {
+			let config = Config::load();
+			print!("{}", sleep_log::format_sleep_report(&SleepLog::load(), config.sleep_drain_warning_percent_per_hour));
+		}
+		Command::Autostart(enable) => {
+			let autostart = autostart::platform_autostart()?;
+			if let Some(enable) = enable {
+				autostart.set_enabled(enable)?;
+			}
+			let enabled = autostart.is_enabled()?;
+			println!("Start at login is {}", if enabled { "on" } else { "off" });
+		}
+		Command::Forward(request) => print_result(single_instance::request(request)),
+		Command::Send(json) => print_result(single_instance::send_line(&json)),
+		Command::Bar(format) => status_bar::run(format)?,
+		Command::Tui(demo) => tui::run(demo)?,
+		Command::Render(manifest) => icon_render::run(&manifest)?,
+	}
+	Ok(())
+}
+
+fn print_result(result: Result<serde_json::Value, String>) {
+	match result {
+		Ok(serde_json::Value::Null) => {}
+		Ok(value) => println!("{}", serde_json::to_string_pretty(&value).unwrap_or_default()),
+		Err(e) => {
+			eprintln!("{}", e);
+			std::process::exit(1);
+		}
+	}
+}
+
+/// Report a bad command line and exit
+pub fn exit_with_error(message: &str) -> ! {
+	attach_console();
+	eprintln!("{}", message);
+	std::process::exit(2);
+}
+
+// Release builds use the windows subsystem so they have no console of their own. Attach to
+// the console of the shell we were started from so command output is visible
+fn attach_console() {
+	#[cfg(target_os = "windows")]
+	unsafe {
+		use windows_sys::Win32::System::Console::{AttachConsole, ATTACH_PARENT_PROCESS};
+		AttachConsole(ATTACH_PARENT_PROCESS);
+	}
+}
@@ -0,0 +1,559 @@
+use std::path::PathBuf;
+use std::time::Duration;
+use crate::icon_builder::{AlertStyle, IconDisplay, IconLayout, OutlineStyle, OverlayMode, Theme, TimeFormat, DEFAULT_OUTLINE_THICKNESS, DEFAULT_POWER_DRAW_CEILING_WATTS};
+use crate::alert_level::{AlertLevel, AlertThresholds};
+use crate::mqtt_publisher::{self, MqttSettings};
+use crate::charge_control::{check_charge_thresholds, ChargeAction, ChargeControlMode};
+use crate::battery_events::{BatteryEvent, DEFAULT_LOW_BATTERY_PERCENT};
+use crate::tray_click::{ClickAction, TrayClick};
+use crate::debug_util::dmsg;
+use crate::battery_monitor::MAX_SAMPLE_INTERVAL;
+
+const APP_DIR_NAME: &str = "windows-tray-battery-percent";
+const CONFIG_FILE_NAME: &str = "config.ini";
+
+/// Directory holding the config file and logs, created on demand
+pub fn app_dir() -> Option<PathBuf> {
+	#[cfg(target_os = "windows")]
+	let base = std::env::var_os("APPDATA").map(PathBuf::from);
+
+	#[cfg(not(target_os = "windows"))]
+	let base = std::env::var_os("XDG_CONFIG_HOME").map(PathBuf::from)
+		.or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")));
+
+	base.map(|base| base.join(APP_DIR_NAME))
+}
+
+/// User settings, stored as `key = value` lines
+#[derive(Debug, Clone, PartialEq)]
+pub struct Config {
+	pub theme: Theme,
+	pub layout: IconLayout,
+	pub display: IconDisplay,
+	pub time_format: TimeFormat,
+	/// Displays the icon takes turns showing, empty to always show `display`
+	pub rotate_views: Vec<IconDisplay>,
+	pub rotate_seconds: u64,
+	/// Directory or zip archive of digit images to use instead of the built in ones
+	pub glyph_pack: Option<PathBuf>,
+	pub outline_style: OutlineStyle,
+	pub outline_color: [u8; 3],
+	/// Outline width in pixels on the 64 pixel icon
+	pub outline_thickness: u32,
+	pub overlay_mode: OverlayMode,
+	/// Power draw that fills the whole icon red in the power draw overlay mode
+	pub power_draw_ceiling_watts: u32,
+	/// Fixed time between battery samples, or None to adapt to what the battery is doing
+	pub update_interval_seconds: Option<u64>,
+	pub notifications: bool,
+	/// Animate the icon while charging
+	pub animations: bool,
+	/// The warning and critical levels start at or below this charge, or this much time left
+	pub warning_percent: i32,
+	pub warning_minutes: u64,
+	pub critical_percent: i32,
+	pub critical_minutes: u64,
+	pub warning_style: AlertStyle,
+	pub critical_style: AlertStyle,
+	/// Sleep drain above this is flagged in the tooltip, menu and report
+	pub sleep_drain_warning_percent_per_hour: f32,
+	/// Localhost port for the Prometheus metrics endpoint, or None to not serve metrics
+	pub metrics_port: Option<u16>,
+	/// MQTT broker as host:port, or None to not publish
+	pub mqtt_broker: Option<String>,
+	/// Prefix for published topics, or None for one based on the host name
+	pub mqtt_topic_prefix: Option<String>,
+	pub mqtt_username: Option<String>,
+	pub mqtt_password: Option<String>,
+	pub charge_control: ChargeControlMode,
+	/// Charging is switched on at or below this
+	pub charge_start_percent: i32,
+	/// Charging is switched off at or above this
+	pub charge_stop_percent: i32,
+	/// Least time between two charge control decisions
+	pub charge_cooldown_seconds: u64,
+	pub charge_start_action: Option<ChargeAction>,
+	pub charge_stop_action: Option<ChargeAction>,
+	/// The low battery event fires below this
+	pub low_battery_percent: i32,
+	/// Commands to run on battery events
+	pub hooks: Vec<(BatteryEvent, String)>,
+	/// Hooks still running after this long are killed
+	pub hook_timeout_seconds: u64,
+	pub left_click_action: ClickAction,
+	pub double_click_action: ClickAction,
+	pub middle_click_action: ClickAction,
+}
+
+impl Default for Config {
+	fn default() -> Self {
+		Self {
+			theme: Theme::LightDigits,
+			layout: IconLayout::Stretched,
+			display: IconDisplay::Percentage,
+			time_format: TimeFormat::Compact,
+			rotate_views: Vec::new(),
+			rotate_seconds: 5,
+			glyph_pack: None,
+			outline_style: OutlineStyle::Off,
+			outline_color: [0, 0, 0],
+			outline_thickness: DEFAULT_OUTLINE_THICKNESS,
+			overlay_mode: OverlayMode::DischargeRate,
+			power_draw_ceiling_watts: DEFAULT_POWER_DRAW_CEILING_WATTS,
+			update_interval_seconds: None,
+			notifications: true,
+			animations: false,
+			warning_percent: 20,
+			warning_minutes: 30,
+			critical_percent: 10,
+			critical_minutes: 15,
+			// Off until asked for, so the icon looks as it always has
+			warning_style: AlertStyle::None,
+			critical_style: AlertStyle::None,
+			sleep_drain_warning_percent_per_hour: 3.0,
+			metrics_port: None,
+			mqtt_broker: None,
+			mqtt_topic_prefix: None,
+			mqtt_username: None,
+			mqtt_password: None,
+			charge_control: ChargeControlMode::Off,
+			charge_start_percent: 20,
+			charge_stop_percent: 80,
+			charge_cooldown_seconds: 300,
+			charge_start_action: None,
+			charge_stop_action: None,
+			low_battery_percent: DEFAULT_LOW_BATTERY_PERCENT,
+			hooks: Vec::new(),
+			hook_timeout_seconds: 30,
+			left_click_action: ClickAction::ShowHistory,
+			double_click_action: ClickAction::None,
+			middle_click_action: ClickAction::Refresh,
+		}
+	}
+}
+
+/// Text names for settings that are one of a fixed set of values, used in the config file and menu ids
+pub trait NamedSetting: Copy + PartialEq + 'static {
+	const NAMES: &'static [(Self, &'static str)];
+
+	fn name(&self) -> &'static str {
+		Self::NAMES.iter().find(|(v, _)| v == self).map(|(_, name)| *name).unwrap_or("")
+	}
+
+	fn from_name(name: &str) -> Option<Self> {
+		Self::NAMES.iter().find(|(_, n)| *n == name).map(|(v, _)| *v)
+	}
+
+	/// The value after this one, going back to the first after the last
+	fn next(&self) -> Self {
+		let index = Self::NAMES.iter().position(|(v, _)| v == self).map_or(0, |i| i + 1);
+		Self::NAMES[index % Self::NAMES.len()].0
+	}
+}
+
+impl NamedSetting for Theme {
+	const NAMES: &'static [(Self, &'static str)] = &[
+		(Theme::LightDigits, "light_digits"),
+		(Theme::DarkDigits, "dark_digits"),
+	];
+}
+
+impl NamedSetting for IconLayout {
+	const NAMES: &'static [(Self, &'static str)] = &[
+		(IconLayout::Stretched, "stretched"),
+		(IconLayout::Proportional, "proportional"),
+	];
+}
+
+impl NamedSetting for IconDisplay {
+	const NAMES: &'static [(Self, &'static str)] = &[
+		(IconDisplay::Percentage, "percentage"),
+		(IconDisplay::TimeRemaining, "time_remaining"),
+		(IconDisplay::Watts, "watts"),
+	];
+}
+
+impl NamedSetting for TimeFormat {
+	const NAMES: &'static [(Self, &'static str)] = &[
+		(TimeFormat::Compact, "compact"),
+		(TimeFormat::HoursMinutes, "hours_minutes"),
+	];
+}
+
+impl NamedSetting for OutlineStyle {
+	const NAMES: &'static [(Self, &'static str)] = &[
+		(OutlineStyle::Off, "off"),
+		(OutlineStyle::Outline, "outline"),
+		(OutlineStyle::Shadow, "shadow"),
+	];
+}
+
+impl NamedSetting for AlertStyle {
+	const NAMES: &'static [(Self, &'static str)] = &[
+		(AlertStyle::None, "none"),
+		(AlertStyle::RedBackground, "red_background"),
+		(AlertStyle::Inverted, "inverted"),
+		(AlertStyle::Blink, "blink"),
+	];
+}
+
+impl NamedSetting for OverlayMode {
+	const NAMES: &'static [(Self, &'static str)] = &[
+		(OverlayMode::DischargeRate, "discharge_rate"),
+		(OverlayMode::PowerDraw, "power_draw"),
+	];
+}
+
+impl Config {
+	/// Load the config file, using defaults for anything missing or invalid
+	pub fn load() -> Self {
+		let Some(path) = app_dir().map(|dir| dir.join(CONFIG_FILE_NAME)) else {
+			return Self::default();
+		};
+		match std::fs::read_to_string(&path) {
+			Ok(text) => Self::parse(&text),
+			Err(_e) => {
+				dmsg!("Couldn't read config {:?}: {}", path, _e);
+				Self::default()
+			}
+		}
+	}
+
+	pub fn save(&self) -> Result<(), String> {
+		let dir = app_dir().ok_or_else(|| "Couldn't find a config directory".to_string())?;
+		std::fs::create_dir_all(&dir).map_err(|e| format!("Failed to create {:?}: {:?}", dir, e))?;
+		let path = dir.join(CONFIG_FILE_NAME);
+		std::fs::write(&path, self.to_text()).map_err(|e| format!("Failed to write {:?}: {:?}", path, e))
+	}
+
+	pub fn parse(text: &str) -> Self {
+		let mut config = Self::default();
+		for line in text.lines() {
+			let line = line.trim();
+			if line.is_empty() || line.starts_with('#') {
+				continue;
+			}
+			let Some((key, value)) = line.split_once('=') else {
+				dmsg!("Ignoring config line: {}", line);
+				continue;
+			};
+			if let Err(_e) = config.set_value(key.trim(), value.trim()) {
+				dmsg!("Ignoring config line: {}: {}", line, _e);
+			}
+		}
+		// The thresholds are checked once both are read, as either may come first in the file
+		if let Err(_e) = config.check_charge_thresholds() {
+			dmsg!("Ignoring charge thresholds: {}", _e);
+			let defaults = Self::default();
+			config.charge_start_percent = defaults.charge_start_percent;
+			config.charge_stop_percent = defaults.charge_stop_percent;
+		}
+		config
+	}
+
+	/// Set a single setting from its text form
+	pub fn set(&mut self, key: &str, value: &str) -> Result<(), String> {
+		let mut config = self.clone();
+		config.set_value(key, value)?;
+		config.check_charge_thresholds()?;
+		*self = config;
+		Ok(())
+	}
+
+	fn check_charge_thresholds(&self) -> Result<(), String> {
+		check_charge_thresholds(self.charge_start_percent, self.charge_stop_percent)
+	}
+
+	fn set_value(&mut self, key: &str, value: &str) -> Result<(), String> {
+		match key {
+			"theme" => self.theme = parse_named(value)?,
+			"layout" => self.layout = parse_named(value)?,
+			"display" => self.display = parse_named(value)?,
+			"time_format" => self.time_format = parse_named(value)?,
+			"rotate_views" => {
+				self.rotate_views = match value {
+					"off" => Vec::new(),
+					views => views.split(',').map(|view| parse_named(view.trim())).collect::<Result<_, _>>()?,
+				};
+			}
+			"rotate_seconds" => self.rotate_seconds = parse_value(value)?,
+			"glyph_pack" => self.glyph_pack = parse_optional(value, "off").map(PathBuf::from),
+			"outline" => self.outline_style = parse_named(value)?,
+			"outline_color" => self.outline_color = parse_color(value)?,
+			"outline_thickness" => self.outline_thickness = parse_value(value)?,
+			"overlay_mode" => self.overlay_mode = parse_named(value)?,
+			"power_draw_ceiling_watts" => self.power_draw_ceiling_watts = parse_value(value)?,
+			"update_interval" => {
+				self.update_interval_seconds = match value {
+					"auto" => None,
+					seconds => Some(parse_update_interval(seconds)?),
+				};
+			}
+			"notifications" => self.notifications = parse_value(value)?,
+			"animations" => self.animations = parse_value(value)?,
+			"warning_percent" => self.warning_percent = parse_value(value)?,
+			"warning_minutes" => self.warning_minutes = parse_value(value)?,
+			"critical_percent" => self.critical_percent = parse_value(value)?,
+			"critical_minutes" => self.critical_minutes = parse_value(value)?,
+			"warning_style" => self.warning_style = parse_named(value)?,
+			"critical_style" => self.critical_style = parse_named(value)?,
+			"sleep_drain_warning_percent_per_hour" => {
+				self.sleep_drain_warning_percent_per_hour = parse_value(value)?;
+			}
+			"metrics_port" => {
+				self.metrics_port = match value {
+					"off" => None,
+					port => Some(parse_value(port)?),
+				};
+			}
+			"mqtt_broker" => self.mqtt_broker = parse_optional(value, "off"),
+			"mqtt_topic_prefix" => self.mqtt_topic_prefix = parse_optional(value, "auto"),
+			"mqtt_username" => self.mqtt_username = parse_optional(value, ""),
+			"mqtt_password" => self.mqtt_password = parse_optional(value, ""),
+			"charge_control" => self.charge_control = parse_named(value)?,
+			"charge_start_percent" => self.charge_start_percent = parse_value(value)?,
+			"charge_stop_percent" => self.charge_stop_percent = parse_value(value)?,
+			"charge_cooldown_seconds" => self.charge_cooldown_seconds = parse_value(value)?,
+			"charge_start_action" => self.charge_start_action = parse_action(value)?,
+			"charge_stop_action" => self.charge_stop_action = parse_action(value)?,
+			"low_battery_percent" => self.low_battery_percent = parse_value(value)?,
+			"hook_timeout_seconds" => self.hook_timeout_seconds = parse_value(value)?,
+			"left_click" => self.left_click_action = parse_named(value)?,
+			"double_click" => self.double_click_action = parse_named(value)?,
+			"middle_click" => self.middle_click_action = parse_named(value)?,
+			_ => {
+				// Hooks are keyed by event, e.g. hook_plugged_in
+				let event = key.strip_prefix("hook_").and_then(BatteryEvent::from_name)
+					.ok_or_else(|| format!("Unknown setting {}", key))?;
+				self.hooks.retain(|(e, _)| *e != event);
+				if !value.is_empty() {
+					self.hooks.push((event, value.to_string()));
+				}
+			}
+		}
+		Ok(())
+	}
+
+	/// Settings for the MQTT publisher, if a broker is configured
+	pub fn mqtt_settings(&self) -> Option<MqttSettings> {
+		let broker = self.mqtt_broker.clone()?;
+		Some(MqttSettings {
+			broker,
+			topic_prefix: self.mqtt_topic_prefix.clone().unwrap_or_else(mqtt_publisher::default_topic_prefix),
+			username: self.mqtt_username.clone(),
+			password: self.mqtt_password.clone(),
+		})
+	}
+
+	/// The hook command for an event, if one is configured
+	pub fn hook(&self, event: BatteryEvent) -> Option<&str> {
+		self.hooks.iter().find(|(e, _)| *e == event).map(|(_, command)| command.as_str())
+	}
+
+	pub fn alert_thresholds(&self) -> AlertThresholds {
+		AlertThresholds {
+			warning_percent: self.warning_percent,
+			critical_percent: self.critical_percent,
+			warning_time: Duration::from_secs(self.warning_minutes * 60),
+			critical_time: Duration::from_secs(self.critical_minutes * 60),
+		}
+	}
+
+	pub fn alert_style(&self, level: AlertLevel) -> AlertStyle {
+		match level {
+			AlertLevel::Normal => AlertStyle::None,
+			AlertLevel::Warning => self.warning_style,
+			AlertLevel::Critical => self.critical_style,
+		}
+	}
+
+	pub fn click_action(&self, click: TrayClick) -> ClickAction {
+		match click {
+			TrayClick::Left => self.left_click_action,
+			TrayClick::Double => self.double_click_action,
+			TrayClick::Middle => self.middle_click_action,
+		}
+	}
+
+	pub fn to_text(&self) -> String {
+		let mut text = String::new();
+		text += &format!("theme = {}\n", self.theme.name());
+		text += &format!("layout = {}\n", self.layout.name());
+		text += &format!("display = {}\n", self.display.name());
+		text += &format!("time_format = {}\n", self.time_format.name());
+		if self.rotate_views.is_empty() {
+			text += "rotate_views = off\n";
+		}
+		else {
+			text += &format!("rotate_views = {}\n", self.rotate_views.iter().map(|view| view.name()).collect::<Vec<_>>().join(", "));
+		}
+		text += &format!("rotate_seconds = {}\n", self.rotate_seconds);
+		match &self.glyph_pack {
+			None => text += "glyph_pack = off\n",
+			Some(path) => text += &format!("glyph_pack = {}\n", path.display()),
+		}
+		text += &format!("outline = {}\n", self.outline_style.name());
+		let [r, g, b] = self.outline_color;
+		text += &format!("outline_color = #{:02x}{:02x}{:02x}\n", r, g, b);
+		text += &format!("outline_thickness = {}\n", self.outline_thickness);
+		text += &format!("overlay_mode = {}\n", self.overlay_mode.name());
+		text += &format!("power_draw_ceiling_watts = {}\n", self.power_draw_ceiling_watts);
+		match self.update_interval_seconds {
+			None => text += "update_interval = auto\n",
+			Some(seconds) => text += &format!("update_interval = {}\n", seconds),
+		}
+		text += &format!("notifications = {}\n", self.notifications);
+		text += &format!("animations = {}\n", self.animations);
+		text += &format!("warning_percent = {}\n", self.warning_percent);
+		text += &format!("warning_minutes = {}\n", self.warning_minutes);
+		text += &format!("critical_percent = {}\n", self.critical_percent);
+		text += &format!("critical_minutes = {}\n", self.critical_minutes);
+		text += &format!("warning_style = {}\n", self.warning_style.name());
+		text += &format!("critical_style = {}\n", self.critical_style.name());
+		text += &format!("sleep_drain_warning_percent_per_hour = {}\n", self.sleep_drain_warning_percent_per_hour);
+		match self.metrics_port {
+			None => text += "metrics_port = off\n",
+			Some(port) => text += &format!("metrics_port = {}\n", port),
+		}
+		text += &format!("mqtt_broker = {}\n", self.mqtt_broker.as_deref().unwrap_or("off"));
+		text += &format!("mqtt_topic_prefix = {}\n", self.mqtt_topic_prefix.as_deref().unwrap_or("auto"));
+		if let Some(username) = &self.mqtt_username {
+			text += &format!("mqtt_username = {}\n", username);
+		}
+		if let Some(password) = &self.mqtt_password {
+			text += &format!("mqtt_password = {}\n", password);
+		}
+		text += &format!("charge_control = {}\n", self.charge_control.name());
+		text += &format!("charge_start_percent = {}\n", self.charge_start_percent);
+		text += &format!("charge_stop_percent = {}\n", self.charge_stop_percent);
+		text += &format!("charge_cooldown_seconds = {}\n", self.charge_cooldown_seconds);
+		text += &format!("charge_start_action = {}\n", self.charge_start_action.as_ref().map_or("none".to_string(), |a| a.to_text()));
+		text += &format!("charge_stop_action = {}\n", self.charge_stop_action.as_ref().map_or("none".to_string(), |a| a.to_text()));
+		text += &format!("low_battery_percent = {}\n", self.low_battery_percent);
+		text += &format!("hook_timeout_seconds = {}\n", self.hook_timeout_seconds);
+		for (event, command) in &self.hooks {
+			text += &format!("hook_{} = {}\n", event.name(), command);
+		}
+		text += &format!("left_click = {}\n", self.left_click_action.name());
+		text += &format!("double_click = {}\n", self.double_click_action.name());
+		text += &format!("middle_click = {}\n", self.middle_click_action.name());
+		text
+	}
+}
+
+// Text setting where one value means it's unset
+fn parse_optional(value: &str, unset: &str) -> Option<String> {
+	if value == unset { None } else { Some(value.to_string()) }
+}
+
+fn parse_action(value: &str) -> Result<Option<ChargeAction>, String> {
+	if value == "none" { Ok(None) } else { ChargeAction::parse(value).map(Some) }
+}
+
+// A colour as #rrggbb, the # being optional
+fn parse_color(value: &str) -> Result<[u8; 3], String> {
+	let hex = value.strip_prefix('#').unwrap_or(value);
+	let channel = |i: usize| hex.get(i..i + 2).and_then(|c| u8::from_str_radix(c, 16).ok());
+	match (hex.len(), channel(0), channel(2), channel(4)) {
+		(6, Some(r), Some(g), Some(b)) => Ok([r, g, b]),
+		_ => Err(format!("Invalid colour {}", value)),
+	}
+}
+
+fn parse_named<T: NamedSetting>(value: &str) -> Result<T, String> {
+	T::from_name(value).ok_or_else(|| format!("Invalid value {}", value))
+}
+
+fn parse_value<T: std::str::FromStr>(value: &str) -> Result<T, String> {
+	value.parse().map_err(|_| format!("Invalid value {}", value))
+}
+
+// Zero would sample without pause, and a long interval would look like the system slept
+fn parse_update_interval(value: &str) -> Result<u64, String> {
+	match parse_value(value)? {
+		0 => Err("The update interval must be at least a second".to_string()),
+		seconds => Ok(u64::min(seconds, MAX_SAMPLE_INTERVAL.as_secs())),
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn saved_text_parses_back_to_the_same_config() {
+		let config = Config {
+			theme: Theme::DarkDigits,
+			display: IconDisplay::TimeRemaining,
+			time_format: TimeFormat::HoursMinutes,
+			rotate_views: vec![IconDisplay::Percentage, IconDisplay::Watts],
+			glyph_pack: Some(PathBuf::from("/tmp/glyphs.zip")),
+			outline_style: OutlineStyle::Shadow,
+			outline_color: [0x12, 0xab, 0xff],
+			update_interval_seconds: Some(45),
+			notifications: false,
+			warning_style: AlertStyle::Blink,
+			sleep_drain_warning_percent_per_hour: 2.5,
+			metrics_port: Some(9184),
+			mqtt_broker: Some("localhost:1883".to_string()),
+			mqtt_username: Some("user".to_string()),
+			charge_control: ChargeControlMode::On,
+			charge_start_percent: 40,
+			charge_stop_percent: 60,
+			charge_start_action: Some(ChargeAction::Command("plug on".to_string())),
+			charge_stop_action: Some(ChargeAction::Mqtt { topic: "plug/set".to_string(), payload: "OFF".to_string() }),
+			hooks: vec![(BatteryEvent::PluggedIn, "echo in".to_string()), (BatteryEvent::LowBattery, "echo low".to_string())],
+			middle_click_action: ClickAction::None,
+			..Config::default()
+		};
+		assert_eq!(Config::parse(&config.to_text()), config);
+		assert_eq!(Config::parse(&Config::default().to_text()), Config::default());
+	}
+
+	#[test]
+	fn bad_values_are_rejected_and_ignored() {
+		let mut config = Config::default();
+		assert!(config.set("theme", "purple").is_err());
+		assert!(config.set("notifications", "maybe").is_err());
+		assert!(config.set("outline_color", "#12345").is_err());
+		assert!(config.set("metrics_port", "70000").is_err());
+		assert!(config.set("charge_start_action", "ring:bell").is_err());
+		assert!(config.set("no_such_setting", "1").is_err());
+		// Start must stay below stop, whichever is set
+		assert!(config.set("charge_start_percent", "90").is_err());
+		assert!(config.set("charge_stop_percent", "10").is_err());
+		assert_eq!(config, Config::default());
+
+		// A file keeps the good lines and defaults the rest
+		let config = Config::parse("theme = purple\nnotifications = false\nnot a setting\ncharge_start_percent = 90\n");
+		assert_eq!(config, Config { notifications: false, ..Config::default() });
+	}
+
+	#[test]
+	fn maps_clicks_to_their_actions() {
+		let mut config = Config::default();
+		assert_eq!(config.click_action(TrayClick::Left), ClickAction::ShowHistory);
+		assert_eq!(config.click_action(TrayClick::Double), ClickAction::None);
+		assert_eq!(config.click_action(TrayClick::Middle), ClickAction::Refresh);
+
+		config.set("left_click", "none").unwrap();
+		config.set("double_click", "power_settings").unwrap();
+		assert_eq!(config.click_action(TrayClick::Left), ClickAction::None);
+		assert_eq!(config.click_action(TrayClick::Double), ClickAction::PowerSettings);
+		assert!(config.set("middle_click", "explode").is_err());
+		assert_eq!(config.click_action(TrayClick::Middle), ClickAction::Refresh);
+	}
+
+	#[test]
+	fn update_interval_is_kept_in_range() {
+		let mut config = Config::default();
+		assert!(config.set("update_interval", "0").is_err());
+		assert_eq!(config.update_interval_seconds, None);
+		config.set("update_interval", "30").unwrap();
+		assert_eq!(config.update_interval_seconds, Some(30));
+		config.set("update_interval", "86400").unwrap();
+		assert_eq!(config.update_interval_seconds, Some(MAX_SAMPLE_INTERVAL.as_secs()));
+		config.set("update_interval", "auto").unwrap();
+		assert_eq!(config.update_interval_seconds, None);
+	}
+}
@@ -0,0 +1,86 @@
+use std::time::UNIX_EPOCH;
+use serde_json::{json, Value};
+use crate::battery_history::BatteryHistory;
+use crate::battery_monitor::{self, BatteryInfo};
+use crate::config::NamedSetting;
+use crate::icon_builder::Theme;
+
+/// Requests served by the running tray icon. Each is sent as a line of JSON such as
+/// `{"request": "set_theme", "theme": "dark_digits"}` and answered with a line of JSON
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ControlRequest {
+	GetStatus,
+	GetHistory,
+	ReloadConfig,
+	SetTheme(Theme),
+	ShowReport,
+}
+
+impl ControlRequest {
+	pub fn parse(line: &str) -> Result<Self, String> {
+		let value: Value = serde_json::from_str(line).map_err(|e| format!("Invalid JSON: {}", e))?;
+		let name = value["request"].as_str().ok_or_else(|| "Missing \"request\"".to_string())?;
+		match name {
+			"get_status" => Ok(ControlRequest::GetStatus),
+			"get_history" => Ok(ControlRequest::GetHistory),
+			"reload_config" => Ok(ControlRequest::ReloadConfig),
+			"show_report" => Ok(ControlRequest::ShowReport),
+			"set_theme" => {
+				let theme = value["theme"].as_str().ok_or_else(|| "Missing \"theme\"".to_string())?;
+				Theme::from_name(theme).map(ControlRequest::SetTheme).ok_or_else(|| format!("Unknown theme {}", theme))
+			}
+			_ => Err(format!("Unknown request {}", name)),
+		}
+	}
+
+	pub fn to_json(self) -> Value {
+		match self {
+			ControlRequest::GetStatus => json!({ "request": "get_status" }),
+			ControlRequest::GetHistory => json!({ "request": "get_history" }),
+			ControlRequest::ReloadConfig => json!({ "request": "reload_config" }),
+			ControlRequest::SetTheme(theme) => json!({ "request": "set_theme", "theme": theme.name() }),
+			ControlRequest::ShowReport => json!({ "request": "show_report" }),
+		}
+	}
+}
+
+/// Wrap the outcome of a request in the response sent back to the client
+pub fn response(result: Result<Value, String>) -> Value {
+	match result {
+		Ok(result) => json!({ "ok": true, "result": result }),
+		Err(error) => json!({ "ok": false, "error": error }),
+	}
+}
+
+/// Unwrap a response received from the running tray icon
+pub fn parse_response(line: &str) -> Result<Value, String> {
+	let mut value: Value = serde_json::from_str(line).map_err(|e| format!("Invalid response: {}", e))?;
+	if value["ok"].as_bool() == Some(true) {
+		Ok(value["result"].take())
+	}
+	else {
+		Err(value["error"].as_str().unwrap_or("Request failed").to_string())
+	}
+}
+
+pub fn battery_info_json(info: &BatteryInfo) -> Value {
+	json!({
+		"percentage": info.percentage,
+		"discharge_rate_percent": info.discharge_rate_percent,
+		"power_draw_watts": battery_monitor::round_watts(info.power_draw_watts),
+		"instant_power_draw_watts": battery_monitor::round_watts(info.instant_power_draw_watts),
+		"is_charging": info.is_charging,
+		"state": info.state.to_string(),
+		"health_percent": info.health_percent,
+		"cycle_count": info.cycle_count,
+	})
+}
+
+/// Samples oldest first, each with its time in unix seconds
+pub fn history_json(history: &BatteryHistory) -> Value {
+	history.samples().map(|sample| {
+		let mut value = battery_info_json(&sample.info);
+		value["time"] = json!(sample.time.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs());
+		value
+	}).collect()
+}
@@ -0,0 +1,196 @@
+use std::fs::File;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use image::RgbaImage;
+use zip::ZipArchive;
+use crate::icon_builder::{GlyphSet, EXTRA_GLYPH_NAMES};
+
+// Far more than a pixel font glyph needs, so a bad archive can't make us read gigabytes
+const MAX_GLYPH_FILE_BYTES: u64 = 1024 * 1024;
+
+enum PackSource {
+	Directory(PathBuf),
+	Archive(ZipArchive<File>),
+}
+
+impl PackSource {
+	fn open(path: &Path) -> Result<Self, String> {
+		if path.is_dir() {
+			return Ok(PackSource::Directory(path.to_path_buf()));
+		}
+		let file = File::open(path).map_err(|e| format!("Failed to open {:?}: {:?}", path, e))?;
+		let archive = ZipArchive::new(file).map_err(|e| format!("Failed to read {:?} as a zip archive: {:?}", path, e))?;
+		Ok(PackSource::Archive(archive))
+	}
+
+	fn read(&mut self, file_name: &str) -> Result<Option<Vec<u8>>, String> {
+		let mut bytes = Vec::new();
+		match self {
+			PackSource::Directory(dir) => {
+				let path = dir.join(file_name);
+				if !path.is_file() {
+					return Ok(None);
+				}
+				let file = File::open(&path).map_err(|e| format!("Failed to open {:?}: {:?}", path, e))?;
+				file.take(MAX_GLYPH_FILE_BYTES).read_to_end(&mut bytes).map_err(|e| format!("Failed to read {:?}: {:?}", path, e))?;
+			}
+			PackSource::Archive(archive) => {
+				// Packs are often zipped along with their folder, so only the file name has to match
+				let Some(name) = archive.file_names().find(|name| name.rsplit('/').next() == Some(file_name)).map(str::to_string) else {
+					return Ok(None);
+				};
+				let file = archive.by_name(&name).map_err(|e| format!("Failed to open {} in archive: {:?}", name, e))?;
+				file.take(MAX_GLYPH_FILE_BYTES).read_to_end(&mut bytes).map_err(|e| format!("Failed to read {} in archive: {:?}", name, e))?;
+			}
+		}
+		Ok(Some(bytes))
+	}
+
+	fn glyph(&mut self, name: &str) -> Result<Option<RgbaImage>, String> {
+		let file_name = format!("{}.bmp", name);
+		let Some(bytes) = self.read(&file_name)? else {
+			return Ok(None);
+		};
+		let image = image::load_from_memory(&bytes).map_err(|e| format!("Failed to load {}: {:?}", file_name, e))?;
+		Ok(Some(image.to_rgba8()))
+	}
+
+	fn required_glyph(&mut self, name: &str) -> Result<RgbaImage, String> {
+		self.glyph(name)?.ok_or_else(|| format!("Glyph pack has no {}.bmp", name))
+	}
+}
+
+/// Load a glyph pack from a directory or zip archive of BMP images named `0.bmp` to `9.bmp`, with
+/// `100.bmp`, `h.bmp`, `m.bmp`, `colon.bmp` and `w.bmp` being optional
+pub fn load_glyph_pack(path: &Path) -> Result<GlyphSet, String> {
+	let mut source = PackSource::open(path)?;
+	let digits = (0..10).map(|digit| source.required_glyph(&digit.to_string())).collect::<Result<Vec<_>, _>>()?;
+	let hundred = source.glyph("100")?;
+	let mut extras = Vec::new();
+	for (c, name) in EXTRA_GLYPH_NAMES {
+		if let Some(glyph) = source.glyph(name)? {
+			extras.push((c, glyph));
+		}
+	}
+
+	let glyphs = GlyphSet {
+		digits: digits.try_into().map_err(|_| "Glyph pack needs ten digits".to_string())?,
+		hundred,
+		extras,
+	};
+	glyphs.validate().map_err(|e| format!("Invalid glyph pack {:?}: {}", path, e))?;
+	Ok(glyphs)
+}
+
+#[cfg(test)]
+mod tests {
+	use std::io::{Cursor, Write};
+	use super::*;
+
+	fn bmp(width: u32, height: u32) -> Vec<u8> {
+		let image = RgbaImage::from_pixel(width, height, image::Rgba([255, 255, 255, 255]));
+		let mut bytes = Cursor::new(Vec::new());
+		image.write_to(&mut bytes, image::ImageFormat::Bmp).unwrap();
+		bytes.into_inner()
+	}
+
+	// Digits of 5x7 and a 100 glyph at twice the resolution
+	fn pack_files() -> Vec<(String, Vec<u8>)> {
+		let mut files: Vec<_> = (0..10).map(|digit| (format!("{}.bmp", digit), bmp(5, 7))).collect();
+		files.push(("100.bmp".to_string(), bmp(9, 14)));
+		files.push(("h.bmp".to_string(), bmp(4, 7)));
+		files
+	}
+
+	fn test_path(name: &str) -> PathBuf {
+		std::env::temp_dir().join(format!("glyph-pack-test-{}-{}", std::process::id(), name))
+	}
+
+	fn write_dir(name: &str, files: &[(String, Vec<u8>)]) -> PathBuf {
+		let dir = test_path(name);
+		std::fs::create_dir_all(&dir).unwrap();
+		for (file_name, bytes) in files {
+			std::fs::write(dir.join(file_name), bytes).unwrap();
+		}
+		dir
+	}
+
+	fn write_zip(name: &str, folder: &str, files: &[(String, Vec<u8>)]) -> PathBuf {
+		let path = test_path(name);
+		let mut zip = zip::ZipWriter::new(File::create(&path).unwrap());
+		let options = zip::write::SimpleFileOptions::default().compression_method(zip::CompressionMethod::Deflated);
+		for (file_name, bytes) in files {
+			zip.start_file(format!("{}{}", folder, file_name), options).unwrap();
+			zip.write_all(bytes).unwrap();
+		}
+		zip.finish().unwrap();
+		path
+	}
+
+	fn check_pack(glyphs: &GlyphSet) {
+		assert!(glyphs.digits.iter().all(|digit| digit.dimensions() == (5, 7)));
+		assert_eq!(glyphs.hundred.as_ref().map(|hundred| hundred.dimensions()), Some((9, 14)));
+		assert_eq!(glyphs.extras.iter().map(|(c, _)| *c).collect::<Vec<_>>(), ['h']);
+	}
+
+	#[test]
+	fn loads_from_a_directory() {
+		let dir = write_dir("dir", &pack_files());
+		let glyphs = load_glyph_pack(&dir);
+		std::fs::remove_dir_all(&dir).unwrap();
+		check_pack(&glyphs.unwrap());
+	}
+
+	#[test]
+	fn loads_from_a_zip_with_or_without_a_folder() {
+		for (name, folder) in [("flat.zip", ""), ("folder.zip", "my-font/")] {
+			let path = write_zip(name, folder, &pack_files());
+			let glyphs = load_glyph_pack(&path);
+			std::fs::remove_file(&path).unwrap();
+			check_pack(&glyphs.unwrap());
+		}
+	}
+
+	#[test]
+	fn rejects_missing_and_oversized_files() {
+		let mut files = pack_files();
+		files.retain(|(name, _)| name != "7.bmp");
+		let path = write_zip("missing.zip", "", &files);
+		let result = load_glyph_pack(&path);
+		std::fs::remove_file(&path).unwrap();
+		assert_eq!(result.err(), Some("Glyph pack has no 7.bmp".to_string()));
+
+		// Only the first megabyte is read, which cuts this image short
+		let mut files = pack_files();
+		files[3].1 = bmp(600, 600);
+		assert!(files[3].1.len() as u64 > MAX_GLYPH_FILE_BYTES);
+		let dir = write_dir("oversized", &files);
+		let result = load_glyph_pack(&dir);
+		std::fs::remove_dir_all(&dir).unwrap();
+		assert!(result.err().is_some_and(|e| e.starts_with("Failed to load 3.bmp")));
+
+		assert!(load_glyph_pack(&test_path("nothing-here")).is_err());
+	}
+
+	#[test]
+	fn checks_the_glyph_sizes_fit_together() {
+		let glyph = |width, height| RgbaImage::new(width, height);
+		let glyphs = || GlyphSet {
+			digits: std::array::from_fn(|_| glyph(5, 7)),
+			hundred: Some(glyph(9, 14)),
+			extras: vec![('h', glyph(4, 7))],
+		};
+		assert_eq!(glyphs().validate(), Ok(()));
+		assert_eq!(GlyphSet { hundred: Some(glyph(9, 7)), ..glyphs() }.validate(), Ok(()));
+		assert_eq!(GlyphSet { hundred: None, extras: Vec::new(), ..glyphs() }.validate(), Ok(()));
+
+		let mut uneven = glyphs();
+		uneven.digits[4] = glyph(6, 7);
+		assert_eq!(uneven.validate(), Err("Digit 4 is 6x7, but 0 is 5x7".to_string()));
+		assert!(GlyphSet { digits: std::array::from_fn(|_| glyph(65, 7)), ..glyphs() }.validate().is_err());
+		assert!(GlyphSet { digits: std::array::from_fn(|_| glyph(0, 7)), ..glyphs() }.validate().is_err());
+		assert!(GlyphSet { hundred: Some(glyph(9, 10)), ..glyphs() }.validate().is_err());
+		assert!(GlyphSet { extras: vec![('m', glyph(4, 8))], ..glyphs() }.validate().is_err());
+		assert!(GlyphSet { extras: vec![(':', glyph(0, 7))], ..glyphs() }.validate().is_err());
+	}
+}
@@ -0,0 +1,241 @@
+use std::num::NonZeroU32;
+use std::rc::Rc;
+use std::time::{Duration, SystemTime};
+use image::{ImageBuffer, Rgba, RgbaImage};
+use winit::dpi::{LogicalSize, PhysicalPosition, PhysicalSize};
+use winit::event_loop::ActiveEventLoop;
+use winit::window::{Window, WindowId, WindowLevel};
+use crate::battery_history::BatteryHistory;
+use crate::icon_builder::{CHARGING_COLOR, DRAINING_COLOR};
+
+const WINDOW_WIDTH: u32 = 480;
+const WINDOW_HEIGHT: u32 = 200;
+const WINDOW_TITLE: &str = "Battery history, last 6 hours";
+const GRAPH_RANGE: Duration = Duration::from_secs(6 * 60 * 60);
+
+// Space between the popup and the point the tray icon was clicked
+const ANCHOR_GAP: i32 = 12;
+
+const GRAPH_MARGIN: u32 = 8;
+const BACKGROUND_COLOR: Rgba<u8> = Rgba([32, 32, 32, 255]);
+const GRID_COLOR: Rgba<u8> = Rgba([64, 64, 64, 255]);
+const CHARGE_LINE_COLOR: Rgba<u8> = Rgba([255, 255, 255, 255]);
+
+/// Draw charge and discharge rate over the range ending at `end`. The background behind each
+/// column is tinted green while charging and red while draining, like the tray icon's overlays.
+/// Charge is drawn in white against a 0-100% scale, and the discharge rate in red where the full
+/// height is 100%/hour, as it is on the icon
+pub fn render_graph(history: &BatteryHistory, end: SystemTime, range: Duration, width: u32, height: u32) -> RgbaImage {
+	let mut image = ImageBuffer::from_pixel(width, height, BACKGROUND_COLOR);
+	let plot_width = width.saturating_sub(2 * GRAPH_MARGIN);
+	let plot_height = height.saturating_sub(2 * GRAPH_MARGIN);
+	if plot_width == 0 || plot_height == 0 {
+		return image;
+	}
+	let (left, top) = (GRAPH_MARGIN, GRAPH_MARGIN);
+	let bottom = top + plot_height - 1;
+
+	// Lines every 25% and every hour
+	for quarter in 0..=4 {
+		let y = bottom - (plot_height - 1) * quarter / 4;
+		for x in left..left + plot_width {
+			image.put_pixel(x, y, GRID_COLOR);
+		}
+	}
+	let hours = (range.as_secs() / 3600) as u32;
+	for hour in 1..hours {
+		let x = left + plot_width * hour / hours;
+		for y in top..=bottom {
+			image.put_pixel(x, y, GRID_COLOR);
+		}
+	}
+
+	// Height in pixels above the bottom of the plot for a value out of 100
+	let scale = |value: i32| ((value.clamp(0, 100) as u32 * (plot_height - 1)) as f32 / 100.0).round() as u32;
+
+	let mut previous: Option<(u32, u32)> = None;
+	for (column, bucket) in history.buckets(end, range, plot_width as usize).iter().enumerate() {
+		let x = left + column as u32;
+		let Some(info) = bucket else {
+			previous = None;
+			continue;
+		};
+
+		// A weak charger gets a fainter green, as on the icon
+		let (tint, strength) = match (info.is_charging, info.discharge_rate_percent > 0) {
+			(true, false) => (CHARGING_COLOR, 0.25),
+			(true, true) => (CHARGING_COLOR, 0.12),
+			(false, _) => (DRAINING_COLOR, 0.2),
+		};
+		for y in top..=bottom {
+			blend(image.get_pixel_mut(x, y), tint, strength);
+		}
+
+		// Join each point to the one before so steep changes stay connected
+		let charge_y = bottom - scale(info.percentage);
+		let rate_y = bottom - scale(info.discharge_rate_percent);
+		let (previous_charge_y, previous_rate_y) = previous.unwrap_or((charge_y, rate_y));
+		if info.discharge_rate_percent > 0 {
+			for y in rate_y.min(previous_rate_y)..=rate_y.max(previous_rate_y) {
+				image.put_pixel(x, y, DRAINING_COLOR);
+			}
+		}
+		for y in charge_y.min(previous_charge_y)..=charge_y.max(previous_charge_y) {
+			image.put_pixel(x, y, CHARGE_LINE_COLOR);
+		}
+		previous = Some((charge_y, rate_y));
+	}
+	image
+}
+
+fn blend(pixel: &mut Rgba<u8>, color: Rgba<u8>, strength: f32) {
+	for channel in 0..3 {
+		let value = pixel[channel] as f32 * (1.0 - strength) + color[channel] as f32 * strength;
+		pixel[channel] = value.round() as u8;
+	}
+}
+
+/// Where to put a popup of the given size so it sits just above the anchor, or below it when
+/// there isn't room above, kept within the monitor
+pub fn popup_position(anchor: PhysicalPosition<f64>, size: PhysicalSize<u32>, monitor_position: PhysicalPosition<i32>, monitor_size: PhysicalSize<u32>) -> PhysicalPosition<i32> {
+	let (width, height) = (size.width as i32, size.height as i32);
+	let (anchor_x, anchor_y) = (anchor.x.round() as i32, anchor.y.round() as i32);
+	let max_x = monitor_position.x + (monitor_size.width as i32 - width).max(0);
+	let max_y = monitor_position.y + (monitor_size.height as i32 - height).max(0);
+
+	let above = anchor_y - ANCHOR_GAP - height;
+	let y = if above >= monitor_position.y { above } else { anchor_y + ANCHOR_GAP };
+	PhysicalPosition::new((anchor_x - width / 2).clamp(monitor_position.x, max_x), y.clamp(monitor_position.y, max_y))
+}
+
+/// A small window showing the battery history graph, opened from the tray icon
+pub struct HistoryWindow {
+	window: Rc<Window>,
+	surface: softbuffer::Surface<Rc<Window>, Rc<Window>>,
+}
+
+impl HistoryWindow {
+	/// Open the window next to the point the tray icon was clicked
+	pub fn open(event_loop: &ActiveEventLoop, anchor: PhysicalPosition<f64>) -> Result<Self, String> {
+		let attributes = Window::default_attributes()
+			.with_title(WINDOW_TITLE)
+			.with_inner_size(LogicalSize::new(WINDOW_WIDTH, WINDOW_HEIGHT))
+			.with_window_level(WindowLevel::AlwaysOnTop)
+			.with_visible(false);
+		let window = Rc::new(event_loop.create_window(attributes).map_err(|e| format!("Failed to create history window: {:?}", e))?);
+
+		// Placed once its size is known, on the monitor that was clicked
+		let monitor = event_loop.available_monitors()
+			.find(|m| {
+				let (position, size) = (m.position(), m.size());
+				(position.x as f64..position.x as f64 + size.width as f64).contains(&anchor.x)
+					&& (position.y as f64..position.y as f64 + size.height as f64).contains(&anchor.y)
+			})
+			.or_else(|| event_loop.primary_monitor());
+		if let Some(monitor) = monitor {
+			window.set_outer_position(popup_position(anchor, window.outer_size(), monitor.position(), monitor.size()));
+		}
+
+		let context = softbuffer::Context::new(window.clone()).map_err(|e| format!("Failed to create drawing context: {:?}", e))?;
+		let surface = softbuffer::Surface::new(&context, window.clone()).map_err(|e| format!("Failed to create drawing surface: {:?}", e))?;
+		window.set_visible(true);
+		window.focus_window();
+		Ok(Self { window, surface })
+	}
+
+	pub fn id(&self) -> WindowId {
+		self.window.id()
+	}
+
+	pub fn request_redraw(&self) {
+		self.window.request_redraw();
+	}
+
+	pub fn draw(&mut self, history: &BatteryHistory) -> Result<(), String> {
+		let size = self.window.inner_size();
+		let (Some(width), Some(height)) = (NonZeroU32::new(size.width), NonZeroU32::new(size.height)) else {
+			return Ok(());
+		};
+		self.surface.resize(width, height).map_err(|e| format!("Failed to resize drawing surface: {:?}", e))?;
+
+		let image = render_graph(history, SystemTime::now(), GRAPH_RANGE, size.width, size.height);
+		let mut buffer = self.surface.buffer_mut().map_err(|e| format!("Failed to get drawing buffer: {:?}", e))?;
+		for (target, pixel) in buffer.iter_mut().zip(image.pixels()) {
+			*target = (pixel[0] as u32) << 16 | (pixel[1] as u32) << 8 | pixel[2] as u32;
+		}
+		buffer.present().map_err(|e| format!("Failed to draw history window: {:?}", e))
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use starship_battery::State;
+	use crate::battery_monitor::BatteryInfo;
+
+	fn battery(percentage: i32, discharge_rate_percent: i32, is_charging: bool) -> BatteryInfo {
+		BatteryInfo {
+			percentage,
+			discharge_rate_percent,
+			power_draw_watts: 0.0,
+			instant_power_draw_watts: 0.0,
+			is_charging,
+			state: if is_charging { State::Charging } else { State::Discharging },
+			health_percent: 100,
+			cycle_count: None,
+		}
+	}
+
+	#[test]
+	fn draws_charge_and_discharge_rate() {
+		let hour = Duration::from_secs(3600);
+		let end = SystemTime::UNIX_EPOCH + 100 * hour;
+		let mut history = BatteryHistory::new();
+		history.push(end - 3 * hour, battery(50, 20, false));
+		// Out of range values are drawn at the edge of the plot
+		history.push(end - hour, battery(120, -30, true));
+
+		// A plot 24 pixels wide, so 15 minutes a column, and 101 high, so 1% a pixel
+		let image = render_graph(&history, end, GRAPH_RANGE, 24 + 2 * GRAPH_MARGIN, 101 + 2 * GRAPH_MARGIN);
+		let bottom = GRAPH_MARGIN + 100;
+		let column = |index: u32| GRAPH_MARGIN + index;
+
+		// The draining sample covers its own column and the next
+		assert_eq!(*image.get_pixel(column(11), bottom - 50), CHARGE_LINE_COLOR);
+		assert_eq!(*image.get_pixel(column(11), bottom - 20), DRAINING_COLOR);
+		assert_eq!(*image.get_pixel(column(11), 30), Rgba([77, 26, 26, 255]));
+		assert_eq!(*image.get_pixel(column(12), bottom - 50), CHARGE_LINE_COLOR);
+		assert_eq!(*image.get_pixel(column(13), 30), BACKGROUND_COLOR);
+
+		assert_eq!(*image.get_pixel(column(19), GRAPH_MARGIN), CHARGE_LINE_COLOR);
+		assert_eq!(*image.get_pixel(column(19), 30), Rgba([24, 88, 24, 255]));
+		assert!((GRAPH_MARGIN..=bottom).all(|y| *image.get_pixel(column(19), y) != DRAINING_COLOR));
+
+		// Grid lines every 25% and every hour
+		assert_eq!(*image.get_pixel(column(1), bottom - 25), GRID_COLOR);
+		assert_eq!(*image.get_pixel(column(4), 30), GRID_COLOR);
+	}
+
+	#[test]
+	fn draws_only_the_background_when_too_small_for_a_plot() {
+		let image = render_graph(&BatteryHistory::new(), SystemTime::now(), GRAPH_RANGE, 2 * GRAPH_MARGIN, 40);
+		assert!(image.pixels().all(|pixel| *pixel == BACKGROUND_COLOR));
+	}
+
+	#[test]
+	fn places_the_popup_within_the_monitor() {
+		let size = PhysicalSize::new(480, 200);
+		let monitor_size = PhysicalSize::new(1920, 1080);
+		let origin = PhysicalPosition::new(0, 0);
+
+		// Above a taskbar at the bottom, pushed in from the right edge
+		assert_eq!(popup_position(PhysicalPosition::new(1900.0, 1070.0), size, origin, monitor_size), PhysicalPosition::new(1440, 858));
+		// Below a panel at the top, pushed in from the left edge
+		assert_eq!(popup_position(PhysicalPosition::new(100.0, 10.0), size, origin, monitor_size), PhysicalPosition::new(0, 22));
+		// On a second monitor to the right
+		let second = PhysicalPosition::new(1920, 0);
+		assert_eq!(popup_position(PhysicalPosition::new(1930.0, 5.0), size, second, PhysicalSize::new(1280, 1024)), PhysicalPosition::new(1920, 17));
+		// Bigger than the monitor, so pinned to its top left corner
+		assert_eq!(popup_position(PhysicalPosition::new(500.0, 500.0), PhysicalSize::new(2000, 1200), origin, monitor_size), PhysicalPosition::new(0, 0));
+	}
+}
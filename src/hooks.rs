@@ -0,0 +1,113 @@
+use std::io::Write;
+use std::process::{Child, Stdio};
+use std::time::{Duration, Instant};
+use crate::battery_events::BatteryEvent;
+use crate::battery_monitor::BatteryInfo;
+use crate::config::NamedSetting;
+use crate::control;
+use crate::shell;
+use crate::debug_util::dmsg;
+
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Run a hook command for an event on its own thread, killing it if it runs past the timeout.
+/// The battery state is passed in `BATTERY_*` environment variables and as JSON on stdin
+pub fn run_hook(event: BatteryEvent, command_line: &str, battery_info: &BatteryInfo, timeout: Duration) -> Result<(), String> {
+	let mut command = shell::shell_command(command_line);
+	shell::hide_console_window(&mut command);
+	command
+		.env("BATTERY_EVENT", event.name())
+		.env("BATTERY_PERCENT", battery_info.percentage.to_string())
+		.env("BATTERY_CHARGING", if battery_info.is_charging { "1" } else { "0" })
+		.env("BATTERY_STATE", battery_info.state.to_string())
+		.env("BATTERY_DISCHARGE_RATE", battery_info.discharge_rate_percent.to_string())
+		.env("BATTERY_POWER_DRAW_WATTS", format!("{:.1}", battery_info.power_draw_watts))
+		.stdin(Stdio::piped())
+		.stdout(Stdio::null())
+		.stderr(Stdio::null());
+
+	let mut child = command.spawn().map_err(|e| format!("Failed to run hook {}: {:?}", command_line, e))?;
+
+	let mut input = control::battery_info_json(battery_info);
+	input["event"] = event.name().into();
+	let stdin = child.stdin.take();
+	let command_line = command_line.to_string();
+	std::thread::spawn(move || {
+		// The hook may exit without reading its input, so a failed write isn't an error
+		if let Some(mut stdin) = stdin {
+			let _ = writeln!(stdin, "{}", input);
+		}
+		if let Err(_e) = wait_with_timeout(&mut child, timeout) {
+			dmsg!("Hook {} failed: {}", command_line, _e);
+		}
+	});
+	Ok(())
+}
+
+fn wait_with_timeout(child: &mut Child, timeout: Duration) -> Result<(), String> {
+	let deadline = Instant::now() + timeout;
+	loop {
+		match child.try_wait().map_err(|e| format!("{:?}", e))? {
+			Some(status) if status.success() => return Ok(()),
+			Some(status) => return Err(format!("exited with {}", status)),
+			None if Instant::now() >= deadline => {
+				let _ = child.kill();
+				let _ = child.wait();
+				return Err(format!("killed after {:?}", timeout));
+			}
+			None => std::thread::sleep(POLL_INTERVAL),
+		}
+	}
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+	use super::*;
+	use starship_battery::State;
+
+	#[test]
+	fn passes_the_battery_state_in_env_vars_and_on_stdin() {
+		let path = std::env::temp_dir().join(format!("battery-hook-test-{}", std::process::id()));
+		let command_line = format!(
+			"echo \"$BATTERY_EVENT $BATTERY_PERCENT $BATTERY_CHARGING $BATTERY_STATE $BATTERY_POWER_DRAW_WATTS\" > {0}.tmp; cat >> {0}.tmp; mv {0}.tmp {0}",
+			path.display(),
+		);
+		let battery_info = BatteryInfo {
+			percentage: 42,
+			discharge_rate_percent: 0,
+			power_draw_watts: 12.34,
+			instant_power_draw_watts: 12.34,
+			is_charging: true,
+			state: State::Charging,
+			health_percent: 100,
+			cycle_count: None,
+		};
+		run_hook(BatteryEvent::PluggedIn, &command_line, &battery_info, Duration::from_secs(5)).unwrap();
+
+		let deadline = Instant::now() + Duration::from_secs(5);
+		while !path.exists() && Instant::now() < deadline {
+			std::thread::sleep(POLL_INTERVAL);
+		}
+		let output = std::fs::read_to_string(&path).unwrap();
+		let _ = std::fs::remove_file(&path);
+		let (env, stdin) = output.split_once('\n').unwrap();
+		assert_eq!(env, "plugged_in 42 1 charging 12.3");
+		let input: serde_json::Value = serde_json::from_str(stdin.trim()).unwrap();
+		assert_eq!(input["event"], "plugged_in");
+		assert_eq!(input["percentage"], 42);
+		assert_eq!(input["is_charging"], true);
+	}
+
+	#[test]
+	fn kills_hooks_that_run_past_the_timeout() {
+		let mut child = shell::shell_command("sleep 10").spawn().unwrap();
+		let started = Instant::now();
+		assert_eq!(wait_with_timeout(&mut child, Duration::from_millis(200)), Err("killed after 200ms".to_string()));
+		assert!(started.elapsed() < Duration::from_secs(5));
+
+		let mut child = shell::shell_command("exit 3").spawn().unwrap();
+		assert_eq!(wait_with_timeout(&mut child, Duration::from_secs(5)), Err("exited with exit status: 3".to_string()));
+		let mut child = shell::shell_command("true").spawn().unwrap();
+		assert_eq!(wait_with_timeout(&mut child, Duration::from_secs(5)), Ok(()));
+	}
+}
@@ -0,0 +1,126 @@
+use std::time::{Duration, Instant};
+use crate::battery_monitor::BatteryInfo;
+use crate::icon_builder::{AlertStyle, IconAnimation};
+
+const CHARGING_FRAME_TIME: Duration = Duration::from_millis(150);
+const BLINK_FRAME_TIME: Duration = Duration::from_millis(500);
+
+/// Which animation suits the battery's state. Blinking is an alert style of its own, so it
+/// happens even with the charging animation turned off. A weak charger isn't animated, as the
+/// battery is still going down
+pub fn choose_animation(battery_info: &BatteryInfo, alert_style: AlertStyle, animations: bool) -> IconAnimation {
+	if alert_style == AlertStyle::Blink {
+		IconAnimation::Blink
+	}
+	else if animations && battery_info.is_charging && battery_info.discharge_rate_percent <= 0 && battery_info.percentage < 100 {
+		IconAnimation::Charging
+	}
+	else {
+		IconAnimation::None
+	}
+}
+
+pub fn frame_time(animation: IconAnimation) -> Duration {
+	match animation {
+		IconAnimation::None => Duration::ZERO,
+		IconAnimation::Charging => CHARGING_FRAME_TIME,
+		IconAnimation::Blink => BLINK_FRAME_TIME,
+	}
+}
+
+/// How many frames have started `elapsed` into an animation, counting from 1. Frames are picked
+/// from the time alone, so the same moment gives the same frame however the event loop wakes
+pub fn frames_started(elapsed: Duration, frame_time: Duration) -> u64 {
+	(elapsed.as_nanos() / frame_time.as_nanos().max(1)) as u64 + 1
+}
+
+/// Works out which frame of a looping animation to show, and when the next one is due
+pub struct AnimationClock {
+	frame_count: usize,
+	frame_time: Duration,
+	started: Instant,
+	// Frames started as of the last one shown
+	shown: u64,
+}
+
+impl AnimationClock {
+	pub fn new(now: Instant) -> Self {
+		Self { frame_count: 0, frame_time: Duration::ZERO, started: now, shown: 1 }
+	}
+
+	/// Start over with `frame_count` frames, the first of which the caller has just shown.
+	/// Fewer than two frames stops the clock
+	pub fn restart(&mut self, frame_count: usize, frame_time: Duration, now: Instant) {
+		self.frame_count = frame_count;
+		self.frame_time = frame_time;
+		self.started = now;
+		self.shown = 1;
+	}
+
+	fn is_running(&self) -> bool {
+		self.frame_count >= 2 && !self.frame_time.is_zero()
+	}
+
+	/// The index of the frame to show, when it's changed since the last tick
+	pub fn tick(&mut self, now: Instant) -> Option<usize> {
+		if !self.is_running() {
+			return None;
+		}
+		let started = frames_started(now.saturating_duration_since(self.started), self.frame_time);
+		if started == self.shown {
+			return None;
+		}
+		self.shown = started;
+		Some(((started - 1) % self.frame_count as u64) as usize)
+	}
+
+	/// When the next frame is due
+	pub fn deadline(&self) -> Option<Instant> {
+		self.is_running().then(|| self.started + self.frame_time * self.shown as u32)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	const FRAME_TIME: Duration = Duration::from_millis(100);
+
+	#[test]
+	fn ticks_through_the_frames_and_loops() {
+		let start = Instant::now();
+		let mut clock = AnimationClock::new(start);
+		assert_eq!(clock.tick(start + FRAME_TIME), None);
+		assert_eq!(clock.deadline(), None);
+
+		clock.restart(3, FRAME_TIME, start);
+		assert_eq!(clock.deadline(), Some(start + FRAME_TIME));
+		assert_eq!(clock.tick(start + FRAME_TIME / 2), None);
+		assert_eq!(clock.tick(start + FRAME_TIME), Some(1));
+		assert_eq!(clock.tick(start + FRAME_TIME), None);
+		assert_eq!(clock.deadline(), Some(start + FRAME_TIME * 2));
+		assert_eq!(clock.tick(start + FRAME_TIME * 2), Some(2));
+		assert_eq!(clock.tick(start + FRAME_TIME * 3), Some(0));
+	}
+
+	#[test]
+	fn late_ticks_skip_to_the_current_frame() {
+		let start = Instant::now();
+		let mut clock = AnimationClock::new(start);
+		clock.restart(4, FRAME_TIME, start);
+		// Waking up late shows the frame for the time, not the next in turn
+		assert_eq!(clock.tick(start + FRAME_TIME * 6 + FRAME_TIME / 2), Some(2));
+		assert_eq!(clock.deadline(), Some(start + FRAME_TIME * 7));
+	}
+
+	#[test]
+	fn too_few_frames_stop_the_clock() {
+		let start = Instant::now();
+		let mut clock = AnimationClock::new(start);
+		clock.restart(1, FRAME_TIME, start);
+		assert_eq!(clock.tick(start + FRAME_TIME * 5), None);
+		assert_eq!(clock.deadline(), None);
+		clock.restart(2, Duration::ZERO, start);
+		assert_eq!(clock.deadline(), None);
+	}
+}
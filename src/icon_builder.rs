@@ -1,4 +1,7 @@
-use image::{imageops, ImageBuffer, RgbaImage};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::time::Duration;
+use image::{imageops, GrayImage, ImageBuffer, Luma, RgbaImage};
 
 const ICON_WIDTH: u32 = 64;
 const ICON_HEIGHT: u32 = 64;
@@ -22,11 +25,212 @@ const ICON_DIGIT_BYTES: [&[u8]; 10] = [
 
 const ICON_100_BYTES: &[u8] = include_bytes!("../assets/100.bmp");
 
+// Extra glyphs for writing out the time remaining and power draw, drawn to match the digits.
+// The embedded font has no percent sign, a glyph pack can add one
+const ICON_TEXT_GLYPH_BYTES: [(char, &[u8]); 4] = [
+	('h', include_bytes!("../assets/h.bmp")),
+	('m', include_bytes!("../assets/m.bmp")),
+	(':', include_bytes!("../assets/colon.bmp")),
+	('w', include_bytes!("../assets/w.bmp")),
+];
+
+// Estimates beyond this come from discharge rates of only a few %/hour, where the rate's
+// whole-percent steps make the estimate jump around, so the percentage is shown instead
+const MAX_TIME_REMAINING: Duration = Duration::from_secs(10 * 60 * 60);
+
+pub const DEFAULT_POWER_DRAW_CEILING_WATTS: u32 = 40;
+
+const CHARGING_ANIMATION_FRAMES: u32 = 8;
+
+pub const DEFAULT_OUTLINE_THICKNESS: u32 = 2;
+// Thicker than this and the edges of neighbouring digits run together
+const MAX_OUTLINE_THICKNESS: u32 = 6;
+
+// Glyphs are blown up to about this height with nearest neighbor before being smoothed down to
+// size, so the pixel font's edges stay crisp
+const GLYPH_PRESCALE_HEIGHT: u32 = 40;
+
+// Glyph packs are pixel fonts, so anything bigger is likely the wrong image
+const MAX_GLYPH_SIZE: u32 = 64;
+
+/// Characters other than digits a glyph set can have, with their file names in a glyph pack
+pub const EXTRA_GLYPH_NAMES: [(char, &str); 4] = [('h', "h"), ('m', "m"), (':', "colon"), ('w', "w")];
+
+const DARK_DIGIT_COLOR: image::Rgba<u8> = image::Rgba([96, 96, 96, 255]);
+
+// Overlay palette, shared with anything else that shows charging and draining
+pub const CHARGING_COLOR: image::Rgba<u8> = image::Rgba([0, 255, 0, 255]);
+pub const DRAINING_COLOR: image::Rgba<u8> = image::Rgba([255, 0, 0, 255]);
+pub const DRAINING_LIGHT_COLOR: image::Rgba<u8> = image::Rgba([255, 200, 200, 255]);
+
+/// What the height of the red overlay represents
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OverlayMode {
+	/// Battery percentage lost per hour, a full icon is 100%/hour
+	DischargeRate,
+	/// Power draw in watts, a full icon is the power draw ceiling
+	PowerDraw,
+}
+
+/// Digit colour, to suit the taskbar behind the icon
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Theme {
+	/// White digits for dark taskbars
+	LightDigits,
+	/// Grey digits for light taskbars
+	DarkDigits,
+}
+
+/// What the digits on the icon show
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IconDisplay {
+	Percentage,
+	/// Estimated time until empty, or until full while charging
+	TimeRemaining,
+	/// Power drawn from the battery, or put into it while charging, e.g. `12w`
+	Watts,
+}
+
+/// Animations the icon can loop through to catch the eye
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IconAnimation {
+	None,
+	/// The green overlay rising from the bottom while charging
+	Charging,
+	/// Flashing between the plain icon and a red background, for the blink alert style
+	Blink,
+}
+
+/// An edge drawn around the digits so they stand out from whatever is behind the icon
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutlineStyle {
+	Off,
+	/// An edge all the way around
+	Outline,
+	/// The edge moved down and to the right, so it falls behind the digits
+	Shadow,
+}
+
+/// How the icon stands out when the battery is running low
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AlertStyle {
+	None,
+	/// The digits on a solid red background
+	RedBackground,
+	/// The digits cut out of a solid block
+	Inverted,
+	/// Flashing the red background on and off. Drawn as an animation
+	Blink,
+}
+
+/// How the time remaining is written
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimeFormat {
+	/// Minutes below an hour, e.g. `45m`, then hours rounded to the nearest, e.g. `2h`
+	Compact,
+	/// e.g. `2:05`
+	HoursMinutes,
+}
+
+/// The time remaining as the icon writes it
+pub fn format_time_remaining(time_remaining: Duration, format: TimeFormat) -> String {
+	let minutes = time_remaining.as_secs() / 60;
+	match format {
+		TimeFormat::Compact if minutes < 60 => format!("{}m", minutes),
+		// Rounded so 1h59m reads as 2h rather than 1h
+		TimeFormat::Compact => format!("{}h", (minutes + 30) / 60),
+		TimeFormat::HoursMinutes => format!("{}:{:02}", minutes / 60, minutes % 60),
+	}
+}
+
+/// The overlays an icon gets for a battery state
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct IconOverlays {
+	/// Plugged in, shown with the green overlay
+	pub charging: bool,
+	/// Plugged in but still losing charge, so the green is dimmed
+	pub weak_charger: bool,
+	/// Fraction of the icon height filled red from the bottom, 0 when not losing charge
+	pub drain_fraction: f32,
+}
+
+/// How the digits are fitted into the icon
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IconLayout {
+	/// Digits stretched to fill the full icon height
+	Stretched,
+	/// Digits keep the pixel font's proportions, centered vertically
+	Proportional,
+}
+
+/// Unscaled glyph images, either embedded or from a glyph pack
+pub struct GlyphSet {
+	pub digits: [RgbaImage; 10],
+	/// 100 as one glyph, so three digits can be squeezed into the width of two. It can be drawn
+	/// at twice the resolution of the digits, to use "half" pixels. Without it 100 is written
+	/// with three digits, like any other number
+	pub hundred: Option<RgbaImage>,
+	/// Other characters for writing text, see EXTRA_GLYPH_NAMES
+	pub extras: Vec<(char, RgbaImage)>,
+}
+
+impl GlyphSet {
+	/// The glyphs built into the app
+	pub fn embedded() -> Self {
+		let load = |bytes: &[u8]| image::load_from_memory(bytes).unwrap().to_rgba8();
+		GlyphSet {
+			digits: ICON_DIGIT_BYTES.map(load),
+			hundred: Some(load(ICON_100_BYTES)),
+			extras: ICON_TEXT_GLYPH_BYTES.iter().map(|(c, bytes)| (*c, load(bytes))).collect(),
+		}
+	}
+
+	/// Check the glyphs fit together. The digits must all be the same size, with the 100 glyph
+	/// and the other characters as tall as them, or the 100 glyph twice as tall
+	pub fn validate(&self) -> Result<(), String> {
+		let (width, height) = self.digits[0].dimensions();
+		if width == 0 || height == 0 || width > MAX_GLYPH_SIZE || height > MAX_GLYPH_SIZE {
+			return Err(format!("Digits are {}x{}, they must be between 1x1 and {}x{}", width, height, MAX_GLYPH_SIZE, MAX_GLYPH_SIZE));
+		}
+		if let Some((digit, glyph)) = self.digits.iter().enumerate().find(|(_, g)| g.dimensions() != (width, height)) {
+			return Err(format!("Digit {} is {}x{}, but 0 is {}x{}", digit, glyph.width(), glyph.height(), width, height));
+		}
+		if let Some(hundred) = self.hundred.as_ref().filter(|g| g.width() == 0 || (g.height() != height && g.height() != 2 * height)) {
+			return Err(format!("The 100 glyph is {}x{}, it must be {} or {} pixels tall", hundred.width(), hundred.height(), height, 2 * height));
+		}
+		if let Some((c, glyph)) = self.extras.iter().find(|(_, g)| g.width() == 0 || g.width() > MAX_GLYPH_SIZE || g.height() != height) {
+			return Err(format!("The {} glyph is {}x{}, it must be {} pixels tall", c, glyph.width(), glyph.height(), height));
+		}
+		Ok(())
+	}
+}
+
+// Which glyph of the set an image is
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum GlyphKey {
+	Char(char),
+	/// The glyph standing in for "100"
+	Hundred,
+}
+
 pub struct IconBuilder {
-	icon_digit_images: [RgbaImage; 10],
-	icon_100_image: RgbaImage,
+	/// Unscaled glyphs, text is scaled to fit when drawn as its length varies
+	glyphs: GlyphSet,
+	/// Glyphs already scaled, by which glyph and the size. Cleared whenever the glyphs or layout change
+	scaled_glyphs: RefCell<HashMap<(GlyphKey, u32, u32), RgbaImage>>,
 	green_gradient_overlay: RgbaImage,
 	red_gradient_overlay: RgbaImage,
+	overlay_mode: OverlayMode,
+	power_draw_ceiling_watts: u32,
+	theme: Theme,
+	layout: IconLayout,
+	display: IconDisplay,
+	time_format: TimeFormat,
+	transition: Option<(IconDisplay, f32)>,
+	alert_style: AlertStyle,
+	outline_style: OutlineStyle,
+	outline_color: image::Rgba<u8>,
+	outline_thickness: u32,
 }
 
 fn image_overlay_multiply(img: &mut RgbaImage, overlay: &RgbaImage, x_offset: i32, y_offset: i32) {
@@ -59,109 +263,386 @@ fn image_overlay_multiply(img: &mut RgbaImage, overlay: &RgbaImage, x_offset: i3
 	}
 }
 
+/// The previous image sliding up out of the icon as the next one slides in from below, easing
+/// in and out so it settles gently
+pub fn slide_transition(previous: &RgbaImage, next: &RgbaImage, progress: f32) -> RgbaImage {
+	let t = progress.clamp(0.0, 1.0);
+	let offset = ((t * t * (3.0 - 2.0 * t)) * ICON_HEIGHT as f32).round() as i64;
+	let mut icon_image = ImageBuffer::new(ICON_WIDTH, ICON_HEIGHT);
+	imageops::overlay(&mut icon_image, previous, 0, -offset);
+	imageops::overlay(&mut icon_image, next, 0, ICON_HEIGHT as i64 - offset);
+	icon_image
+}
+
+/// The largest alpha within `radius` pixels of each pixel, growing shapes outwards by the radius
+pub fn dilate_alpha(image: &RgbaImage, radius: u32) -> GrayImage {
+	let r = radius as i64;
+	ImageBuffer::from_fn(image.width(), image.height(), |x, y| {
+		let mut alpha = 0;
+		for dy in -r..=r {
+			for dx in -r..=r {
+				// Round the corners, so diagonal edges are as thick as straight ones
+				if dx * dx + dy * dy > r * r + r {
+					continue;
+				}
+				let (sx, sy) = (x as i64 + dx, y as i64 + dy);
+				if sx >= 0 && sy >= 0 && sx < image.width() as i64 && sy < image.height() as i64 {
+					alpha = alpha.max(image.get_pixel(sx as u32, sy as u32)[3]);
+				}
+			}
+		}
+		Luma([alpha])
+	})
+}
+
+/// Draw an outline or shadow of `color` behind the shapes in an image
+pub fn add_outline(image: &RgbaImage, style: OutlineStyle, color: image::Rgba<u8>, thickness: u32) -> RgbaImage {
+	let offset = match style {
+		OutlineStyle::Off => return image.clone(),
+		OutlineStyle::Outline => 0,
+		OutlineStyle::Shadow => thickness as i64,
+	};
+	let edge = dilate_alpha(image, thickness);
+	let mut outlined = ImageBuffer::from_fn(image.width(), image.height(), |x, y| {
+		let (sx, sy) = (x as i64 - offset, y as i64 - offset);
+		let alpha = if sx >= 0 && sy >= 0 { edge.get_pixel(sx as u32, sy as u32)[0] } else { 0 };
+		image::Rgba([color[0], color[1], color[2], alpha])
+	});
+	imageops::overlay(&mut outlined, image, 0, 0);
+	outlined
+}
+
+// Empty columns at the left and right of a glyph, none for a glyph that's all blank
+fn blank_sides(glyph: &RgbaImage) -> (u32, u32) {
+	let column_blank = |x: u32| (0..glyph.height()).all(|y| glyph.get_pixel(x, y)[3] == 0);
+	let left = (0..glyph.width()).take_while(|&x| column_blank(x)).count() as u32;
+	if left == glyph.width() {
+		return (0, 0);
+	}
+	let right = (0..glyph.width()).rev().take_while(|&x| column_blank(x)).count() as u32;
+	(left, right)
+}
+
+/// Scale a glyph to size, scaling up with nearest neighbor first to avoid fuzziness when resizing
+fn scale_glyph(img: &RgbaImage, w: u32, h: u32) -> RgbaImage {
+	let prescale = (GLYPH_PRESCALE_HEIGHT / img.height()).max(1);
+	let img = imageops::resize(img, prescale*img.width(), prescale*img.height(), imageops::FilterType::Nearest);
+	imageops::resize(&img, w, h, imageops::FilterType::Lanczos3)
+}
+
 impl IconBuilder {
 	pub fn new() -> Result<Self, Box<dyn std::error::Error>> {
-		// We load and cache all the digit images here
-		// Also scale up the image using nearest neighbor interpolation to avoid fuzziness later
-		let icon_digit_images = std::array::from_fn(|i| {
-			let img = image::load_from_memory(ICON_DIGIT_BYTES[i]).unwrap().to_rgba8();
-			let img = {
-				let w = 8*img.width();
-				let h = 8*img.height();
-				imageops::resize(&img, w, h, imageops::FilterType::Nearest)
-			};
-			{
-				let w = (ICON_WIDTH - ICON_DIGIT_GAP_W) / 2 - ICON_MARGIN_W;
-				let h = ICON_HEIGHT - 2*ICON_MARGIN_H;
-				imageops::resize(&img, w, h, imageops::FilterType::Lanczos3)
-			}
-		});
-
-		// Load and scale the 100% icon
-		let icon_100_image = {
-			let img = image::load_from_memory(ICON_100_BYTES).unwrap().to_rgba8();
-			let img = {
-				// This is scaled less than all the digits as the resource is already at double resolution,
-				// so that the 100 icon can use "half" pixels to squish into the required space
-				let w = 4*img.width();
-				let h = 4*img.height();
-				imageops::resize(&img, w, h, imageops::FilterType::Nearest)
-			};
-			{
-				let w = ICON_WIDTH  - 2*ICON_MARGIN_W;
-				let h = ICON_HEIGHT - 2*ICON_MARGIN_H;
-				imageops::resize(&img, w, h, imageops::FilterType::Lanczos3)
-			}
-		};
+		let glyphs = GlyphSet::embedded();
 
 		// Create green overlay used when charging
 		let green_gradient_overlay = {
 			let mut img = ImageBuffer::new(ICON_WIDTH, ICON_HEIGHT);
-			let start_color = image::Rgba([0, 255, 0, 255]);
 			let end_color = image::Rgba([255, 255, 255, 255]);
-			imageops::vertical_gradient(&mut img, &start_color, &end_color);
+			imageops::vertical_gradient(&mut img, &CHARGING_COLOR, &end_color);
 			img
 		};
 
 		// Create red overlay used when discharging
 		let red_gradient_overlay = {
 			let mut img = ImageBuffer::new(ICON_WIDTH, ICON_HEIGHT);
-			let start_color = image::Rgba([255, 200, 200, 255]);
-			let end_color = image::Rgba([255, 0, 0, 255]);
-			imageops::vertical_gradient(&mut img, &start_color, &end_color);
+			imageops::vertical_gradient(&mut img, &DRAINING_LIGHT_COLOR, &DRAINING_COLOR);
 			img
 		};
 		
 		Ok(IconBuilder {
-			icon_digit_images,
-			icon_100_image,
+			glyphs,
+			scaled_glyphs: RefCell::new(HashMap::new()),
 			green_gradient_overlay,
 			red_gradient_overlay,
+			overlay_mode: OverlayMode::DischargeRate,
+			power_draw_ceiling_watts: DEFAULT_POWER_DRAW_CEILING_WATTS,
+			theme: Theme::LightDigits,
+			layout: IconLayout::Stretched,
+			display: IconDisplay::Percentage,
+			time_format: TimeFormat::Compact,
+			transition: None,
+			alert_style: AlertStyle::None,
+			outline_style: OutlineStyle::Off,
+			outline_color: image::Rgba([0, 0, 0, 255]),
+			outline_thickness: DEFAULT_OUTLINE_THICKNESS,
 		})
 	}
-	
-	pub fn create_percentage_icon(&self, percentage: i32, discharge_rate_percent: i32, is_charging: bool)
+
+	/// Draw with a different set of glyphs, which should have been validated
+	pub fn set_glyphs(&mut self, glyphs: GlyphSet) {
+		self.glyphs = glyphs;
+		self.scaled_glyphs.get_mut().clear();
+	}
+
+	pub fn set_overlay_mode(&mut self, overlay_mode: OverlayMode) {
+		self.overlay_mode = overlay_mode;
+	}
+
+	pub fn set_power_draw_ceiling_watts(&mut self, ceiling_watts: u32) {
+		self.power_draw_ceiling_watts = ceiling_watts.max(1);
+	}
+
+	pub fn set_theme(&mut self, theme: Theme) {
+		self.theme = theme;
+	}
+
+	pub fn set_layout(&mut self, layout: IconLayout) {
+		if layout != self.layout {
+			self.scaled_glyphs.get_mut().clear();
+		}
+		self.layout = layout;
+	}
+
+	pub fn set_display(&mut self, display: IconDisplay) {
+		self.display = display;
+	}
+
+	pub fn set_time_format(&mut self, time_format: TimeFormat) {
+		self.time_format = time_format;
+	}
+
+	/// Slide from the previous display to the current one, `progress` going from 0 to 1. None
+	/// draws the current display on its own
+	pub fn set_transition(&mut self, transition: Option<(IconDisplay, f32)>) {
+		self.transition = transition;
+	}
+
+	pub fn set_alert_style(&mut self, alert_style: AlertStyle) {
+		self.alert_style = alert_style;
+	}
+
+	pub fn set_outline_style(&mut self, outline_style: OutlineStyle) {
+		self.outline_style = outline_style;
+	}
+
+	pub fn set_outline_color(&mut self, color: [u8; 3]) {
+		self.outline_color = image::Rgba([color[0], color[1], color[2], 255]);
+	}
+
+	/// Width of the outline in pixels on the 64 pixel icon
+	pub fn set_outline_thickness(&mut self, thickness: u32) {
+		self.outline_thickness = thickness.clamp(1, MAX_OUTLINE_THICKNESS);
+	}
+
+	pub fn overlays(&self, discharge_rate_percent: i32, power_draw_watts: f32, is_charging: bool) -> IconOverlays {
+		// Fraction of the icon height to fill with red, negative when the battery is gaining charge
+		let drain_fraction = match self.overlay_mode {
+			OverlayMode::DischargeRate => discharge_rate_percent as f32 / 100.0,
+			OverlayMode::PowerDraw => power_draw_watts / self.power_draw_ceiling_watts as f32,
+		};
+		IconOverlays {
+			charging: is_charging,
+			// If we are discharging despite being plugged in it must be a weak usb-c charger
+			weak_charger: is_charging && drain_fraction > 0.0,
+			drain_fraction: drain_fraction.clamp(0.0, 1.0),
+		}
+	}
+
+	/// A single colour standing in for the icon, for showing the percentage as text. The digit
+	/// colour is blended with the overlays the same way the icon blends them
+	pub fn text_color(&self, overlays: IconOverlays) -> image::Rgba<u8> {
+		let mut color = match self.theme {
+			Theme::LightDigits => image::Rgba([255, 255, 255, 255]),
+			Theme::DarkDigits => DARK_DIGIT_COLOR,
+		};
+		let mut multiply = |tint: [u8; 3]| {
+			for (channel, tint) in color.0.iter_mut().zip(tint) {
+				*channel = ((*channel as u16 * tint as u16) / 255) as u8;
+			}
+		};
+		// Sample the green gradient a quarter of the way down, where the digits start
+		if overlays.charging {
+			multiply(if overlays.weak_charger { [191, 255, 191] } else { [64, 255, 64] });
+		}
+		if overlays.drain_fraction > 0.0 {
+			let fade = (255.0 * (1.0 - overlays.drain_fraction)).round() as u8;
+			multiply([255, fade, fade]);
+		}
+		color
+	}
+
+	/// The icon for the configured display. The time remaining falls back to the percentage
+	/// when there's no estimate or it's too far out to be reliable
+	pub fn create_icon(&self, percentage: i32, time_remaining: Option<Duration>, discharge_rate_percent: i32, power_draw_watts: f32, is_charging: bool)
+			-> Result<RgbaImage, Box<dyn std::error::Error>> {
+		let draw = |display| self.draw_display(display, percentage, time_remaining, power_draw_watts);
+		let icon_image = match self.transition {
+			None => draw(self.display),
+			Some((previous, progress)) => slide_transition(&draw(previous), &draw(self.display), progress),
+		};
+		// The overlays stay put while the digits slide under them
+		Ok(self.apply_overlays(icon_image, discharge_rate_percent, power_draw_watts, is_charging))
+	}
+
+	pub fn create_percentage_icon(&self, percentage: i32, discharge_rate_percent: i32, power_draw_watts: f32, is_charging: bool)
 			-> Result<RgbaImage, Box<dyn std::error::Error>> {
-		let p = percentage.clamp(0, 100);
+		Ok(self.apply_overlays(self.draw_percentage(percentage), discharge_rate_percent, power_draw_watts, is_charging))
+	}
+
+	/// Frames for an animation of the icon create_icon would draw, looped in order. No
+	/// animation gives the plain icon as the only frame
+	pub fn create_animation_frames(&self, animation: IconAnimation, percentage: i32, time_remaining: Option<Duration>, discharge_rate_percent: i32, power_draw_watts: f32, is_charging: bool)
+			-> Result<Vec<RgbaImage>, Box<dyn std::error::Error>> {
+		let icon_image = self.draw_display(self.display, percentage, time_remaining, power_draw_watts);
+		let overlays = self.overlays(discharge_rate_percent, power_draw_watts, is_charging);
+		let frames = match animation {
+			IconAnimation::None => vec![self.apply_overlays(icon_image, discharge_rate_percent, power_draw_watts, is_charging)],
+			// Rising in steps to the full overlay, which the last frame holds for a step
+			IconAnimation::Charging => (1..=CHARGING_ANIMATION_FRAMES)
+				.map(|step| {
+					let fill = ICON_HEIGHT * step / CHARGING_ANIMATION_FRAMES;
+					self.finish_icon(icon_image.clone(), self.alert_style, overlays, fill)
+				})
+				.chain(std::iter::once(self.apply_overlays(icon_image.clone(), discharge_rate_percent, power_draw_watts, is_charging)))
+				.collect(),
+			IconAnimation::Blink => vec![
+				self.finish_icon(icon_image.clone(), AlertStyle::RedBackground, overlays, ICON_HEIGHT),
+				self.finish_icon(icon_image, AlertStyle::None, overlays, ICON_HEIGHT),
+			],
+		};
+		Ok(frames)
+	}
 
+	fn draw_display(&self, display: IconDisplay, percentage: i32, time_remaining: Option<Duration>, power_draw_watts: f32) -> RgbaImage {
+		// A glyph pack without the letters can't write the text, and leaving them out would be misleading
+		let text = match (display, time_remaining.filter(|t| *t < MAX_TIME_REMAINING)) {
+			(IconDisplay::TimeRemaining, Some(time_remaining)) => self.draw_text(&format_time_remaining(time_remaining, self.time_format)),
+			(IconDisplay::Watts, _) => self.draw_text(&format!("{}w", power_draw_watts.abs().round() as i32)),
+			_ => None,
+		};
+		text.unwrap_or_else(|| self.draw_percentage(percentage))
+	}
+
+	// Always at least two digits. Calibrated batteries can read a little over 100
+	fn draw_percentage(&self, percentage: i32) -> RgbaImage {
+		let text = format!("{:02}", percentage.clamp(0, 999));
+		self.draw_text(&text).unwrap_or_else(|| ImageBuffer::new(ICON_WIDTH, ICON_HEIGHT))
+	}
+
+	/// Split text into glyphs, each with how many of its pixels make up one pixel of the digits.
+	/// None if a character has no glyph
+	fn text_glyphs(&self, text: &str) -> Option<Vec<(GlyphKey, &RgbaImage, f32)>> {
+		let font_height = self.glyphs.digits[0].height() as f32;
+		let mut glyphs = Vec::new();
+		let mut rest = text;
+		while let Some(c) = rest.chars().next() {
+			let (key, glyph) = match (&self.glyphs.hundred, c.to_digit(10)) {
+				(Some(hundred), _) if rest.starts_with("100") => {
+					rest = &rest[3..];
+					(GlyphKey::Hundred, hundred)
+				}
+				(_, Some(digit)) => {
+					rest = &rest[1..];
+					(GlyphKey::Char(c), &self.glyphs.digits[digit as usize])
+				}
+				(_, None) => {
+					rest = &rest[c.len_utf8()..];
+					(GlyphKey::Char(c), self.glyphs.extras.iter().find(|(g, _)| *g == c).map(|(_, img)| img)?)
+				}
+			};
+			glyphs.push((key, glyph, glyph.height() as f32 / font_height));
+		}
+		Some(glyphs)
+	}
+
+	/// Write text as a row of glyphs filling the icon width. Each gap between glyphs is the gap
+	/// between the two digits of a percentage, scaled with the glyphs. Blank columns at the sides
+	/// of a glyph, as the colon has, overlap its neighbours so the gap is between the ink
+	fn draw_text(&self, text: &str) -> Option<RgbaImage> {
+		let glyphs = self.text_glyphs(text)?;
 		let mut icon_image = ImageBuffer::new(ICON_WIDTH, ICON_HEIGHT);
-		if p == 100 {
-			imageops::overlay(&mut icon_image, &self.icon_100_image, ICON_MARGIN_W as i64, ICON_MARGIN_H as i64);
-		}
-		else {
-			let tens = p / 10;
-			let ones = p % 10;
-			
-			// Get the digit images
-			let tens_img = &self.icon_digit_images[tens as usize];
-			let ones_img = &self.icon_digit_images[ones as usize];
-			
-			// Copy digits onto icon_image
-			let x1 = ICON_MARGIN_W;
-			let x2 = ICON_WIDTH - ones_img.width() - ICON_MARGIN_W;
-			imageops::overlay(&mut icon_image, tens_img, x1 as i64, ICON_MARGIN_H as i64);
-			imageops::overlay(&mut icon_image, ones_img, x2 as i64, ICON_MARGIN_H as i64);
+		if glyphs.is_empty() {
+			return Some(icon_image);
 		}
-		
-		// Apply green gradient if charging
-		if is_charging {
-			if discharge_rate_percent > 0 {
-				// If we are discharging despite being plugged in it must be a weak usb-c charger so dim the green overlay
-				image_overlay_multiply(&mut icon_image, &self.green_gradient_overlay, 0, -32);
+
+		// Lay out in font pixels first, then scale the row to the icon width
+		let (digit_w, digit_h) = self.glyphs.digits[0].dimensions();
+		let two_digit_scale = ((ICON_WIDTH - ICON_DIGIT_GAP_W) / 2 - ICON_MARGIN_W) as f32 / digit_w as f32;
+		let gap = ICON_DIGIT_GAP_W as f32 / two_digit_scale;
+		let mut placed = Vec::new();
+		let mut x = 0.0;
+		let mut previous_blank = 0.0;
+		for (i, (key, glyph, pixel_size)) in glyphs.iter().enumerate() {
+			let (blank_left, blank_right) = blank_sides(glyph);
+			if i > 0 {
+				x += gap - previous_blank - blank_left as f32 / pixel_size;
 			}
-			else {
-				image_overlay_multiply(&mut icon_image, &self.green_gradient_overlay, 0, 0);
+			let width = glyph.width() as f32 / pixel_size;
+			placed.push((*key, *glyph, x, width));
+			x += width;
+			previous_blank = blank_right as f32 / pixel_size;
+		}
+		let scale = (ICON_WIDTH - 2*ICON_MARGIN_W) as f32 / x;
+
+		// The proportional layout keeps the font's aspect, no taller than two digits would be
+		let glyph_height = match self.layout {
+			IconLayout::Stretched => ICON_HEIGHT - 2*ICON_MARGIN_H,
+			IconLayout::Proportional => ((digit_h as f32 * scale.min(two_digit_scale)).round() as u32).min(ICON_HEIGHT - 2*ICON_MARGIN_H),
+		};
+		let y = (ICON_HEIGHT - glyph_height) / 2;
+
+		let mut scaled_glyphs = self.scaled_glyphs.borrow_mut();
+		for (key, glyph, font_x, width) in placed {
+			let x = ICON_MARGIN_W as i64 + (font_x * scale).round() as i64;
+			let width = ((width * scale).round() as u32).max(1);
+			let scaled = scaled_glyphs.entry((key, width, glyph_height)).or_insert_with(|| scale_glyph(glyph, width, glyph_height));
+			imageops::overlay(&mut icon_image, scaled, x, y as i64);
+		}
+		Some(icon_image)
+	}
+
+	// Tint for the theme, then add the charging and draining overlays
+	fn apply_overlays(&self, icon_image: RgbaImage, discharge_rate_percent: i32, power_draw_watts: f32, is_charging: bool) -> RgbaImage {
+		let overlays = self.overlays(discharge_rate_percent, power_draw_watts, is_charging);
+		self.finish_icon(icon_image, self.alert_style, overlays, ICON_HEIGHT)
+	}
+
+	// Turn the drawn digits into the icon, with the green overlay only covering `charging_fill`
+	// rows from the bottom. Blinking is left to the animation frames, which pick a style per frame
+	fn finish_icon(&self, mut icon_image: RgbaImage, alert_style: AlertStyle, overlays: IconOverlays, charging_fill: u32) -> RgbaImage {
+		if alert_style == AlertStyle::Inverted {
+			for pixel in icon_image.pixels_mut() {
+				*pixel = image::Rgba([255, 255, 255, 255 - pixel[3]]);
 			}
 		}
 
-		// Apply red overlay based on discharge rate (fills from bottom to top)
-		if discharge_rate_percent > 0 {
+		// Darken the digits for light taskbars. The overlays below multiply so they still show as darker colours
+		if self.theme == Theme::DarkDigits {
+			let tint = ImageBuffer::from_pixel(ICON_WIDTH, ICON_HEIGHT, DARK_DIGIT_COLOR);
+			image_overlay_multiply(&mut icon_image, &tint, 0, 0);
+		}
+
+		// The alert styles with a solid background don't need an outline to stand out. It goes on
+		// before the overlays so they tint it along with the digits
+		if !matches!(alert_style, AlertStyle::RedBackground | AlertStyle::Inverted) {
+			icon_image = add_outline(&icon_image, self.outline_style, self.outline_color, self.outline_thickness);
+		}
+
+		// Apply green gradient if charging
+		if overlays.charging {
+			// Dim the green overlay for a weak charger
+			let offset = if overlays.weak_charger { -32 } else { 0 };
+			// Cut the top off the gradient rather than moving it, so it lines up however far it fills
+			let top = (ICON_HEIGHT.saturating_sub(charging_fill) as i32 - offset).clamp(0, ICON_HEIGHT as i32) as u32;
+			let green = imageops::crop_imm(&self.green_gradient_overlay, 0, top, ICON_WIDTH, ICON_HEIGHT - top).to_image();
+			image_overlay_multiply(&mut icon_image, &green, 0, top as i32 + offset);
+		}
+
+		// Apply red overlay based on discharge rate or power draw (fills from bottom to top).
+		// It would be lost against a red background, which says enough on its own
+		if overlays.drain_fraction > 0.0 && alert_style != AlertStyle::RedBackground {
 			let fill_height =
-				(discharge_rate_percent as f32 / 100.0 * ICON_HEIGHT as f32)
+				(overlays.drain_fraction * ICON_HEIGHT as f32)
 				.round().clamp(0.0, ICON_HEIGHT as f32) as u32;
 
 			image_overlay_multiply(&mut icon_image, &self.red_gradient_overlay, 0, (ICON_HEIGHT - fill_height) as i32);
 		}
+
+		if alert_style == AlertStyle::RedBackground {
+			let mut background = ImageBuffer::from_pixel(ICON_WIDTH, ICON_HEIGHT, DRAINING_COLOR);
+			imageops::overlay(&mut background, &icon_image, 0, 0);
+			icon_image = background;
+		}
 		
 		#[cfg(feature = "debug_image_icon")]
 		let icon_image = {
@@ -170,6 +651,127 @@ impl IconBuilder {
 			img
 		};
 
-		Ok(icon_image)
+		icon_image
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn minutes(minutes: u64) -> Duration {
+		Duration::from_secs(minutes * 60)
+	}
+
+	#[test]
+	fn compact_time_rounds_to_the_nearest_hour() {
+		assert_eq!(format_time_remaining(minutes(0), TimeFormat::Compact), "0m");
+		assert_eq!(format_time_remaining(minutes(59), TimeFormat::Compact), "59m");
+		assert_eq!(format_time_remaining(minutes(60), TimeFormat::Compact), "1h");
+		assert_eq!(format_time_remaining(minutes(89), TimeFormat::Compact), "1h");
+		assert_eq!(format_time_remaining(minutes(90), TimeFormat::Compact), "2h");
+		assert_eq!(format_time_remaining(minutes(119), TimeFormat::Compact), "2h");
+		assert_eq!(format_time_remaining(minutes(119), TimeFormat::HoursMinutes), "1:59");
+	}
+
+	// A 9x9 image with a single white pixel in the middle
+	fn dot() -> RgbaImage {
+		let mut image = RgbaImage::new(9, 9);
+		image.put_pixel(4, 4, image::Rgba([255, 255, 255, 255]));
+		image
+	}
+
+	#[test]
+	fn dilation_rounds_the_corners() {
+		let edge = dilate_alpha(&dot(), 2);
+		let covered = |x, y| edge.get_pixel(x, y)[0] == 255;
+		assert!(covered(4, 4) && covered(6, 4) && covered(4, 2) && covered(6, 5) && covered(5, 3));
+		assert!(!covered(7, 4) && !covered(6, 6) && !covered(2, 2));
+		assert_eq!(edge.pixels().filter(|pixel| pixel[0] == 255).count(), 21);
+	}
+
+	#[test]
+	fn outline_surrounds_and_shadow_falls_behind() {
+		let black = image::Rgba([0, 0, 0, 255]);
+		let white = image::Rgba([255, 255, 255, 255]);
+		let clear = image::Rgba([0, 0, 0, 0]);
+
+		let outlined = add_outline(&dot(), OutlineStyle::Outline, black, 1);
+		assert_eq!(*outlined.get_pixel(4, 4), white);
+		for (x, y) in [(3, 3), (4, 3), (5, 5), (3, 5)] {
+			assert_eq!(*outlined.get_pixel(x, y), black);
+		}
+		assert_eq!(*outlined.get_pixel(2, 4), clear);
+		assert_eq!(*outlined.get_pixel(6, 4), clear);
+
+		// The same edge moved down and right by the thickness
+		let shadowed = add_outline(&dot(), OutlineStyle::Shadow, black, 2);
+		assert_eq!(*shadowed.get_pixel(4, 4), white);
+		assert_eq!(*shadowed.get_pixel(6, 6), black);
+		assert_eq!(*shadowed.get_pixel(8, 6), black);
+		assert_eq!(*shadowed.get_pixel(6, 4), black);
+		assert_eq!(*shadowed.get_pixel(3, 4), clear);
+		assert_eq!(*shadowed.get_pixel(4, 2), clear);
+
+		assert_eq!(add_outline(&dot(), OutlineStyle::Off, black, 2), dot());
+	}
+
+	// Pixels the charging overlay has tinted green, and the highest row it reaches
+	fn green_tint(icon: &RgbaImage) -> (usize, Option<u32>) {
+		let tinted: Vec<u32> = icon.enumerate_pixels()
+			.filter(|(_, _, pixel)| pixel[3] > 0 && pixel[0] < pixel[1])
+			.map(|(_, y, _)| y)
+			.collect();
+		(tinted.len(), tinted.iter().min().copied())
+	}
+
+	#[test]
+	fn charging_animation_fills_up_in_steps() {
+		let icon_builder = IconBuilder::new().unwrap();
+		let frames = icon_builder.create_animation_frames(IconAnimation::Charging, 50, None, -20, 10.0, true).unwrap();
+		assert_eq!(frames.len(), CHARGING_ANIMATION_FRAMES as usize + 1);
+
+		let mut previous = 0;
+		for (step, frame) in (1..=CHARGING_ANIMATION_FRAMES).zip(&frames) {
+			let (count, top) = green_tint(frame);
+			let fill = ICON_HEIGHT * step / CHARGING_ANIMATION_FRAMES;
+			assert!(top.is_none_or(|top| top >= ICON_HEIGHT - fill), "frame {} tinted above its fill", step);
+			assert!(count >= previous);
+			previous = count;
+		}
+		assert!(green_tint(&frames[0]).0 < previous);
+		// The last frame holds the full overlay, as the still icon shows it
+		assert_eq!(frames[CHARGING_ANIMATION_FRAMES as usize], frames[CHARGING_ANIMATION_FRAMES as usize - 1]);
+		assert_eq!(frames.last(), Some(&icon_builder.create_icon(50, None, -20, 10.0, true).unwrap()));
+	}
+
+	#[test]
+	fn scaled_glyphs_are_reused_until_the_layout_changes() {
+		let mut icon_builder = IconBuilder::new().unwrap();
+		let first = icon_builder.create_icon(42, None, 10, 5.0, false).unwrap();
+		let cached = icon_builder.scaled_glyphs.borrow().len();
+		assert!(cached > 0);
+		assert_eq!(icon_builder.create_icon(42, None, 10, 5.0, false).unwrap(), first);
+		assert_eq!(icon_builder.scaled_glyphs.borrow().len(), cached);
+
+		icon_builder.set_layout(IconLayout::Proportional);
+		assert!(icon_builder.scaled_glyphs.borrow().is_empty());
+		icon_builder.set_layout(IconLayout::Stretched);
+		assert_eq!(icon_builder.create_icon(42, None, 10, 5.0, false).unwrap(), first);
+
+		// A new glyph set isn't drawn with the old one's scaled glyphs
+		let mut glyphs = GlyphSet::embedded();
+		for digit in &mut glyphs.digits {
+			*digit = imageops::flip_horizontal(digit);
+		}
+		icon_builder.set_glyphs(glyphs);
+		assert!(icon_builder.scaled_glyphs.borrow().is_empty());
+		assert_ne!(icon_builder.create_icon(42, None, 10, 5.0, false).unwrap(), first);
+
+		// The cache follows the builder when it moves
+		let moved = Box::new(icon_builder);
+		let cached = moved.scaled_glyphs.borrow().len();
+		moved.create_icon(42, None, 10, 5.0, false).unwrap();
+		assert_eq!(moved.scaled_glyphs.borrow().len(), cached);
 	}
 }
@@ -0,0 +1,186 @@
+use std::fmt::Write;
+use image::{imageops, RgbaImage};
+
+/// Sizes in an exported ICO file, covering the tray icon at the common display scales
+pub const DEFAULT_ICO_SIZES: [u32; 7] = [16, 20, 24, 32, 40, 48, 64];
+
+// ICO entries store sizes in a byte, with 0 meaning 256
+const MAX_ICO_SIZE: u32 = 256;
+
+pub fn check_ico_sizes(sizes: &[u32]) -> Result<(), String> {
+	if sizes.is_empty() {
+		return Err("An ICO file needs at least one size".to_string());
+	}
+	match sizes.iter().find(|size| **size == 0 || **size > MAX_ICO_SIZE) {
+		Some(size) => Err(format!("ICO sizes must be between 1 and {}, got {}", MAX_ICO_SIZE, size)),
+		None => Ok(()),
+	}
+}
+
+/// Encode an icon as an ICO file holding it at each of `sizes`, scaled down from the full size
+/// image. Entries are 32 bit bitmaps, which every version of Windows can read
+pub fn encode_ico(image: &RgbaImage, sizes: &[u32]) -> Result<Vec<u8>, String> {
+	check_ico_sizes(sizes)?;
+
+	let entries: Vec<Vec<u8>> = sizes.iter()
+		.map(|size| ico_bitmap(&imageops::resize(image, *size, *size, imageops::FilterType::Triangle)))
+		.collect();
+
+	// ICONDIR header, then a directory entry per size, then the bitmaps
+	let mut ico = Vec::new();
+	ico.extend_from_slice(&0u16.to_le_bytes());
+	ico.extend_from_slice(&1u16.to_le_bytes());
+	ico.extend_from_slice(&(sizes.len() as u16).to_le_bytes());
+	let mut offset = 6 + 16 * sizes.len();
+	for (size, entry) in sizes.iter().zip(&entries) {
+		let size_byte = (*size % MAX_ICO_SIZE) as u8;
+		ico.extend_from_slice(&[size_byte, size_byte, 0, 0]);
+		ico.extend_from_slice(&1u16.to_le_bytes());
+		ico.extend_from_slice(&32u16.to_le_bytes());
+		ico.extend_from_slice(&(entry.len() as u32).to_le_bytes());
+		ico.extend_from_slice(&(offset as u32).to_le_bytes());
+		offset += entry.len();
+	}
+	for entry in entries {
+		ico.extend_from_slice(&entry);
+	}
+	Ok(ico)
+}
+
+// A bitmap as ICO files store it: a BITMAPINFOHEADER with the height doubled, the BGRA pixels
+// bottom row first, then a 1 bit mask marking the transparent pixels for old readers
+fn ico_bitmap(image: &RgbaImage) -> Vec<u8> {
+	let (width, height) = image.dimensions();
+	let mask_row_bytes = width.div_ceil(32) * 4;
+	let pixel_bytes = width * height * 4;
+	let mask_bytes = mask_row_bytes * height;
+
+	let mut bitmap = Vec::with_capacity((40 + pixel_bytes + mask_bytes) as usize);
+	bitmap.extend_from_slice(&40u32.to_le_bytes());
+	bitmap.extend_from_slice(&(width as i32).to_le_bytes());
+	bitmap.extend_from_slice(&(2 * height as i32).to_le_bytes());
+	bitmap.extend_from_slice(&1u16.to_le_bytes());
+	bitmap.extend_from_slice(&32u16.to_le_bytes());
+	// Uncompressed, then the image size and the unused resolution and palette fields
+	bitmap.extend_from_slice(&0u32.to_le_bytes());
+	bitmap.extend_from_slice(&(pixel_bytes + mask_bytes).to_le_bytes());
+	bitmap.extend_from_slice(&[0; 16]);
+
+	for y in (0..height).rev() {
+		for x in 0..width {
+			let [r, g, b, a] = image.get_pixel(x, y).0;
+			bitmap.extend_from_slice(&[b, g, r, a]);
+		}
+	}
+	for y in (0..height).rev() {
+		let mut row = vec![0u8; mask_row_bytes as usize];
+		for x in (0..width).filter(|x| image.get_pixel(*x, y)[3] == 0) {
+			row[(x / 8) as usize] |= 0x80 >> (x % 8);
+		}
+		bitmap.extend_from_slice(&row);
+	}
+	bitmap
+}
+
+/// Encode an icon as an SVG of square pixels, so it scales up without blurring. Each run of
+/// same coloured pixels in a row is one rectangle
+pub fn encode_svg(image: &RgbaImage) -> String {
+	let (width, height) = image.dimensions();
+	let mut svg = format!(
+		"<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{}\" height=\"{}\" viewBox=\"0 0 {} {}\" shape-rendering=\"crispEdges\">\n",
+		width, height, width, height,
+	);
+	for y in 0..height {
+		let mut x = 0;
+		while x < width {
+			let pixel = image.get_pixel(x, y);
+			let run = (x..width).take_while(|x| image.get_pixel(*x, y) == pixel).count() as u32;
+			let [r, g, b, a] = pixel.0;
+			if a > 0 {
+				let _ = write!(svg, "<rect x=\"{}\" y=\"{}\" width=\"{}\" height=\"1\" fill=\"#{:02x}{:02x}{:02x}\"", x, y, run, r, g, b);
+				if a < 255 {
+					let _ = write!(svg, " fill-opacity=\"{:.3}\"", a as f32 / 255.0);
+				}
+				svg.push_str("/>\n");
+			}
+			x += run;
+		}
+	}
+	svg.push_str("</svg>\n");
+	svg
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn u16_at(bytes: &[u8], offset: usize) -> u16 {
+		u16::from_le_bytes(bytes[offset..offset + 2].try_into().unwrap())
+	}
+
+	fn u32_at(bytes: &[u8], offset: usize) -> u32 {
+		u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+	}
+
+	#[test]
+	fn writes_the_ico_header_and_directory() {
+		let image = RgbaImage::from_pixel(64, 64, image::Rgba([255, 0, 0, 255]));
+		let ico = encode_ico(&image, &[16, 40, 256]).unwrap();
+		assert_eq!(ico[..6], [0, 0, 1, 0, 3, 0]);
+
+		// Each entry's bitmap follows the last, starting after the directory
+		let mut offset = 6 + 3 * 16;
+		for (i, size) in [16u32, 40, 256].into_iter().enumerate() {
+			let entry = &ico[6 + 16 * i..6 + 16 * (i + 1)];
+			let size_byte = if size == 256 { 0 } else { size as u8 };
+			assert_eq!(entry[..4], [size_byte, size_byte, 0, 0]);
+			assert_eq!((u16_at(entry, 4), u16_at(entry, 6)), (1, 32));
+			let bytes = u32_at(entry, 8) as usize;
+			assert_eq!(u32_at(entry, 12) as usize, offset);
+			assert_eq!(bytes, 40 + (size * size * 4 + size.div_ceil(32) * 4 * size) as usize);
+
+			// The bitmap header gives the doubled height
+			assert_eq!(u32_at(&ico, offset), 40);
+			assert_eq!(u32_at(&ico, offset + 4), size);
+			assert_eq!(u32_at(&ico, offset + 8), 2 * size);
+			offset += bytes;
+		}
+		assert_eq!(ico.len(), offset);
+
+		assert!(encode_ico(&image, &[]).is_err());
+		assert!(encode_ico(&image, &[16, 257]).is_err());
+	}
+
+	#[test]
+	fn pads_mask_rows_to_four_bytes() {
+		// 40 pixels wide needs five bytes of mask a row, padded to eight
+		let mut image = RgbaImage::from_pixel(40, 2, image::Rgba([10, 20, 30, 255]));
+		image.put_pixel(0, 0, image::Rgba([0, 0, 0, 0]));
+		image.put_pixel(39, 1, image::Rgba([0, 0, 0, 0]));
+		let bitmap = ico_bitmap(&image);
+		assert_eq!(bitmap.len(), 40 + 40 * 2 * 4 + 2 * 8);
+		assert_eq!(u32_at(&bitmap, 20) as usize, bitmap.len() - 40);
+
+		// Pixels are BGRA, bottom row first
+		assert_eq!(bitmap[40..44], [30, 20, 10, 255]);
+		assert_eq!(bitmap[40 + 39 * 4..40 + 40 * 4], [0, 0, 0, 0]);
+
+		let mask = &bitmap[40 + 40 * 2 * 4..];
+		assert_eq!(mask[..8], [0, 0, 0, 0, 0x01, 0, 0, 0]);
+		assert_eq!(mask[8..], [0x80, 0, 0, 0, 0, 0, 0, 0]);
+	}
+
+	#[test]
+	fn writes_runs_of_pixels_as_svg_rectangles() {
+		let mut image = RgbaImage::new(4, 1);
+		image.put_pixel(0, 0, image::Rgba([255, 0, 0, 255]));
+		image.put_pixel(1, 0, image::Rgba([255, 0, 0, 255]));
+		image.put_pixel(3, 0, image::Rgba([0, 0, 255, 51]));
+		assert_eq!(encode_svg(&image), concat!(
+			"<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"4\" height=\"1\" viewBox=\"0 0 4 1\" shape-rendering=\"crispEdges\">\n",
+			"<rect x=\"0\" y=\"0\" width=\"2\" height=\"1\" fill=\"#ff0000\"/>\n",
+			"<rect x=\"3\" y=\"0\" width=\"1\" height=\"1\" fill=\"#0000ff\" fill-opacity=\"0.200\"/>\n",
+			"</svg>\n",
+		));
+	}
+}
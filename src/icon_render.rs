@@ -0,0 +1,232 @@
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use image::RgbaImage;
+use serde_json::Value;
+use crate::config::{Config, NamedSetting};
+use crate::glyph_pack;
+use crate::icon_builder::{AlertStyle, IconBuilder};
+use crate::icon_export::{self, DEFAULT_ICO_SIZES};
+
+/// File types the render command can write
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExportFormat {
+	Ico,
+	Svg,
+}
+
+impl NamedSetting for ExportFormat {
+	const NAMES: &'static [(Self, &'static str)] = &[
+		(ExportFormat::Ico, "ico"),
+		(ExportFormat::Svg, "svg"),
+	];
+}
+
+/// One icon to render: the settings to draw it with and the battery state it shows
+#[derive(Debug, Clone, PartialEq)]
+pub struct IconState {
+	/// File name for the icon, without the extension
+	pub name: String,
+	pub config: Config,
+	pub percentage: i32,
+	pub time_remaining: Option<Duration>,
+	pub discharge_rate_percent: i32,
+	pub power_draw_watts: i32,
+	pub is_charging: bool,
+	pub alert_style: AlertStyle,
+}
+
+/// A batch of icons to render, read from a JSON manifest
+#[derive(Debug, Clone, PartialEq)]
+pub struct RenderManifest {
+	pub output_dir: PathBuf,
+	pub formats: Vec<ExportFormat>,
+	pub ico_sizes: Vec<u32>,
+	pub icons: Vec<IconState>,
+}
+
+impl RenderManifest {
+	/// Parse a manifest. Relative paths in it are relative to `base_dir`, the manifest's directory
+	pub fn parse(text: &str, base_dir: &Path) -> Result<Self, String> {
+		let value: Value = serde_json::from_str(text).map_err(|e| format!("Invalid JSON: {}", e))?;
+
+		let output_dir = base_dir.join(value["output_dir"].as_str().unwrap_or("."));
+		let formats = match &value["formats"] {
+			Value::Null => ExportFormat::NAMES.iter().map(|(format, _)| *format).collect(),
+			formats => json_array(formats, "formats")?.iter()
+				.map(|format| format.as_str().and_then(ExportFormat::from_name).ok_or_else(|| format!("Unknown format {}", format)))
+				.collect::<Result<_, _>>()?,
+		};
+		let ico_sizes = match &value["ico_sizes"] {
+			Value::Null => DEFAULT_ICO_SIZES.to_vec(),
+			sizes => json_array(sizes, "ico_sizes")?.iter()
+				.map(|size| size.as_u64().and_then(|n| u32::try_from(n).ok()).ok_or_else(|| format!("Invalid ICO size {}", size)))
+				.collect::<Result<_, _>>()?,
+		};
+		icon_export::check_ico_sizes(&ico_sizes)?;
+
+		// Settings for every icon start from the defaults, so the output doesn't depend on the
+		// config of whoever runs the command
+		let mut config = Config::default();
+		apply_settings(&mut config, &value["settings"])?;
+		let icons = json_array(&value["icons"], "icons")?.iter()
+			.map(|icon| parse_icon_state(icon, &config, base_dir))
+			.collect::<Result<Vec<_>, _>>()?;
+		if icons.is_empty() {
+			return Err("The manifest has no icons".to_string());
+		}
+		Ok(Self { output_dir, formats, ico_sizes, icons })
+	}
+}
+
+fn parse_icon_state(value: &Value, config: &Config, base_dir: &Path) -> Result<IconState, String> {
+	let name = value["name"].as_str().ok_or_else(|| "Icon is missing \"name\"".to_string())?;
+	// Names become file names, which must stay in the output directory
+	if Path::new(name).file_name().and_then(|n| n.to_str()) != Some(name) {
+		return Err(format!("Invalid icon name {}", name));
+	}
+	// Missing numbers are None, anything else that isn't a whole number in range is an error
+	let int = |key: &str| -> Result<Option<i32>, String> {
+		match &value[key] {
+			Value::Null => Ok(None),
+			n => n.as_i64().and_then(|n| i32::try_from(n).ok()).map(Some)
+				.ok_or_else(|| format!("Icon {}: invalid \"{}\" {}", name, key, n)),
+		}
+	};
+	let minutes_remaining = match &value["minutes_remaining"] {
+		Value::Null => None,
+		minutes => Some(minutes.as_u64().and_then(|m| m.checked_mul(60)).map(Duration::from_secs)
+			.ok_or_else(|| format!("Icon {}: invalid \"minutes_remaining\" {}", name, minutes))?),
+	};
+	let is_charging = match &value["charging"] {
+		Value::Null => false,
+		charging => charging.as_bool().ok_or_else(|| format!("Icon {}: \"charging\" must be true or false, got {}", name, charging))?,
+	};
+	let alert_style = match &value["alert_style"] {
+		Value::Null => AlertStyle::None,
+		style => style.as_str().and_then(AlertStyle::from_name).ok_or_else(|| format!("Icon {}: unknown alert style {}", name, style))?,
+	};
+
+	let mut config = config.clone();
+	apply_settings(&mut config, &value["settings"]).map_err(|e| format!("Icon {}: {}", name, e))?;
+	config.glyph_pack = config.glyph_pack.map(|path| base_dir.join(path));
+
+	Ok(IconState {
+		name: name.to_string(),
+		config,
+		percentage: int("percentage")?.ok_or_else(|| format!("Icon {} is missing \"percentage\"", name))?,
+		time_remaining: minutes_remaining,
+		discharge_rate_percent: int("discharge_rate")?.unwrap_or(0),
+		power_draw_watts: int("watts")?.unwrap_or(0),
+		is_charging,
+		alert_style,
+	})
+}
+
+// Settings are given as in the config file, though numbers and booleans needn't be quoted
+fn apply_settings(config: &mut Config, settings: &Value) -> Result<(), String> {
+	let Some(settings) = settings.as_object() else {
+		return match settings {
+			Value::Null => Ok(()),
+			_ => Err("\"settings\" must be an object".to_string()),
+		};
+	};
+	for (key, value) in settings {
+		let text = value.as_str().map(str::to_string).unwrap_or_else(|| value.to_string());
+		config.set(key, &text).map_err(|e| format!("{}: {}", key, e))?;
+	}
+	Ok(())
+}
+
+fn json_array<'a>(value: &'a Value, key: &str) -> Result<&'a Vec<Value>, String> {
+	value.as_array().ok_or_else(|| format!("\"{}\" must be an array", key))
+}
+
+/// Draw an icon the way the tray would for the state
+pub fn render_icon(state: &IconState) -> Result<RgbaImage, String> {
+	let config = &state.config;
+	let mut icon_builder = IconBuilder::new().map_err(|e| format!("Failed to load icon images: {:?}", e))?;
+	if let Some(path) = &config.glyph_pack {
+		icon_builder.set_glyphs(glyph_pack::load_glyph_pack(path)?);
+	}
+	icon_builder.set_theme(config.theme);
+	icon_builder.set_layout(config.layout);
+	icon_builder.set_display(config.display);
+	icon_builder.set_time_format(config.time_format);
+	icon_builder.set_outline_style(config.outline_style);
+	icon_builder.set_outline_color(config.outline_color);
+	icon_builder.set_outline_thickness(config.outline_thickness);
+	icon_builder.set_overlay_mode(config.overlay_mode);
+	icon_builder.set_power_draw_ceiling_watts(config.power_draw_ceiling_watts);
+	// A still icon can't blink, so it shows the red half of the blink
+	icon_builder.set_alert_style(match state.alert_style {
+		AlertStyle::Blink => AlertStyle::RedBackground,
+		style => style,
+	});
+
+	icon_builder.create_icon(state.percentage, state.time_remaining, state.discharge_rate_percent, state.power_draw_watts as f32, state.is_charging)
+		.map_err(|e| format!("Failed to draw icon {}: {:?}", state.name, e))
+}
+
+/// Render every icon in a manifest file, printing the files written
+pub fn run(manifest_path: &Path) -> Result<(), String> {
+	let text = std::fs::read_to_string(manifest_path).map_err(|e| format!("Failed to read {:?}: {:?}", manifest_path, e))?;
+	let base_dir = manifest_path.parent().unwrap_or(Path::new("."));
+	let manifest = RenderManifest::parse(&text, base_dir).map_err(|e| format!("Invalid manifest {:?}: {}", manifest_path, e))?;
+
+	std::fs::create_dir_all(&manifest.output_dir).map_err(|e| format!("Failed to create {:?}: {:?}", manifest.output_dir, e))?;
+	for state in &manifest.icons {
+		let icon = render_icon(state)?;
+		for format in &manifest.formats {
+			let bytes = match format {
+				ExportFormat::Ico => icon_export::encode_ico(&icon, &manifest.ico_sizes)?,
+				ExportFormat::Svg => icon_export::encode_svg(&icon).into_bytes(),
+			};
+			let path = manifest.output_dir.join(format!("{}.{}", state.name, format.name()));
+			std::fs::write(&path, bytes).map_err(|e| format!("Failed to write {:?}: {:?}", path, e))?;
+			println!("{}", path.display());
+		}
+	}
+	Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn parse(icons: &str) -> Result<RenderManifest, String> {
+		RenderManifest::parse(&format!("{{ \"icons\": {} }}", icons), Path::new("/tmp"))
+	}
+
+	#[test]
+	fn parses_icon_numbers() {
+		let manifest = parse(r#"[{ "name": "low", "percentage": 8, "discharge_rate": -20, "minutes_remaining": 45 }]"#).unwrap();
+		let icon = &manifest.icons[0];
+		assert_eq!((icon.percentage, icon.discharge_rate_percent, icon.power_draw_watts), (8, -20, 0));
+		assert_eq!(icon.time_remaining, Some(Duration::from_secs(45 * 60)));
+		assert_eq!(manifest.ico_sizes, DEFAULT_ICO_SIZES.to_vec());
+	}
+
+	#[test]
+	fn rejects_numbers_out_of_range() {
+		assert!(parse(r#"[{ "name": "big", "percentage": 4294967346 }]"#).is_err());
+		assert!(parse(r#"[{ "name": "half", "percentage": 50, "watts": 1.5 }]"#).is_err());
+		assert!(parse(r#"[{ "name": "none" }]"#).is_err());
+		let manifest = r#"{ "ico_sizes": [4294967312], "icons": [{ "name": "a", "percentage": 50 }] }"#;
+		assert!(RenderManifest::parse(manifest, Path::new("/tmp")).is_err());
+	}
+
+	#[test]
+	fn rejects_fields_of_the_wrong_type() {
+		for minutes in ["-5", "1.5", "\"45\"", "18446744073709551615"] {
+			let icon = format!(r#"[{{ "name": "a", "percentage": 50, "minutes_remaining": {} }}]"#, minutes);
+			assert!(parse(&icon).is_err(), "accepted minutes_remaining {}", minutes);
+		}
+		assert!(parse(r#"[{ "name": "a", "percentage": 50, "charging": "yes" }]"#).is_err());
+		assert!(parse(r#"[{ "name": "a", "percentage": 50, "charging": 1 }]"#).is_err());
+		assert!(parse(r#"[{ "name": "a", "percentage": 50, "alert_style": 2 }]"#).is_err());
+
+		let manifest = parse(r#"[{ "name": "a", "percentage": 50, "charging": true, "alert_style": "blink" }]"#).unwrap();
+		assert!(manifest.icons[0].is_charging);
+		assert_eq!(manifest.icons[0].alert_style, AlertStyle::Blink);
+	}
+}
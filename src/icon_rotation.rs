@@ -0,0 +1,158 @@
+use std::time::{Duration, Instant};
+use crate::icon_builder::IconDisplay;
+
+const TRANSITION_TIME: Duration = Duration::from_millis(300);
+const TRANSITION_FRAME_TIME: Duration = Duration::from_millis(30);
+
+// Shorter than this and the transition would take up most of each view's time
+const MIN_ROTATE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// What the icon shows at one point in the rotation
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RotationFrame {
+	pub view: IconDisplay,
+	/// The view sliding out and how far along the slide is, from 0 to 1
+	pub transition: Option<(IconDisplay, f32)>,
+}
+
+/// Takes the icon through a list of views on its own timer, separate from battery sampling,
+/// with a short slide between views
+pub struct IconRotation {
+	views: Vec<IconDisplay>,
+	interval: Duration,
+	index: usize,
+	switched_at: Instant,
+	// When the next transition frame is due, while a transition is being drawn
+	next_frame: Option<Instant>,
+}
+
+impl IconRotation {
+	pub fn new(now: Instant) -> Self {
+		Self { views: Vec::new(), interval: MIN_ROTATE_INTERVAL, index: 0, switched_at: now, next_frame: None }
+	}
+
+	/// Views to take turns showing, each for `interval`. Rotation is off with fewer than two.
+	/// Setting the same views again carries on where the rotation was
+	pub fn set_views(&mut self, views: &[IconDisplay], interval: Duration, now: Instant) {
+		let interval = interval.max(MIN_ROTATE_INTERVAL);
+		if views == self.views && interval == self.interval {
+			return;
+		}
+		self.views = views.to_vec();
+		self.interval = interval;
+		self.index = 0;
+		self.switched_at = now;
+		self.next_frame = None;
+	}
+
+	/// The frame for `now`, or None when not rotating
+	pub fn frame(&self, now: Instant) -> Option<RotationFrame> {
+		if self.views.len() < 2 {
+			return None;
+		}
+		let view = self.views[self.index];
+		let previous = self.views[(self.index + self.views.len() - 1) % self.views.len()];
+		let progress = now.saturating_duration_since(self.switched_at).as_secs_f32() / TRANSITION_TIME.as_secs_f32();
+		let transition = (self.next_frame.is_some() && progress < 1.0).then_some((previous, progress));
+		Some(RotationFrame { view, transition })
+	}
+
+	/// Move the rotation on to `now`, giving the frame to draw if the icon needs redrawing
+	pub fn tick(&mut self, now: Instant) -> Option<RotationFrame> {
+		if self.views.len() < 2 {
+			return None;
+		}
+		if now >= self.switched_at + self.interval {
+			self.index = (self.index + 1) % self.views.len();
+			self.switched_at = now;
+			self.next_frame = Some(now);
+		}
+		match self.next_frame {
+			Some(due) if now >= due => {
+				let frame = self.frame(now)?;
+				// The last frame draws the new view on its own and ends the transition
+				self.next_frame = frame.transition.map(|_| now + TRANSITION_FRAME_TIME);
+				Some(frame)
+			}
+			_ => None,
+		}
+	}
+
+	/// When `tick` next has something to draw
+	pub fn deadline(&self) -> Option<Instant> {
+		if self.views.len() < 2 {
+			return None;
+		}
+		Some(self.next_frame.unwrap_or(self.switched_at + self.interval))
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn millis(millis: u64) -> Duration {
+		Duration::from_millis(millis)
+	}
+
+	fn transition_progress(frame: Option<RotationFrame>) -> Option<f32> {
+		frame.unwrap().transition.map(|(view, progress)| {
+			assert_eq!(view, IconDisplay::Percentage);
+			progress
+		})
+	}
+
+	#[test]
+	fn switches_views_with_a_transition() {
+		let start = Instant::now();
+		let mut rotation = IconRotation::new(start);
+		rotation.set_views(&[IconDisplay::Percentage, IconDisplay::Watts], Duration::from_secs(5), start);
+		assert_eq!(rotation.frame(start), Some(RotationFrame { view: IconDisplay::Percentage, transition: None }));
+		assert_eq!(rotation.tick(start + millis(1000)), None);
+		assert_eq!(rotation.deadline(), Some(start + millis(5000)));
+
+		// Switching starts the slide, which is drawn a frame at a time
+		let switched = start + millis(5000);
+		let frame = rotation.tick(switched);
+		assert_eq!(frame.unwrap().view, IconDisplay::Watts);
+		assert_eq!(transition_progress(frame), Some(0.0));
+		assert_eq!(rotation.deadline(), Some(switched + TRANSITION_FRAME_TIME));
+		assert_eq!(rotation.tick(switched + millis(10)), None);
+
+		let progress = transition_progress(rotation.tick(switched + millis(150))).unwrap();
+		assert!((progress - 0.5).abs() < 1e-3);
+		assert_eq!(rotation.deadline(), Some(switched + millis(150) + TRANSITION_FRAME_TIME));
+
+		// Once the slide is over the new view is drawn on its own, and then nothing until the next switch
+		assert_eq!(rotation.tick(switched + TRANSITION_TIME), Some(RotationFrame { view: IconDisplay::Watts, transition: None }));
+		assert_eq!(rotation.deadline(), Some(switched + millis(5000)));
+		assert_eq!(rotation.tick(switched + millis(1000)), None);
+
+		// And round to the first view again
+		assert_eq!(rotation.tick(switched + millis(5000)).unwrap().view, IconDisplay::Percentage);
+	}
+
+	#[test]
+	fn setting_views_keeps_or_restarts_the_rotation() {
+		let start = Instant::now();
+		let mut rotation = IconRotation::new(start);
+		let views = [IconDisplay::Percentage, IconDisplay::TimeRemaining];
+		rotation.set_views(&views, Duration::from_secs(5), start);
+		rotation.tick(start + millis(5000));
+
+		// The same views carry on
+		rotation.set_views(&views, Duration::from_secs(5), start + millis(6000));
+		assert_eq!(rotation.frame(start + millis(6000)).unwrap().view, IconDisplay::TimeRemaining);
+
+		// A new interval starts over, and is kept to at least a second
+		rotation.set_views(&views, millis(10), start + millis(6000));
+		assert_eq!(rotation.frame(start + millis(6000)).unwrap().view, IconDisplay::Percentage);
+		assert_eq!(rotation.deadline(), Some(start + millis(6000) + MIN_ROTATE_INTERVAL));
+
+		// A single view doesn't rotate
+		rotation.set_views(&[IconDisplay::Watts], Duration::from_secs(5), start);
+		assert_eq!(rotation.tick(start + millis(60_000)), None);
+		assert_eq!(rotation.frame(start), None);
+		assert_eq!(rotation.deadline(), None);
+	}
+}
@@ -5,17 +5,51 @@
 
 use winit::event_loop::EventLoop;
 use tray_icon::{TrayIconEvent, menu::MenuEvent};
+use crate::debug_util::dmsg;
 
 mod battery_monitor;
 mod icon_builder;
 mod battery_tray_icon;
 mod battery_tray_app;
+mod power_events;
+mod sample_scheduler;
+mod config;
+mod sleep_log;
+mod cli;
+mod tray_menu;
+mod shell;
+mod battery_report;
+mod autostart;
+mod single_instance;
+mod control;
+mod battery_history;
+mod metrics_exporter;
+mod mqtt_publisher;
+mod charge_control;
+mod battery_events;
+mod hooks;
+mod status_bar;
+mod tui;
+mod history_window;
+mod tray_click;
+mod icon_rotation;
+mod icon_animation;
+mod alert_level;
+mod glyph_pack;
+mod icon_export;
+mod icon_render;
 mod debug_util;
 
+// How long a control request waits for the event loop to answer
+const CONTROL_REQUEST_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(5);
+
 #[derive(Debug)]
 pub enum UserEvent {
 	TrayIconEvent(tray_icon::TrayIconEvent),
 	MenuEvent(tray_icon::menu::MenuEvent),
+	PowerEvent(power_events::PowerEvent),
+	/// A request from another process, answered through the sender
+	ControlRequest(control::ControlRequest, std::sync::mpsc::Sender<Result<serde_json::Value, String>>),
 }
 
 fn main() -> Result<(), String> {
@@ -23,18 +57,49 @@ fn main() -> Result<(), String> {
 	#[cfg(debug_assertions)]
 	std::env::set_var("RUST_BACKTRACE", "full");
 
+	// Commands other than the default run and exit without creating the tray icon
+	let command = cli::parse_args(std::env::args().skip(1)).unwrap_or_else(|e| cli::exit_with_error(&e));
+	if command != cli::Command::Tray {
+		return cli::run(command);
+	}
+
+	// Only one tray icon at a time. Other processes reach this one through its control endpoint
+	let instance_listener = match single_instance::acquire()? {
+		single_instance::Instance::Primary(listener) => listener,
+		single_instance::Instance::Secondary => {
+			dmsg!("Already running, exiting");
+			return Ok(());
+		}
+	};
+
+	let config = config::Config::load();
+
 	// Create battery monitor
 	let battery_monitor = battery_monitor::BatteryMonitor::new()?;
 
 	// The icon builder holds the image resources for drawing digits
 	let icon_builder = icon_builder::IconBuilder::new().unwrap();
-	
-	// Create application
-	let mut app = battery_tray_app::BatteryTrayApp::new(battery_monitor, icon_builder);
 
 	// Create event loop with user events
 	let event_loop = EventLoop::<UserEvent>::with_user_event().build().map_err(|e| format!("Failed to create event loop: {:?}", e))?;
 
+	// Subscribe to OS power notifications so we update as soon as the power state changes
+	let proxy = event_loop.create_proxy();
+	let power_events = power_events::PowerEventSource::start(move |event| {
+		let _ = proxy.send_event(UserEvent::PowerEvent(event));
+	});
+
+	// Control requests are answered on the event loop, which owns the battery monitor and config
+	let proxy = event_loop.create_proxy();
+	instance_listener.start(move |request| {
+		let (reply, response) = std::sync::mpsc::channel();
+		proxy.send_event(UserEvent::ControlRequest(request, reply)).map_err(|_| "The app is shutting down".to_string())?;
+		response.recv_timeout(CONTROL_REQUEST_TIMEOUT).map_err(|_| "Timed out waiting for the app".to_string())?
+	})?;
+
+	// Create application
+	let mut app = battery_tray_app::BatteryTrayApp::new(battery_monitor, icon_builder, power_events, config);
+
 	// Set up tray event handlers to forward events to winit event loop
 	let proxy = event_loop.create_proxy();
 	TrayIconEvent::set_event_handler(Some(move |event| {
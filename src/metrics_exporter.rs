@@ -0,0 +1,175 @@
+use std::io::{BufRead, BufReader, Write};
+use std::net::{Ipv4Addr, TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use crate::battery_monitor::{self, BatteryInfo};
+use crate::debug_util::dmsg;
+
+const STATES: [&str; 5] = ["unknown", "charging", "discharging", "empty", "full"];
+
+// Requests are answered one at a time, so a client that stops talking can't hold up the rest
+// for longer than this
+const CONNECTION_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// What the exporter reports, updated after each battery sample
+#[derive(Debug, Clone, Default)]
+pub struct MetricsSnapshot {
+	pub battery_info: Option<BatteryInfo>,
+	pub samples_taken: u64,
+	pub sample_errors: u64,
+}
+
+/// Serves the latest battery sample in the Prometheus text format on localhost
+pub struct MetricsExporter {
+	snapshot: Arc<Mutex<MetricsSnapshot>>,
+	port: u16,
+}
+
+impl MetricsExporter {
+	pub fn start(port: u16) -> Result<Self, String> {
+		let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, port))
+			.map_err(|e| format!("Failed to listen on port {}: {:?}", port, e))?;
+		let port = listener.local_addr().map_err(|e| format!("Failed to read metrics address: {:?}", e))?.port();
+		let snapshot = Arc::new(Mutex::new(MetricsSnapshot::default()));
+
+		let shared = snapshot.clone();
+		std::thread::Builder::new()
+			.name("metrics-exporter".into())
+			.spawn(move || {
+				for stream in listener.incoming().flatten() {
+					if let Err(_e) = serve(stream, &shared) {
+						dmsg!("Metrics request failed: {}", _e);
+					}
+				}
+			})
+			.map_err(|e| format!("Failed to start metrics exporter: {:?}", e))?;
+
+		Ok(Self { snapshot, port })
+	}
+
+	/// The port being served, which the OS picks when started with port 0
+	pub fn port(&self) -> u16 {
+		self.port
+	}
+
+	pub fn update(&self, snapshot: MetricsSnapshot) {
+		if let Ok(mut current) = self.snapshot.lock() {
+			*current = snapshot;
+		}
+	}
+}
+
+fn serve(stream: TcpStream, snapshot: &Mutex<MetricsSnapshot>) -> Result<(), String> {
+	stream.set_read_timeout(Some(CONNECTION_TIMEOUT)).map_err(|e| format!("Failed to set timeout: {:?}", e))?;
+	stream.set_write_timeout(Some(CONNECTION_TIMEOUT)).map_err(|e| format!("Failed to set timeout: {:?}", e))?;
+	let mut reader = BufReader::new(stream);
+	let mut request_line = String::new();
+	reader.read_line(&mut request_line).map_err(|e| format!("Failed to read request: {:?}", e))?;
+
+	// Skip the headers, nothing in them changes the response
+	let mut header = String::new();
+	while reader.read_line(&mut header).map_err(|e| format!("Failed to read request: {:?}", e))? > 0 && !header.trim().is_empty() {
+		header.clear();
+	}
+
+	let mut parts = request_line.split_whitespace();
+	let (status, body) = match (parts.next(), parts.next()) {
+		(Some("GET"), Some("/metrics")) => {
+			let snapshot = snapshot.lock().map(|s| s.clone()).unwrap_or_default();
+			("200 OK", format_metrics(&snapshot))
+		}
+		_ => ("404 Not Found", "Metrics are at /metrics\n".to_string()),
+	};
+
+	let response = format!(
+		"HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4; charset=utf-8\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+		status, body.len(), body,
+	);
+	reader.get_mut().write_all(response.as_bytes()).map_err(|e| format!("Failed to send response: {:?}", e))
+}
+
+/// Render the snapshot in the Prometheus text exposition format. Battery gauges are left out
+/// until there's been a successful sample
+pub fn format_metrics(snapshot: &MetricsSnapshot) -> String {
+	let mut text = String::new();
+	let mut metric = |name: &str, kind: &str, help: &str, samples: &[(String, f64)]| {
+		text += &format!("# HELP {} {}\n# TYPE {} {}\n", name, help, name, kind);
+		for (labels, value) in samples {
+			text += &format!("{}{} {}\n", name, labels, value);
+		}
+	};
+
+	if let Some(info) = &snapshot.battery_info {
+		metric("battery_charge_percent", "gauge", "State of charge in percent", &[(String::new(), info.percentage as f64)]);
+		metric("battery_discharge_rate_percent_per_hour", "gauge", "Discharge rate in percent per hour, negative while charging",
+			&[(String::new(), info.discharge_rate_percent as f64)]);
+		metric("battery_power_draw_watts", "gauge", "Smoothed power drawn from the battery, negative while charging",
+			&[(String::new(), battery_monitor::round_watts(info.power_draw_watts))]);
+		metric("battery_plugged_in", "gauge", "1 when on external power", &[(String::new(), if info.is_charging { 1.0 } else { 0.0 })]);
+
+		let state = info.state.to_string();
+		let states: Vec<(String, f64)> = STATES.iter()
+			.map(|s| (format!("{{state=\"{}\"}}", s), if *s == state { 1.0 } else { 0.0 }))
+			.collect();
+		metric("battery_state", "gauge", "Power state reported by the battery", &states);
+
+		metric("battery_health_percent", "gauge", "Full charge capacity as a percentage of design capacity",
+			&[(String::new(), info.health_percent as f64)]);
+		if let Some(cycle_count) = info.cycle_count {
+			metric("battery_cycle_count", "gauge", "Charge cycles reported by the battery", &[(String::new(), cycle_count as f64)]);
+		}
+	}
+
+	metric("battery_samples_total", "counter", "Successful battery samples", &[(String::new(), snapshot.samples_taken as f64)]);
+	metric("battery_sample_errors_total", "counter", "Failed battery samples", &[(String::new(), snapshot.sample_errors as f64)]);
+	text
+}
+
+#[cfg(test)]
+mod tests {
+	use std::io::Read;
+	use starship_battery::State;
+	use super::*;
+
+	fn get(port: u16, path: &str) -> String {
+		let mut stream = TcpStream::connect((Ipv4Addr::LOCALHOST, port)).unwrap();
+		write!(stream, "GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path).unwrap();
+		let mut response = String::new();
+		stream.read_to_string(&mut response).unwrap();
+		response
+	}
+
+	#[test]
+	fn serves_metrics_over_http() {
+		let exporter = MetricsExporter::start(0).unwrap();
+		// A client that connects and says nothing mustn't block the next one for good
+		let _idle = TcpStream::connect((Ipv4Addr::LOCALHOST, exporter.port())).unwrap();
+
+		exporter.update(MetricsSnapshot {
+			battery_info: Some(BatteryInfo {
+				percentage: 78,
+				discharge_rate_percent: 12,
+				power_draw_watts: 9.0,
+				instant_power_draw_watts: 10.0,
+				is_charging: false,
+				state: State::Discharging,
+				health_percent: 91,
+				cycle_count: Some(120),
+			}),
+			samples_taken: 3,
+			sample_errors: 1,
+		});
+
+		let response = get(exporter.port(), "/metrics");
+		assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+		let body = response.split_once("\r\n\r\n").unwrap().1;
+		assert!(body.contains("# TYPE battery_charge_percent gauge\nbattery_charge_percent 78\n"));
+		assert!(body.contains("battery_state{state=\"discharging\"} 1\n"));
+		assert!(body.contains("battery_state{state=\"charging\"} 0\n"));
+		assert!(body.contains("battery_cycle_count 120\n"));
+		assert!(body.contains("battery_samples_total 3\n"));
+		assert!(body.contains("battery_sample_errors_total 1\n"));
+
+		assert!(get(exporter.port(), "/").starts_with("HTTP/1.1 404 Not Found\r\n"));
+	}
+}
@@ -0,0 +1,394 @@
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::sync::Arc;
+use std::time::Duration;
+use serde_json::{json, Value};
+use crate::battery_monitor::BatteryInfo;
+use crate::control;
+use crate::debug_util::dmsg;
+
+const KEEP_ALIVE: Duration = Duration::from_secs(60);
+const MIN_RECONNECT_DELAY: Duration = Duration::from_secs(1);
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(5 * 60);
+const DISCOVERY_PREFIX: &str = "homeassistant";
+
+/// Where and how to publish, from the config file
+#[derive(Debug, Clone, PartialEq)]
+pub struct MqttSettings {
+	/// Broker address as host:port
+	pub broker: String,
+	/// Topics are published under this, e.g. `<prefix>/state`
+	pub topic_prefix: String,
+	pub username: Option<String>,
+	pub password: Option<String>,
+}
+
+/// Publishes battery state to an MQTT broker from a background thread, along with Home Assistant
+/// discovery config so the sensors show up without any setup. Everything is retained, and the
+/// broker marks us offline through our will message if we disappear
+pub struct MqttPublisher {
+	sender: Sender<Message>,
+	connected: Arc<AtomicBool>,
+}
+
+enum Message {
+	State(BatteryInfo),
+	/// A one-off message, not retained
+	Custom { topic: String, payload: String },
+}
+
+impl MqttPublisher {
+	pub fn start(settings: MqttSettings) -> Result<Self, String> {
+		let (sender, receiver) = mpsc::channel();
+		let connected = Arc::new(AtomicBool::new(false));
+		let thread_connected = connected.clone();
+		std::thread::Builder::new()
+			.name("mqtt-publisher".into())
+			.spawn(move || run(settings, receiver, &thread_connected))
+			.map_err(|e| format!("Failed to start MQTT publisher: {:?}", e))?;
+		Ok(Self { sender, connected })
+	}
+
+	pub fn publish(&self, battery_info: &BatteryInfo) {
+		let _ = self.sender.send(Message::State(battery_info.clone()));
+	}
+
+	/// Send a message to any topic. Returns false if it was dropped because we're not connected
+	pub fn publish_message(&self, topic: &str, payload: &str) -> bool {
+		self.connected.load(Ordering::SeqCst)
+			&& self.sender.send(Message::Custom { topic: topic.to_string(), payload: payload.to_string() }).is_ok()
+	}
+}
+
+// Keep a connection to the broker, reconnecting with backoff, until the publisher is dropped
+fn run(settings: MqttSettings, receiver: Receiver<Message>, connected: &AtomicBool) {
+	let node_id = node_id();
+	let mut latest: Option<BatteryInfo> = None;
+	let mut delay = MIN_RECONNECT_DELAY;
+
+	loop {
+		match Connection::open(&settings, &node_id) {
+			Ok(mut connection) => {
+				delay = MIN_RECONNECT_DELAY;
+				connected.store(true, Ordering::SeqCst);
+				let result = connection.serve(&settings, &node_id, &receiver, &mut latest);
+				connected.store(false, Ordering::SeqCst);
+				match result {
+					Ok(()) => return,
+					Err(_e) => { dmsg!("MQTT connection lost: {}", _e); }
+				}
+			}
+			Err(_e) => { dmsg!("Failed to connect to MQTT broker {}: {}", settings.broker, _e); }
+		}
+
+		// Wait before reconnecting, keeping track of the latest state in the meantime
+		let retry_at = std::time::Instant::now() + delay;
+		loop {
+			let remaining = retry_at.saturating_duration_since(std::time::Instant::now());
+			match receiver.recv_timeout(remaining) {
+				Ok(Message::State(info)) => latest = Some(info),
+				Ok(Message::Custom { .. }) => {}
+				Err(RecvTimeoutError::Timeout) => break,
+				Err(RecvTimeoutError::Disconnected) => return,
+			}
+		}
+		delay = (delay * 2).min(MAX_RECONNECT_DELAY);
+	}
+}
+
+struct Connection {
+	stream: TcpStream,
+}
+
+impl Connection {
+	fn open(settings: &MqttSettings, node_id: &str) -> Result<Self, String> {
+		let stream = TcpStream::connect(&settings.broker).map_err(|e| format!("{:?}", e))?;
+		stream.set_read_timeout(Some(KEEP_ALIVE)).map_err(|e| format!("{:?}", e))?;
+		stream.set_write_timeout(Some(KEEP_ALIVE)).map_err(|e| format!("{:?}", e))?;
+		let mut connection = Self { stream };
+
+		let will = (availability_topic(settings), "offline");
+		let client_id = format!("battery-percent-{}", node_id);
+		connection.send(&encode_connect(&client_id, KEEP_ALIVE, will, settings.username.as_deref(), settings.password.as_deref())?)?;
+
+		let mut connack = [0u8; 4];
+		connection.stream.read_exact(&mut connack).map_err(|e| format!("No CONNACK: {:?}", e))?;
+		if connack[0] != 0x20 || connack[3] != 0 {
+			return Err(format!("Connection refused, code {}", connack[3]));
+		}
+		Ok(connection)
+	}
+
+	fn send(&mut self, packet: &[u8]) -> Result<(), String> {
+		self.stream.write_all(packet).map_err(|e| format!("Failed to send: {:?}", e))
+	}
+
+	fn publish(&mut self, topic: &str, payload: &str) -> Result<(), String> {
+		self.send(&encode_publish(topic, payload.as_bytes(), true)?)
+	}
+
+	/// Announce ourselves and publish state changes until the channel closes
+	fn serve(&mut self, settings: &MqttSettings, node_id: &str, receiver: &Receiver<Message>, latest: &mut Option<BatteryInfo>) -> Result<(), String> {
+		for (topic, config) in discovery_configs(settings, node_id) {
+			self.publish(&topic, &config.to_string())?;
+		}
+		self.publish(&availability_topic(settings), "online")?;
+		if let Some(info) = latest {
+			self.publish(&state_topic(settings), &control::battery_info_json(info).to_string())?;
+		}
+
+		loop {
+			match receiver.recv_timeout(KEEP_ALIVE / 2) {
+				// A message that can't be encoded is dropped, the connection is still fine
+				Ok(Message::Custom { topic, payload }) => match encode_publish(&topic, payload.as_bytes(), false) {
+					Ok(packet) => self.send(&packet)?,
+					Err(_e) => { dmsg!("Not publishing to {}: {}", topic, _e); }
+				},
+				Ok(Message::State(info)) => {
+					// Kept even if publishing fails, so it's sent again once we reconnect
+					let state = control::battery_info_json(&info).to_string();
+					*latest = Some(info);
+					self.publish(&state_topic(settings), &state)?;
+				}
+				Err(RecvTimeoutError::Timeout) => {
+					// Nothing to send, so ping to keep the connection alive and check it's still up
+					self.send(&[0xC0, 0x00])?;
+					let mut pingresp = [0u8; 2];
+					self.stream.read_exact(&mut pingresp).map_err(|e| format!("No PINGRESP: {:?}", e))?;
+				}
+				Err(RecvTimeoutError::Disconnected) => return Ok(()),
+			}
+		}
+	}
+}
+
+fn state_topic(settings: &MqttSettings) -> String {
+	format!("{}/state", settings.topic_prefix)
+}
+
+fn availability_topic(settings: &MqttSettings) -> String {
+	format!("{}/availability", settings.topic_prefix)
+}
+
+/// Default topic prefix, unique per machine
+pub fn default_topic_prefix() -> String {
+	format!("battery-percent/{}", node_id())
+}
+
+// Host name reduced to the characters Home Assistant allows in ids
+fn node_id() -> String {
+	let host = std::env::var("COMPUTERNAME").or_else(|_| std::env::var("HOSTNAME")).ok()
+		.or_else(|| std::fs::read_to_string("/proc/sys/kernel/hostname").ok())
+		.unwrap_or_else(|| "laptop".to_string());
+	host.trim().chars().map(|c| if c.is_ascii_alphanumeric() { c.to_ascii_lowercase() } else { '_' }).collect()
+}
+
+/// Home Assistant discovery topics and their config payloads, one per sensor
+pub fn discovery_configs(settings: &MqttSettings, node_id: &str) -> Vec<(String, Value)> {
+	let device = json!({
+		"identifiers": [format!("battery_percent_{}", node_id)],
+		"name": format!("{} battery", node_id),
+	});
+	let sensor = |component: &str, object_id: &str, name: &str, extra: Value| {
+		let mut config = json!({
+			"name": name,
+			"unique_id": format!("battery_percent_{}_{}", node_id, object_id),
+			"state_topic": state_topic(settings),
+			"availability_topic": availability_topic(settings),
+			"device": device,
+		});
+		if let (Some(config), Some(extra)) = (config.as_object_mut(), extra.as_object()) {
+			config.extend(extra.clone());
+		}
+		(format!("{}/{}/{}/{}/config", DISCOVERY_PREFIX, component, node_id, object_id), config)
+	};
+
+	vec![
+		sensor("sensor", "charge", "Battery", json!({
+			"device_class": "battery",
+			"unit_of_measurement": "%",
+			"value_template": "{{ value_json.percentage }}",
+		})),
+		sensor("sensor", "power_draw", "Battery power draw", json!({
+			"device_class": "power",
+			"unit_of_measurement": "W",
+			"value_template": "{{ value_json.power_draw_watts }}",
+		})),
+		sensor("sensor", "discharge_rate", "Battery discharge rate", json!({
+			"unit_of_measurement": "%/h",
+			"value_template": "{{ value_json.discharge_rate_percent }}",
+		})),
+		sensor("binary_sensor", "plugged_in", "Battery plugged in", json!({
+			"device_class": "plug",
+			"value_template": "{{ 'ON' if value_json.is_charging else 'OFF' }}",
+		})),
+	]
+}
+
+// MQTT 3.1.1 packets. We only publish at QoS 0, so CONNECT and PUBLISH are all we need to build
+
+// Largest remaining length the four length bytes can hold
+const MAX_REMAINING_LENGTH: usize = 268_435_455;
+
+fn encode_packet(header: u8, body: &[u8]) -> Result<Vec<u8>, String> {
+	if body.len() > MAX_REMAINING_LENGTH {
+		return Err(format!("Packet of {} bytes is too long", body.len()));
+	}
+	let mut packet = vec![header];
+	// Remaining length is 7 bits per byte, with the top bit set when more bytes follow
+	let mut length = body.len();
+	loop {
+		let byte = (length % 128) as u8;
+		length /= 128;
+		packet.push(if length > 0 { byte | 0x80 } else { byte });
+		if length == 0 {
+			break;
+		}
+	}
+	packet.extend_from_slice(body);
+	Ok(packet)
+}
+
+// Strings and binary fields are prefixed with a two byte length
+fn push_bytes(buffer: &mut Vec<u8>, bytes: &[u8]) -> Result<(), String> {
+	let len = u16::try_from(bytes.len()).map_err(|_| format!("Field of {} bytes is too long", bytes.len()))?;
+	buffer.extend_from_slice(&len.to_be_bytes());
+	buffer.extend_from_slice(bytes);
+	Ok(())
+}
+
+pub fn encode_connect(client_id: &str, keep_alive: Duration, will: (String, &str), username: Option<&str>, password: Option<&str>) -> Result<Vec<u8>, String> {
+	// A password is only allowed along with a user name, brokers drop the connection otherwise
+	let password = password.filter(|_| username.is_some());
+
+	// Clean session, with a retained QoS 0 will
+	let mut flags = 0x02 | 0x04 | 0x20;
+	if username.is_some() {
+		flags |= 0x80;
+	}
+	if password.is_some() {
+		flags |= 0x40;
+	}
+
+	let keep_alive = u16::try_from(keep_alive.as_secs()).map_err(|_| format!("Keep alive of {:?} is too long", keep_alive))?;
+
+	let mut body = Vec::new();
+	push_bytes(&mut body, b"MQTT")?;
+	body.push(4);
+	body.push(flags);
+	body.extend_from_slice(&keep_alive.to_be_bytes());
+	push_bytes(&mut body, client_id.as_bytes())?;
+	push_bytes(&mut body, will.0.as_bytes())?;
+	push_bytes(&mut body, will.1.as_bytes())?;
+	for field in [username, password].into_iter().flatten() {
+		push_bytes(&mut body, field.as_bytes())?;
+	}
+	encode_packet(0x10, &body)
+}
+
+pub fn encode_publish(topic: &str, payload: &[u8], retain: bool) -> Result<Vec<u8>, String> {
+	let mut body = Vec::new();
+	push_bytes(&mut body, topic.as_bytes())?;
+	body.extend_from_slice(payload);
+	encode_packet(if retain { 0x31 } else { 0x30 }, &body)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn encodes_connect() {
+		let will = ("p/availability".to_string(), "offline");
+		let expected: Vec<u8> = [
+			&[0x10, 51][..],
+			&[0, 4], b"MQTT", &[4, 0x02 | 0x04 | 0x20 | 0x80 | 0x40], &[0, 60],
+			&[0, 2], b"id",
+			&[0, 14], b"p/availability",
+			&[0, 7], b"offline",
+			&[0, 4], b"user",
+			&[0, 4], b"pass",
+		].concat();
+		assert_eq!(encode_connect("id", Duration::from_secs(60), will.clone(), Some("user"), Some("pass")), Ok(expected));
+
+		// Without a user name the password is left out
+		let expected: Vec<u8> = [
+			&[0x10, 39][..],
+			&[0, 4], b"MQTT", &[4, 0x02 | 0x04 | 0x20], &[0, 60],
+			&[0, 2], b"id",
+			&[0, 14], b"p/availability",
+			&[0, 7], b"offline",
+		].concat();
+		assert_eq!(encode_connect("id", Duration::from_secs(60), will.clone(), None, Some("pass")), Ok(expected));
+
+		// The keep alive is only two bytes
+		assert!(encode_connect("id", Duration::from_secs(65535), will.clone(), None, None).is_ok());
+		assert!(encode_connect("id", Duration::from_secs(65536), will, None, None).is_err());
+	}
+
+	#[test]
+	fn encodes_publish() {
+		assert_eq!(encode_publish("a/b", b"on", true), Ok([&[0x31, 7, 0, 3][..], b"a/b", b"on"].concat()));
+		assert_eq!(encode_publish("a/b", b"on", false).unwrap()[0], 0x30);
+	}
+
+	#[test]
+	fn rejects_fields_too_long_for_their_length() {
+		let topic = "t".repeat(usize::from(u16::MAX));
+		assert!(encode_publish(&topic, b"", true).is_ok());
+		assert!(encode_publish(&format!("{}t", topic), b"", true).is_err());
+		assert!(encode_connect(&format!("{}t", topic), Duration::from_secs(60), ("a".to_string(), "offline"), None, None).is_err());
+	}
+
+	#[test]
+	fn encodes_long_remaining_length() {
+		let packet = encode_packet(0x30, &[0; 321]).unwrap();
+		// 321 = 65 + 2 * 128
+		assert_eq!(packet[..3], [0x30, 0x80 | 65, 2]);
+		assert_eq!(packet.len(), 3 + 321);
+	}
+
+	// Needs a broker such as mosquitto on localhost:1883 that allows anonymous clients
+	#[test]
+	#[ignore]
+	fn publishes_to_local_broker() {
+		let settings = MqttSettings {
+			broker: "localhost:1883".to_string(),
+			topic_prefix: format!("battery-percent-test/{}", std::process::id()),
+			username: None,
+			password: None,
+		};
+		let publisher = MqttPublisher::start(settings.clone()).unwrap();
+		publisher.publish(&BatteryInfo {
+			percentage: 55,
+			discharge_rate_percent: 8,
+			power_draw_watts: 7.0,
+			instant_power_draw_watts: 7.0,
+			is_charging: false,
+			state: starship_battery::State::Discharging,
+			health_percent: 90,
+			cycle_count: None,
+		});
+
+		// Subscribe with a connection of our own and wait for the retained state
+		let mut subscriber = Connection::open(&MqttSettings { topic_prefix: format!("{}/subscriber", settings.topic_prefix), ..settings.clone() }, "test").unwrap();
+		let topic = state_topic(&settings);
+		let mut subscribe = vec![0, 1];
+		push_bytes(&mut subscribe, topic.as_bytes()).unwrap();
+		subscribe.push(0);
+		subscriber.send(&encode_packet(0x82, &subscribe).unwrap()).unwrap();
+
+		let deadline = std::time::Instant::now() + Duration::from_secs(10);
+		let mut received = Vec::new();
+		let mut buf = [0u8; 1024];
+		subscriber.stream.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+		while std::time::Instant::now() < deadline && !String::from_utf8_lossy(&received).contains("\"percentage\":55") {
+			if let Ok(read) = subscriber.stream.read(&mut buf) {
+				received.extend_from_slice(&buf[..read]);
+			}
+		}
+		assert!(String::from_utf8_lossy(&received).contains("\"percentage\":55"));
+	}
+}
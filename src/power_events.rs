@@ -0,0 +1,216 @@
+use crate::debug_util::dmsg;
+
+/// Notifications from the OS that the power state may have changed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PowerEvent {
+	/// The power source or battery charge changed
+	Changed,
+	/// The system is about to sleep
+	Suspending,
+	/// The system woke up from sleep
+	Resumed,
+}
+
+/// Subscription to OS power notifications. If no subscription could be made the app
+/// falls back to polling the battery on a timer
+pub struct PowerEventSource {
+	is_event_driven: bool,
+}
+
+impl PowerEventSource {
+	pub fn start<F: Fn(PowerEvent) + Send + 'static>(handler: F) -> Self {
+		let is_event_driven = match platform::subscribe(Box::new(handler)) {
+			Ok(()) => true,
+			Err(_e) => {
+				dmsg!("Power notifications unavailable, polling only: {}", _e);
+				false
+			}
+		};
+
+		Self { is_event_driven }
+	}
+
+	/// Whether power changes will be reported as events, rather than only being seen by polling
+	pub fn is_event_driven(&self) -> bool {
+		self.is_event_driven
+	}
+
+	/// Whether resuming from sleep will be reported as an event
+	pub fn reports_resume(&self) -> bool {
+		self.is_event_driven && platform::REPORTS_RESUME
+	}
+}
+
+type PowerEventHandler = Box<dyn Fn(PowerEvent) + Send + 'static>;
+
+#[cfg(target_os = "linux")]
+mod platform {
+	use std::fs::File;
+	use std::io::Read;
+	use std::os::fd::{FromRawFd, OwnedFd};
+	use super::{PowerEvent, PowerEventHandler};
+	use crate::debug_util::dmsg;
+
+	// Multicast group the kernel sends uevents to
+	const KERNEL_UEVENT_GROUP: u32 = 1;
+
+	// The kernel sends no uevent for suspend or resume
+	pub const REPORTS_RESUME: bool = false;
+
+	pub fn subscribe(handler: PowerEventHandler) -> Result<(), String> {
+		// Listen for kernel uevents on a netlink socket, the same source udev uses
+		let socket = unsafe {
+			let fd = libc::socket(libc::AF_NETLINK, libc::SOCK_DGRAM | libc::SOCK_CLOEXEC, libc::NETLINK_KOBJECT_UEVENT);
+			if fd < 0 {
+				return Err(format!("Failed to create uevent socket: {}", std::io::Error::last_os_error()));
+			}
+			let socket = OwnedFd::from_raw_fd(fd);
+
+			let mut addr: libc::sockaddr_nl = std::mem::zeroed();
+			addr.nl_family = libc::AF_NETLINK as libc::sa_family_t;
+			addr.nl_groups = KERNEL_UEVENT_GROUP;
+			let addr_ptr = &addr as *const libc::sockaddr_nl as *const libc::sockaddr;
+			if libc::bind(fd, addr_ptr, std::mem::size_of::<libc::sockaddr_nl>() as libc::socklen_t) < 0 {
+				return Err(format!("Failed to bind uevent socket: {}", std::io::Error::last_os_error()));
+			}
+			socket
+		};
+
+		std::thread::Builder::new()
+			.name("power-events".into())
+			.spawn(move || {
+				let mut socket = File::from(socket);
+				let mut buf = [0u8; 8192];
+				loop {
+					let len = match socket.read(&mut buf) {
+						Ok(len) => len,
+						Err(_e) => {
+							dmsg!("Failed to read uevent: {}", _e);
+							return;
+						}
+					};
+
+					// Messages are "action@devpath" followed by null separated KEY=VALUE pairs
+					let is_power_supply = buf[..len]
+						.split(|b| *b == 0)
+						.any(|field| field == b"SUBSYSTEM=power_supply");
+					if is_power_supply {
+						handler(PowerEvent::Changed);
+					}
+				}
+			})
+			.map_err(|e| format!("Failed to start power event thread: {:?}", e))?;
+
+		Ok(())
+	}
+}
+
+#[cfg(target_os = "windows")]
+mod platform {
+	use std::sync::{Mutex, OnceLock};
+	use windows_sys::core::GUID;
+	use windows_sys::Win32::Foundation::{HWND, LPARAM, LRESULT, WPARAM};
+	use windows_sys::Win32::System::LibraryLoader::GetModuleHandleW;
+	use windows_sys::Win32::System::Power::RegisterPowerSettingNotification;
+	use windows_sys::Win32::System::SystemServices::{GUID_ACDC_POWER_SOURCE, GUID_BATTERY_PERCENTAGE_REMAINING};
+	use windows_sys::Win32::UI::WindowsAndMessaging::{
+		CreateWindowExW, DefWindowProcW, DispatchMessageW, GetMessageW, RegisterClassW,
+		DEVICE_NOTIFY_WINDOW_HANDLE, MSG, PBT_APMPOWERSTATUSCHANGE, PBT_APMRESUMEAUTOMATIC,
+		PBT_APMSUSPEND, PBT_POWERSETTINGCHANGE, WM_POWERBROADCAST, WNDCLASSW,
+	};
+	use super::{PowerEvent, PowerEventHandler};
+	use crate::debug_util::dmsg;
+
+	static HANDLER: OnceLock<Mutex<PowerEventHandler>> = OnceLock::new();
+
+	const POWER_SETTINGS: [GUID; 2] = [GUID_ACDC_POWER_SOURCE, GUID_BATTERY_PERCENTAGE_REMAINING];
+
+	pub const REPORTS_RESUME: bool = true;
+
+	unsafe extern "system" fn window_proc(hwnd: HWND, msg: u32, wparam: WPARAM, lparam: LPARAM) -> LRESULT {
+		if msg == WM_POWERBROADCAST {
+			let event = match wparam as u32 {
+				PBT_POWERSETTINGCHANGE | PBT_APMPOWERSTATUSCHANGE => Some(PowerEvent::Changed),
+				PBT_APMSUSPEND => Some(PowerEvent::Suspending),
+				PBT_APMRESUMEAUTOMATIC => Some(PowerEvent::Resumed),
+				_ => None,
+			};
+			if let (Some(event), Some(handler)) = (event, HANDLER.get()) {
+				if let Ok(handler) = handler.lock() {
+					handler(event);
+				}
+			}
+			return 1;
+		}
+		DefWindowProcW(hwnd, msg, wparam, lparam)
+	}
+
+	pub fn subscribe(handler: PowerEventHandler) -> Result<(), String> {
+		if HANDLER.set(Mutex::new(handler)).is_err() {
+			return Err("Already subscribed to power events".to_string());
+		}
+
+		// Power broadcasts are delivered to a window, so create a hidden window on its own thread
+		// and pump its messages there. It has to be a top-level window, as message-only windows
+		// don't get the broadcasts for suspend and resume
+		let (result_tx, result_rx) = std::sync::mpsc::channel();
+		std::thread::Builder::new()
+			.name("power-events".into())
+			.spawn(move || unsafe {
+				let class_name: Vec<u16> = "windows-tray-battery-percent-power\0".encode_utf16().collect();
+				let instance = GetModuleHandleW(std::ptr::null());
+				let class = WNDCLASSW {
+					style: 0,
+					lpfnWndProc: Some(window_proc),
+					cbClsExtra: 0,
+					cbWndExtra: 0,
+					hInstance: instance,
+					hIcon: std::ptr::null_mut(),
+					hCursor: std::ptr::null_mut(),
+					hbrBackground: std::ptr::null_mut(),
+					lpszMenuName: std::ptr::null(),
+					lpszClassName: class_name.as_ptr(),
+				};
+				if RegisterClassW(&class) == 0 {
+					let _ = result_tx.send(Err(format!("Failed to register window class: {}", std::io::Error::last_os_error())));
+					return;
+				}
+
+				let hwnd = CreateWindowExW(
+					0, class_name.as_ptr(), std::ptr::null(), 0,
+					0, 0, 0, 0,
+					std::ptr::null_mut(), std::ptr::null_mut(), instance, std::ptr::null(),
+				);
+				if hwnd.is_null() {
+					let _ = result_tx.send(Err(format!("Failed to create power window: {}", std::io::Error::last_os_error())));
+					return;
+				}
+
+				for setting in POWER_SETTINGS.iter() {
+					if RegisterPowerSettingNotification(hwnd, setting, DEVICE_NOTIFY_WINDOW_HANDLE) == 0 {
+						dmsg!("Failed to register power setting notification: {}", std::io::Error::last_os_error());
+					}
+				}
+				let _ = result_tx.send(Ok(()));
+
+				let mut msg: MSG = std::mem::zeroed();
+				while GetMessageW(&mut msg, std::ptr::null_mut(), 0, 0) > 0 {
+					DispatchMessageW(&msg);
+				}
+			})
+			.map_err(|e| format!("Failed to start power event thread: {:?}", e))?;
+
+		result_rx.recv().map_err(|e| format!("Power event thread exited: {:?}", e))?
+	}
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "windows")))]
+mod platform {
+	use super::PowerEventHandler;
+
+	pub const REPORTS_RESUME: bool = false;
+
+	pub fn subscribe(_handler: PowerEventHandler) -> Result<(), String> {
+		Err("Power notifications are not supported on this platform".to_string())
+	}
+}
@@ -0,0 +1,176 @@
+use std::time::Duration;
+use crate::battery_monitor::{BatteryInfo, MAX_SAMPLE_INTERVAL};
+
+// Percentage at or below which we sample quickly so the icon keeps up as the battery runs out
+const LOW_BATTERY_PERCENT: i32 = 20;
+
+// Change in discharge rate between samples that counts as the rate still settling
+const RATE_CHANGING_PERCENT: i32 = 5;
+
+const NO_BATTERY_SLEEP_SECONDS: u64 = 30;
+const FULL_ON_AC_SLEEP_SECONDS: u64 = 60;
+const CHARGING_SLEEP_SECONDS: u64 = 30;
+const FAST_SLEEP_SECONDS: u64 = 5;
+const STEADY_SLEEP_SECONDS: u64 = 10;
+
+// When the OS tells us about power changes we only need to poll to keep the discharge rate fresh,
+// and an unplug will still be noticed straight away
+const EVENT_DRIVEN_FULL_ON_AC_SLEEP_SECONDS: u64 = 300;
+const EVENT_DRIVEN_STEADY_SLEEP_SECONDS: u64 = 60;
+
+/// Why the scheduler picked the interval it did
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ScheduleReason {
+	/// The last sample failed, so retry at a relaxed pace
+	NoBattery,
+	/// Plugged in and full, nothing interesting is going to happen
+	FullOnAc,
+	Charging,
+	/// Discharging at low charge
+	Low,
+	/// Discharge rate moved noticeably since the previous sample
+	RateChanging,
+	/// Discharging at a steady rate
+	Steady,
+	/// The user picked a fixed interval
+	Fixed,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ScheduleDecision {
+	pub reason: ScheduleReason,
+	pub interval: Duration,
+}
+
+/// Chooses how long to wait before the next battery sample based on the current state
+pub struct SampleScheduler {
+	is_event_driven: bool,
+	fixed_interval: Option<Duration>,
+	previous_discharge_rate_percent: Option<i32>,
+}
+
+impl SampleScheduler {
+	pub fn new(is_event_driven: bool) -> Self {
+		Self {
+			is_event_driven,
+			fixed_interval: None,
+			previous_discharge_rate_percent: None,
+		}
+	}
+
+	/// Always wait this long between samples, or None to adapt to the battery state
+	/// Sample at a fixed interval rather than choosing one, or go back to choosing with None.
+	/// The interval is kept between a second and MAX_SAMPLE_INTERVAL
+	pub fn set_fixed_interval(&mut self, fixed_interval: Option<Duration>) {
+		self.fixed_interval = fixed_interval.map(|interval| interval.clamp(Duration::from_secs(1), MAX_SAMPLE_INTERVAL));
+	}
+
+	pub fn fixed_interval(&self) -> Option<Duration> {
+		self.fixed_interval
+	}
+
+	/// Decide when to sample next given the result of the latest sample
+	pub fn decide(&mut self, battery_info: Option<&BatteryInfo>) -> ScheduleDecision {
+		let reason = match battery_info {
+			_ if self.fixed_interval.is_some() => ScheduleReason::Fixed,
+			None => ScheduleReason::NoBattery,
+			Some(info) => {
+				let rate_changed = self.previous_discharge_rate_percent
+					.is_some_and(|prev| (info.discharge_rate_percent - prev).abs() >= RATE_CHANGING_PERCENT);
+
+				if info.is_charging && info.percentage >= 100 && info.discharge_rate_percent <= 0 {
+					ScheduleReason::FullOnAc
+				}
+				else if rate_changed {
+					ScheduleReason::RateChanging
+				}
+				else if info.is_charging {
+					ScheduleReason::Charging
+				}
+				else if info.percentage <= LOW_BATTERY_PERCENT {
+					ScheduleReason::Low
+				}
+				else {
+					ScheduleReason::Steady
+				}
+			}
+		};
+		self.previous_discharge_rate_percent = battery_info.map(|info| info.discharge_rate_percent);
+
+		ScheduleDecision { reason, interval: self.interval(reason) }
+	}
+
+	fn interval(&self, reason: ScheduleReason) -> Duration {
+		let seconds = match reason {
+			ScheduleReason::Fixed => return self.fixed_interval.unwrap_or(Duration::from_secs(STEADY_SLEEP_SECONDS)),
+			ScheduleReason::NoBattery => NO_BATTERY_SLEEP_SECONDS,
+			ScheduleReason::FullOnAc => {
+				if self.is_event_driven { EVENT_DRIVEN_FULL_ON_AC_SLEEP_SECONDS } else { FULL_ON_AC_SLEEP_SECONDS }
+			}
+			ScheduleReason::Charging => CHARGING_SLEEP_SECONDS,
+			ScheduleReason::Low | ScheduleReason::RateChanging => FAST_SLEEP_SECONDS,
+			ScheduleReason::Steady => {
+				if self.is_event_driven { EVENT_DRIVEN_STEADY_SLEEP_SECONDS } else { STEADY_SLEEP_SECONDS }
+			}
+		};
+		Duration::from_secs(seconds)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use starship_battery::State;
+
+	fn battery(percentage: i32, discharge_rate_percent: i32, is_charging: bool) -> BatteryInfo {
+		BatteryInfo {
+			percentage,
+			discharge_rate_percent,
+			power_draw_watts: 0.0,
+			instant_power_draw_watts: 0.0,
+			is_charging,
+			state: if is_charging { State::Charging } else { State::Discharging },
+			health_percent: 100,
+			cycle_count: None,
+		}
+	}
+
+	#[test]
+	fn full_on_ac_polls_slowly() {
+		let mut scheduler = SampleScheduler::new(false);
+		let decision = scheduler.decide(Some(&battery(100, 0, true)));
+		assert_eq!(decision, ScheduleDecision { reason: ScheduleReason::FullOnAc, interval: Duration::from_secs(FULL_ON_AC_SLEEP_SECONDS) });
+
+		let mut scheduler = SampleScheduler::new(true);
+		let decision = scheduler.decide(Some(&battery(100, 0, true)));
+		assert_eq!(decision.interval, Duration::from_secs(EVENT_DRIVEN_FULL_ON_AC_SLEEP_SECONDS));
+	}
+
+	#[test]
+	fn low_battery_polls_quickly() {
+		let mut scheduler = SampleScheduler::new(true);
+		let decision = scheduler.decide(Some(&battery(LOW_BATTERY_PERCENT, 10, false)));
+		assert_eq!(decision, ScheduleDecision { reason: ScheduleReason::Low, interval: Duration::from_secs(FAST_SLEEP_SECONDS) });
+	}
+
+	#[test]
+	fn changing_rate_polls_quickly_until_it_settles() {
+		let mut scheduler = SampleScheduler::new(false);
+		assert_eq!(scheduler.decide(Some(&battery(60, 10, false))).reason, ScheduleReason::Steady);
+		let decision = scheduler.decide(Some(&battery(60, 10 + RATE_CHANGING_PERCENT, false)));
+		assert_eq!(decision, ScheduleDecision { reason: ScheduleReason::RateChanging, interval: Duration::from_secs(FAST_SLEEP_SECONDS) });
+		let decision = scheduler.decide(Some(&battery(60, 10 + RATE_CHANGING_PERCENT, false)));
+		assert_eq!(decision, ScheduleDecision { reason: ScheduleReason::Steady, interval: Duration::from_secs(STEADY_SLEEP_SECONDS) });
+	}
+
+	#[test]
+	fn each_reason_has_its_own_interval() {
+		let mut scheduler = SampleScheduler::new(false);
+		assert_eq!(scheduler.decide(None), ScheduleDecision { reason: ScheduleReason::NoBattery, interval: Duration::from_secs(NO_BATTERY_SLEEP_SECONDS) });
+		assert_eq!(scheduler.decide(Some(&battery(50, -20, true))).interval, Duration::from_secs(CHARGING_SLEEP_SECONDS));
+
+		scheduler.set_fixed_interval(Some(Duration::from_secs(7)));
+		assert_eq!(scheduler.decide(Some(&battery(50, 10, false))), ScheduleDecision { reason: ScheduleReason::Fixed, interval: Duration::from_secs(7) });
+		assert_eq!(scheduler.decide(None).interval, Duration::from_secs(7));
+	}
+}
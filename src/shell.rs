@@ -0,0 +1,115 @@
+use std::path::Path;
+use std::process::{Command, Stdio};
+
+/// Stop a console program from opening a window of its own
+pub fn hide_console_window(command: &mut Command) {
+	#[cfg(target_os = "windows")]
+	{
+		use std::os::windows::process::CommandExt;
+		const CREATE_NO_WINDOW: u32 = 0x08000000;
+		command.creation_flags(CREATE_NO_WINDOW);
+	}
+}
+
+/// Start a helper process without waiting for it or showing a console window
+pub fn spawn_detached(mut command: Command) -> Result<(), String> {
+	hide_console_window(&mut command);
+	command
+		.stdin(Stdio::null())
+		.stdout(Stdio::null())
+		.stderr(Stdio::null())
+		.spawn()
+		.map(|mut child| {
+			// Wait on a thread of its own so the finished child is reaped rather than left a zombie
+			std::thread::spawn(move || { let _ = child.wait(); });
+		})
+		.map_err(|e| format!("Failed to run {:?}: {:?}", command.get_program(), e))
+}
+
+/// A command line run through the system shell, so users can write pipes and arguments as usual
+pub fn shell_command(command_line: &str) -> Command {
+	#[cfg(target_os = "windows")]
+	{
+		// cmd does its own parsing of the line, so pass it on as written rather than quoted
+		use std::os::windows::process::CommandExt;
+		let mut command = Command::new("cmd");
+		command.arg("/C").raw_arg(command_line);
+		command
+	}
+
+	#[cfg(not(target_os = "windows"))]
+	{
+		let mut command = Command::new("sh");
+		command.args(["-c", command_line]);
+		command
+	}
+}
+
+/// Open a file or folder with the desktop's default handler
+pub fn open_path(path: &Path) -> Result<(), String> {
+	#[cfg(target_os = "windows")]
+	let command = {
+		let mut command = Command::new("explorer");
+		command.arg(path);
+		command
+	};
+
+	#[cfg(not(target_os = "windows"))]
+	let command = {
+		let mut command = Command::new("xdg-open");
+		command.arg(path);
+		command
+	};
+
+	spawn_detached(command)
+}
+
+/// Open the desktop's power settings
+pub fn open_power_settings() -> Result<(), String> {
+	#[cfg(target_os = "windows")]
+	let command = {
+		let mut command = Command::new("explorer");
+		command.arg("ms-settings:powersleep");
+		command
+	};
+
+	// There's no desktop independent way, so go with GNOME's settings panel
+	#[cfg(not(target_os = "windows"))]
+	let command = {
+		let mut command = Command::new("gnome-control-center");
+		command.arg("power");
+		command
+	};
+
+	spawn_detached(command)
+}
+
+/// Show a desktop notification
+pub fn notify(title: &str, body: &str) -> Result<(), String> {
+	#[cfg(target_os = "windows")]
+	let command = {
+		// Raise a toast through PowerShell so we don't need our own registered app id
+		let script = format!(
+			"[Windows.UI.Notifications.ToastNotificationManager, Windows.UI.Notifications, ContentType = WindowsRuntime] | Out-Null; \
+			$xml = [Windows.UI.Notifications.ToastNotificationManager]::GetTemplateContent([Windows.UI.Notifications.ToastTemplateType]::ToastText02); \
+			$text = $xml.GetElementsByTagName('text'); \
+			$text.Item(0).AppendChild($xml.CreateTextNode('{}')) | Out-Null; \
+			$text.Item(1).AppendChild($xml.CreateTextNode('{}')) | Out-Null; \
+			[Windows.UI.Notifications.ToastNotificationManager]::CreateToastNotifier('Windows PowerShell').Show([Windows.UI.Notifications.ToastNotification]::new($xml))",
+			title.replace('\'', "''"),
+			body.replace('\'', "''"),
+		);
+		let mut command = Command::new("powershell");
+		command.args(["-NoProfile", "-NonInteractive", "-Command", &script]);
+		command
+	};
+
+	#[cfg(not(target_os = "windows"))]
+	let command = {
+		let mut command = Command::new("notify-send");
+		command.args(["--app-name=windows-tray-battery-percent", title, body]);
+		command
+	};
+
+	spawn_detached(command)
+}
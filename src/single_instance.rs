@@ -0,0 +1,479 @@
+use std::io::{BufRead, BufReader, Read, Write};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+use serde_json::Value;
+use crate::control::{self, ControlRequest};
+use crate::debug_util::dmsg;
+
+// Clients are a few CLI commands and scripts, so anything past this is misbehaving
+const MAX_CONNECTIONS: usize = 8;
+// Connections idle for longer are closed, so a stuck client can't hold on to one of them
+const CONNECTION_TIMEOUT: Duration = Duration::from_secs(60);
+
+pub enum Instance {
+	/// We're the only instance. Requests from other processes arrive through the listener
+	Primary(InstanceListener),
+	/// Another instance is already running
+	Secondary,
+}
+
+/// Holds the single instance lock for as long as the app runs
+pub struct InstanceListener {
+	listener: platform::Listener,
+}
+
+impl InstanceListener {
+	/// Start answering control requests. Each connection gets its own thread so a client that
+	/// keeps its connection open doesn't hold up others, up to MAX_CONNECTIONS at once
+	pub fn start<F>(self, handler: F) -> Result<(), String>
+	where
+		F: Fn(ControlRequest) -> Result<Value, String> + Send + Sync + 'static,
+	{
+		let handler = Arc::new(handler);
+		let connections = Arc::new(AtomicUsize::new(0));
+		self.listener.start(move |stream| {
+			if connections.fetch_add(1, Ordering::SeqCst) >= MAX_CONNECTIONS {
+				connections.fetch_sub(1, Ordering::SeqCst);
+				dmsg!("Too many control connections, dropping one");
+				return;
+			}
+			let handler = handler.clone();
+			let connections = connections.clone();
+			std::thread::spawn(move || {
+				if let Err(_e) = handle_connection(stream, &*handler) {
+					dmsg!("Control connection failed: {}", _e);
+				}
+				connections.fetch_sub(1, Ordering::SeqCst);
+			});
+		})
+	}
+}
+
+/// Take the single instance lock, or find out another instance already has it
+pub fn acquire() -> Result<Instance, String> {
+	Ok(match platform::Listener::acquire()? {
+		Some(listener) => Instance::Primary(InstanceListener { listener }),
+		None => Instance::Secondary,
+	})
+}
+
+/// Send a request to the running instance and wait for its result
+pub fn request(request: ControlRequest) -> Result<Value, String> {
+	send_line(&request.to_json().to_string())
+}
+
+/// Send a line of JSON to the running instance and wait for its result
+pub fn send_line(line: &str) -> Result<Value, String> {
+	let mut stream = platform::connect()?;
+	writeln!(stream, "{}", line).map_err(|e| format!("Failed to send request: {:?}", e))?;
+	let mut response = String::new();
+	BufReader::new(stream).read_line(&mut response).map_err(|e| format!("Failed to read response: {:?}", e))?;
+	control::parse_response(&response)
+}
+
+// Requests and responses are one line of JSON each, until the client closes the connection
+fn handle_connection<S: Read + Write, F: Fn(ControlRequest) -> Result<Value, String>>(stream: S, handler: &F) -> Result<(), String> {
+	let mut reader = BufReader::new(stream);
+	let mut line = String::new();
+	loop {
+		line.clear();
+		let read = reader.read_line(&mut line).map_err(|e| format!("Failed to read request: {:?}", e))?;
+		if read == 0 {
+			return Ok(());
+		}
+		if line.trim().is_empty() {
+			continue;
+		}
+		let response = control::response(ControlRequest::parse(line.trim()).and_then(handler));
+		writeln!(reader.get_mut(), "{}", response).map_err(|e| format!("Failed to send response: {:?}", e))?;
+	}
+}
+
+#[cfg(target_os = "linux")]
+mod platform {
+	use std::os::fd::AsRawFd;
+	use std::os::linux::net::SocketAddrExt;
+	use std::os::unix::net::{SocketAddr, UnixListener, UnixStream};
+	use super::CONNECTION_TIMEOUT;
+	use crate::debug_util::dmsg;
+
+	// Abstract sockets are released by the kernel when we exit, so the lock can't go stale
+	fn socket_addr() -> Result<SocketAddr, String> {
+		let name = format!("windows-tray-battery-percent-{}", unsafe { libc::getuid() });
+		SocketAddr::from_abstract_name(name).map_err(|e| format!("Invalid socket name: {:?}", e))
+	}
+
+	// Abstract sockets have no file permissions, so anyone on the machine can connect. Only
+	// our own user gets to send requests
+	pub fn is_same_user(stream: &UnixStream) -> bool {
+		let mut cred: libc::ucred = unsafe { std::mem::zeroed() };
+		let mut len = std::mem::size_of::<libc::ucred>() as libc::socklen_t;
+		let result = unsafe {
+			libc::getsockopt(
+				stream.as_raw_fd(),
+				libc::SOL_SOCKET,
+				libc::SO_PEERCRED,
+				&mut cred as *mut libc::ucred as *mut libc::c_void,
+				&mut len,
+			)
+		};
+		result == 0 && cred.uid == unsafe { libc::getuid() }
+	}
+
+	pub struct Listener(UnixListener);
+
+	impl Listener {
+		pub fn acquire() -> Result<Option<Self>, String> {
+			match UnixListener::bind_addr(&socket_addr()?) {
+				Ok(listener) => Ok(Some(Listener(listener))),
+				Err(e) if e.kind() == std::io::ErrorKind::AddrInUse => Ok(None),
+				Err(e) => Err(format!("Failed to bind instance socket: {:?}", e)),
+			}
+		}
+
+		pub fn start<F: Fn(UnixStream) + Send + 'static>(self, handler: F) -> Result<(), String> {
+			std::thread::Builder::new()
+				.name("instance-listener".into())
+				.spawn(move || {
+					for stream in self.0.incoming().flatten() {
+						if !is_same_user(&stream) {
+							dmsg!("Refused a control connection from another user");
+						}
+						else if let Err(_e) = stream.set_read_timeout(Some(CONNECTION_TIMEOUT)) {
+							dmsg!("Failed to set control connection timeout: {:?}", _e);
+						}
+						else {
+							handler(stream);
+						}
+					}
+				})
+				.map(|_| ())
+				.map_err(|e| format!("Failed to start instance listener: {:?}", e))
+		}
+	}
+
+	pub fn connect() -> Result<UnixStream, String> {
+		UnixStream::connect_addr(&socket_addr()?).map_err(|e| format!("Failed to connect to running instance: {:?}", e))
+	}
+}
+
+#[cfg(target_os = "windows")]
+mod platform {
+	use std::ffi::c_void;
+	use std::fs::File;
+	use std::io::{Read, Write};
+	use std::time::Duration;
+	use windows_sys::Win32::Foundation::{
+		CloseHandle, GetLastError, BOOL, ERROR_ALREADY_EXISTS, ERROR_BROKEN_PIPE, ERROR_IO_PENDING, ERROR_PIPE_CONNECTED,
+		HANDLE, INVALID_HANDLE_VALUE, WAIT_TIMEOUT,
+	};
+	use windows_sys::Win32::Security::{
+		AddAccessAllowedAce, GetLengthSid, GetTokenInformation, InitializeAcl, InitializeSecurityDescriptor, SetSecurityDescriptorDacl,
+		TokenUser, ACCESS_ALLOWED_ACE, ACL, ACL_REVISION, SECURITY_ATTRIBUTES, SECURITY_DESCRIPTOR, TOKEN_QUERY, TOKEN_USER,
+	};
+	use windows_sys::Win32::Storage::FileSystem::{
+		ReadFile, WriteFile, FILE_ALL_ACCESS, FILE_FLAG_FIRST_PIPE_INSTANCE, FILE_FLAG_OVERLAPPED, PIPE_ACCESS_DUPLEX,
+	};
+	use windows_sys::Win32::System::IO::{CancelIoEx, GetOverlappedResult, OVERLAPPED};
+	use windows_sys::Win32::System::Pipes::{
+		ConnectNamedPipe, CreateNamedPipeW, PIPE_READMODE_BYTE, PIPE_REJECT_REMOTE_CLIENTS, PIPE_TYPE_BYTE,
+		PIPE_UNLIMITED_INSTANCES, PIPE_WAIT,
+	};
+	use windows_sys::Win32::System::SystemServices::SECURITY_DESCRIPTOR_REVISION;
+	use windows_sys::Win32::System::Threading::{CreateEventW, CreateMutexW, GetCurrentProcess, OpenProcessToken, WaitForSingleObject, INFINITE};
+	use super::CONNECTION_TIMEOUT;
+	use crate::debug_util::dmsg;
+
+	const PIPE_BUFFER_SIZE: u32 = 4096;
+
+	fn wide(s: &str) -> Vec<u16> {
+		s.encode_utf16().chain(std::iter::once(0)).collect()
+	}
+
+	// Pipe names are shared by every session on the machine, so include the user name
+	fn pipe_name() -> String {
+		let user = std::env::var("USERNAME").unwrap_or_default();
+		format!("\\\\.\\pipe\\windows-tray-battery-percent-{}", user)
+	}
+
+	/// The named mutex is the lock, it's released by Windows when we exit
+	pub struct Listener {
+		_mutex: HANDLE,
+	}
+
+	// The mutex handle is only held, never used, so it's fine to move between threads
+	unsafe impl Send for Listener {}
+
+	impl Listener {
+		pub fn acquire() -> Result<Option<Self>, String> {
+			let mutex = unsafe { CreateMutexW(std::ptr::null(), 0, wide("Local\\windows-tray-battery-percent").as_ptr()) };
+			if mutex.is_null() {
+				return Err(format!("Failed to create instance mutex: {}", std::io::Error::last_os_error()));
+			}
+			if unsafe { GetLastError() } == ERROR_ALREADY_EXISTS {
+				return Ok(None);
+			}
+			Ok(Some(Listener { _mutex: mutex }))
+		}
+
+		pub fn start<F: Fn(Pipe) + Send + 'static>(self, handler: F) -> Result<(), String> {
+			std::thread::Builder::new()
+				.name("instance-listener".into())
+				.spawn(move || {
+					let _lock = self;
+					let name = wide(&pipe_name());
+					let security = match UserOnlySecurity::new() {
+						Ok(security) => security,
+						Err(_e) => {
+							dmsg!("Failed to restrict the control pipe to our user: {:?}", _e);
+							return;
+						}
+					};
+					// The first instance must be ours, otherwise another process got to the name first
+					// and would be reading our clients' requests
+					let mut open_mode = PIPE_ACCESS_DUPLEX | FILE_FLAG_OVERLAPPED | FILE_FLAG_FIRST_PIPE_INSTANCE;
+					loop {
+						// Each client gets a fresh pipe instance, which is closed when the Pipe is dropped
+						let pipe = match Pipe::create(&name, open_mode, &security.attributes) {
+							Ok(pipe) => pipe,
+							Err(_e) => {
+								dmsg!("Failed to create control pipe: {:?}", _e);
+								return;
+							}
+						};
+						open_mode &= !FILE_FLAG_FIRST_PIPE_INSTANCE;
+						match pipe.connect() {
+							Ok(()) => handler(pipe),
+							Err(_e) => {
+								dmsg!("Failed to accept control connection: {:?}", _e);
+							}
+						}
+					}
+				})
+				.map(|_| ())
+				.map_err(|e| format!("Failed to start instance listener: {:?}", e))
+		}
+	}
+
+	/// Security attributes for a pipe only our own user can open. Pipes are open to everyone
+	/// logged in by default, and on Linux the same is done by checking the peer's uid
+	struct UserOnlySecurity {
+		attributes: SECURITY_ATTRIBUTES,
+		// Pointed to by the attributes, so they're kept alongside
+		_descriptor: Box<SECURITY_DESCRIPTOR>,
+		_acl: Vec<u64>,
+	}
+
+	impl UserOnlySecurity {
+		fn new() -> std::io::Result<Self> {
+			let token_user = current_token_user()?;
+			let sid = unsafe { (*(token_user.as_ptr() as *const TOKEN_USER)).User.Sid };
+
+			// A DACL with a single entry giving our user full access, and nobody else anything
+			let acl_size = std::mem::size_of::<ACL>() + std::mem::size_of::<ACCESS_ALLOWED_ACE>() + unsafe { GetLengthSid(sid) } as usize;
+			let mut acl = vec![0u64; acl_size.div_ceil(8)];
+			let acl_ptr = acl.as_mut_ptr() as *mut ACL;
+			let mut descriptor: Box<SECURITY_DESCRIPTOR> = Box::new(unsafe { std::mem::zeroed() });
+			let descriptor_ptr = &mut *descriptor as *mut SECURITY_DESCRIPTOR as *mut c_void;
+			let ok = unsafe {
+				InitializeAcl(acl_ptr, (acl.len() * 8) as u32, ACL_REVISION) != 0
+					&& AddAccessAllowedAce(acl_ptr, ACL_REVISION, FILE_ALL_ACCESS, sid) != 0
+					&& InitializeSecurityDescriptor(descriptor_ptr, SECURITY_DESCRIPTOR_REVISION) != 0
+					&& SetSecurityDescriptorDacl(descriptor_ptr, 1, acl_ptr, 0) != 0
+			};
+			if !ok {
+				return Err(std::io::Error::last_os_error());
+			}
+
+			let attributes = SECURITY_ATTRIBUTES {
+				nLength: std::mem::size_of::<SECURITY_ATTRIBUTES>() as u32,
+				lpSecurityDescriptor: descriptor_ptr,
+				bInheritHandle: 0,
+			};
+			Ok(UserOnlySecurity { attributes, _descriptor: descriptor, _acl: acl })
+		}
+	}
+
+	// The TOKEN_USER for our process, in a buffer aligned for it. The SID it points to is inside the buffer
+	fn current_token_user() -> std::io::Result<Vec<u64>> {
+		let mut token = std::ptr::null_mut();
+		if unsafe { OpenProcessToken(GetCurrentProcess(), TOKEN_QUERY, &mut token) } == 0 {
+			return Err(std::io::Error::last_os_error());
+		}
+		// The first call fails but gives the size needed
+		let mut len = 0;
+		unsafe { GetTokenInformation(token, TokenUser, std::ptr::null_mut(), 0, &mut len) };
+		let mut buffer = vec![0u64; (len as usize).div_ceil(8)];
+		let ok = unsafe { GetTokenInformation(token, TokenUser, buffer.as_mut_ptr() as *mut c_void, len, &mut len) } != 0;
+		let error = std::io::Error::last_os_error();
+		unsafe { CloseHandle(token) };
+		if ok { Ok(buffer) } else { Err(error) }
+	}
+
+	/// Server end of a pipe instance. It's opened for overlapped I/O so reads can time out,
+	/// which synchronous pipe reads can't
+	pub struct Pipe {
+		handle: HANDLE,
+		// Signalled when an operation completes
+		event: HANDLE,
+	}
+
+	// The handles are only used by one thread at a time
+	unsafe impl Send for Pipe {}
+
+	impl Pipe {
+		fn create(name: &[u16], open_mode: u32, security: &SECURITY_ATTRIBUTES) -> std::io::Result<Self> {
+			let handle = unsafe {
+				CreateNamedPipeW(
+					name.as_ptr(),
+					open_mode,
+					PIPE_TYPE_BYTE | PIPE_READMODE_BYTE | PIPE_WAIT | PIPE_REJECT_REMOTE_CLIENTS,
+					PIPE_UNLIMITED_INSTANCES,
+					PIPE_BUFFER_SIZE,
+					PIPE_BUFFER_SIZE,
+					0,
+					security,
+				)
+			};
+			if handle == INVALID_HANDLE_VALUE {
+				return Err(std::io::Error::last_os_error());
+			}
+			let event = unsafe { CreateEventW(std::ptr::null(), 1, 0, std::ptr::null()) };
+			if event.is_null() {
+				let error = std::io::Error::last_os_error();
+				unsafe { CloseHandle(handle) };
+				return Err(error);
+			}
+			Ok(Pipe { handle, event })
+		}
+
+		/// Wait for a client to connect
+		fn connect(&self) -> std::io::Result<()> {
+			self.overlapped(None, |overlapped| unsafe { ConnectNamedPipe(self.handle, overlapped) }).map(|_| ())
+		}
+
+		// Start an operation and wait for it to finish, cancelling it if it takes longer than the timeout
+		fn overlapped<F: FnOnce(*mut OVERLAPPED) -> BOOL>(&self, timeout: Option<Duration>, start: F) -> std::io::Result<usize> {
+			let mut overlapped: OVERLAPPED = unsafe { std::mem::zeroed() };
+			overlapped.hEvent = self.event;
+			if start(&mut overlapped) == 0 {
+				match unsafe { GetLastError() } {
+					ERROR_IO_PENDING => {}
+					// A client connected between creating the pipe and waiting for one
+					ERROR_PIPE_CONNECTED => return Ok(0),
+					error => return Err(std::io::Error::from_raw_os_error(error as i32)),
+				}
+				let wait = timeout.map_or(INFINITE, |timeout| u32::try_from(timeout.as_millis()).unwrap_or(INFINITE));
+				if unsafe { WaitForSingleObject(self.event, wait) } == WAIT_TIMEOUT {
+					// The OVERLAPPED has to outlive the operation, so wait for the cancel to land
+					let mut transferred = 0;
+					unsafe {
+						CancelIoEx(self.handle, &overlapped);
+						GetOverlappedResult(self.handle, &overlapped, &mut transferred, 1);
+					}
+					return Err(std::io::Error::new(std::io::ErrorKind::TimedOut, "Timed out"));
+				}
+			}
+			let mut transferred = 0;
+			if unsafe { GetOverlappedResult(self.handle, &overlapped, &mut transferred, 1) } == 0 {
+				return Err(std::io::Error::last_os_error());
+			}
+			Ok(transferred as usize)
+		}
+	}
+
+	impl Read for Pipe {
+		fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+			let len = u32::try_from(buf.len()).unwrap_or(u32::MAX);
+			let result = self.overlapped(Some(CONNECTION_TIMEOUT), |overlapped| unsafe {
+				ReadFile(self.handle, buf.as_mut_ptr(), len, std::ptr::null_mut(), overlapped)
+			});
+			match result {
+				// The client closing its end is the end of the stream
+				Err(e) if e.raw_os_error() == Some(ERROR_BROKEN_PIPE as i32) => Ok(0),
+				result => result,
+			}
+		}
+	}
+
+	impl Write for Pipe {
+		fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+			let len = u32::try_from(buf.len()).unwrap_or(u32::MAX);
+			self.overlapped(Some(CONNECTION_TIMEOUT), |overlapped| unsafe {
+				WriteFile(self.handle, buf.as_ptr(), len, std::ptr::null_mut(), overlapped)
+			})
+		}
+
+		fn flush(&mut self) -> std::io::Result<()> {
+			Ok(())
+		}
+	}
+
+	impl Drop for Pipe {
+		fn drop(&mut self) {
+			unsafe {
+				CloseHandle(self.handle);
+				CloseHandle(self.event);
+			}
+		}
+	}
+
+	pub fn connect() -> Result<File, String> {
+		std::fs::OpenOptions::new()
+			.read(true)
+			.write(true)
+			.open(pipe_name())
+			.map_err(|e| format!("Failed to connect to running instance: {:?}", e))
+	}
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "windows")))]
+mod platform {
+	use std::io::Cursor;
+
+	/// No lock on other platforms, every launch runs its own tray icon
+	pub struct Listener;
+
+	impl Listener {
+		pub fn acquire() -> Result<Option<Self>, String> {
+			Ok(Some(Listener))
+		}
+
+		pub fn start<F: Fn(Cursor<Vec<u8>>) + Send + 'static>(self, _handler: F) -> Result<(), String> {
+			Ok(())
+		}
+	}
+
+	pub fn connect() -> Result<Cursor<Vec<u8>>, String> {
+		Err("Talking to the running instance isn't supported on this platform".to_string())
+	}
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+	use std::os::unix::net::UnixStream;
+	use super::*;
+
+	#[test]
+	fn answers_requests_from_our_own_user() {
+		let (client, server) = UnixStream::pair().unwrap();
+		assert!(platform::is_same_user(&server));
+
+		let thread = std::thread::spawn(move || {
+			handle_connection(server, &|request| match request {
+				ControlRequest::GetStatus => Ok(serde_json::json!("fine")),
+				_ => Err("unexpected".to_string()),
+			})
+		});
+		let mut client_writer = client.try_clone().unwrap();
+		writeln!(client_writer, "{}", ControlRequest::GetStatus.to_json()).unwrap();
+		writeln!(client_writer, "{{\"request\": \"bogus\"}}").unwrap();
+		let mut lines = BufReader::new(client).lines();
+		assert_eq!(control::parse_response(&lines.next().unwrap().unwrap()), Ok(serde_json::json!("fine")));
+		assert_eq!(control::parse_response(&lines.next().unwrap().unwrap()), Err("Unknown request bogus".to_string()));
+
+		client_writer.shutdown(std::net::Shutdown::Both).unwrap();
+		assert_eq!(thread.join().unwrap(), Ok(()));
+	}
+}
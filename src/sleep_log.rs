@@ -0,0 +1,183 @@
+use std::collections::VecDeque;
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use crate::battery_monitor::SleepDrain;
+use crate::config;
+use crate::debug_util::dmsg;
+
+const SLEEP_LOG_FILE_NAME: &str = "sleep-drain.log";
+const SLEEP_LOG_LENGTH: usize = 50;
+
+/// Rolling log of battery drain during sleep, kept on disk so it survives restarts and can be
+/// read by the report command
+pub struct SleepLog {
+	path: Option<PathBuf>,
+	entries: VecDeque<SleepDrain>,
+}
+
+impl SleepLog {
+	pub fn load() -> Self {
+		let path = config::app_dir().map(|dir| dir.join(SLEEP_LOG_FILE_NAME));
+		let entries = match path.as_ref().map(std::fs::read_to_string) {
+			Some(Ok(text)) => text.lines().filter_map(parse_entry).collect(),
+			_ => VecDeque::new(),
+		};
+		Self { path, entries }
+	}
+
+	pub fn entries(&self) -> impl DoubleEndedIterator<Item = &SleepDrain> {
+		self.entries.iter()
+	}
+
+	pub fn last(&self) -> Option<&SleepDrain> {
+		self.entries.back()
+	}
+
+	pub fn push(&mut self, sleep_drain: SleepDrain) {
+		self.entries.push_back(sleep_drain);
+		while self.entries.len() > SLEEP_LOG_LENGTH {
+			self.entries.pop_front();
+		}
+		if let Err(_e) = self.save() {
+			dmsg!("Failed to save sleep log: {}", _e);
+		}
+	}
+
+	fn save(&self) -> Result<(), String> {
+		let path = self.path.as_ref().ok_or_else(|| "Couldn't find a log directory".to_string())?;
+		if let Some(dir) = path.parent() {
+			std::fs::create_dir_all(dir).map_err(|e| format!("Failed to create {:?}: {:?}", dir, e))?;
+		}
+		let text: String = self.entries.iter().map(format_entry).collect();
+		std::fs::write(path, text).map_err(|e| format!("Failed to write {:?}: {:?}", path, e))
+	}
+}
+
+// Each line is "<start unix seconds> <duration seconds> <soc before> <soc after>"
+fn format_entry(sleep_drain: &SleepDrain) -> String {
+	let started = sleep_drain.started.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+	format!("{} {} {} {}\n", started, sleep_drain.duration.as_secs(), sleep_drain.soc_before, sleep_drain.soc_after)
+}
+
+fn parse_entry(line: &str) -> Option<SleepDrain> {
+	let mut fields = line.split_whitespace();
+	let started = UNIX_EPOCH + Duration::from_secs(fields.next()?.parse().ok()?);
+	let duration = Duration::from_secs(fields.next()?.parse().ok()?);
+	let soc_before = fields.next()?.parse().ok()?;
+	let soc_after = fields.next()?.parse().ok()?;
+	Some(SleepDrain { started, duration, soc_before, soc_after })
+}
+
+pub fn is_high_drain(sleep_drain: &SleepDrain, warning_percent_per_hour: f32) -> bool {
+	sleep_drain.drain_rate_percent_per_hour() > warning_percent_per_hour
+}
+
+/// One line summary, e.g. "Last sleep: -12% in 8h05m (1.5%/hour)"
+pub fn describe_sleep_drain(sleep_drain: &SleepDrain, warning_percent_per_hour: f32) -> String {
+	format!("Last sleep: {:+.0}% in {} ({:.1}%/hour){}",
+		-sleep_drain.drain_percent(),
+		format_duration(sleep_drain.duration),
+		sleep_drain.drain_rate_percent_per_hour(),
+		if is_high_drain(sleep_drain, warning_percent_per_hour) { " - high drain!" } else { "" },
+	)
+}
+
+/// Summary of the last sleep followed by a table of all recorded sleeps, newest first
+pub fn format_sleep_report(sleep_log: &SleepLog, warning_percent_per_hour: f32) -> String {
+	let Some(last) = sleep_log.last() else {
+		return "No sleeps recorded yet.\n".to_string();
+	};
+
+	let mut report = format!("{}\n\n", describe_sleep_drain(last, warning_percent_per_hour));
+	report += &format!("{:<20}  {:>8}  {:>6}  {:>6}  {:>8}\n", "Started", "Duration", "Before", "After", "%/hour");
+	for sleep_drain in sleep_log.entries().rev() {
+		report += &format!("{:<20}  {:>8}  {:>5.0}%  {:>5.0}%  {:>8.1}{}\n",
+			format_utc(sleep_drain.started),
+			format_duration(sleep_drain.duration),
+			sleep_drain.soc_before * 100.0,
+			sleep_drain.soc_after * 100.0,
+			sleep_drain.drain_rate_percent_per_hour(),
+			if is_high_drain(sleep_drain, warning_percent_per_hour) { "  !" } else { "" },
+		);
+	}
+	report
+}
+
+/// Hours and minutes, e.g. `8h05m`, or only minutes below an hour
+pub fn format_duration(duration: Duration) -> String {
+	let minutes = duration.as_secs() / 60;
+	if minutes < 60 {
+		format!("{}m", minutes)
+	}
+	else {
+		format!("{}h{:02}m", minutes / 60, minutes % 60)
+	}
+}
+
+/// Format as "YYYY-MM-DD HH:MM UTC" without pulling in a date library
+pub fn format_utc(time: SystemTime) -> String {
+	let secs = time.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs() as i64;
+	let days = secs.div_euclid(86400);
+	let day_secs = secs.rem_euclid(86400);
+
+	// Civil date from days since the epoch, see https://howardhinnant.github.io/date_algorithms.html
+	let z = days + 719468;
+	let era = z.div_euclid(146097);
+	let doe = z.rem_euclid(146097);
+	let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+	let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+	let mp = (5 * doy + 2) / 153;
+	let day = doy - (153 * mp + 2) / 5 + 1;
+	let month = if mp < 10 { mp + 3 } else { mp - 9 };
+	let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+	format!("{:04}-{:02}-{:02} {:02}:{:02} UTC", year, month, day, day_secs / 3600, (day_secs % 3600) / 60)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn sleep_drain(duration: Duration, soc_before: f32, soc_after: f32) -> SleepDrain {
+		SleepDrain { started: UNIX_EPOCH + Duration::from_secs(1_700_000_000), duration, soc_before, soc_after }
+	}
+
+	#[test]
+	fn entries_round_trip_through_the_log_format() {
+		let entry = sleep_drain(Duration::from_secs(8 * 3600 + 300), 0.9, 0.78);
+		let line = format_entry(&entry);
+		assert_eq!(line, "1700000000 29100 0.9 0.78\n");
+		assert_eq!(parse_entry(line.trim_end()), Some(entry));
+		assert_eq!(parse_entry("1700000000 29100 0.9"), None);
+		assert_eq!(parse_entry("not a number 0.9 0.78"), None);
+	}
+
+	#[test]
+	fn computes_the_drain_over_the_sleep() {
+		let entry = sleep_drain(Duration::from_secs(4 * 3600), 0.8, 0.7);
+		assert!((entry.drain_percent() - 10.0).abs() < 1e-4);
+		assert!((entry.drain_rate_percent_per_hour() - 2.5).abs() < 1e-4);
+		// Charged while asleep
+		assert!(sleep_drain(Duration::from_secs(3600), 0.5, 0.6).drain_percent() < 0.0);
+		// A zero length sleep doesn't divide by zero
+		assert_eq!(sleep_drain(Duration::ZERO, 0.8, 0.7).drain_rate_percent_per_hour(), 0.0);
+	}
+
+	#[test]
+	fn describes_the_drain_rate() {
+		let entry = sleep_drain(Duration::from_secs(8 * 3600 + 300), 0.9, 0.78);
+		assert_eq!(describe_sleep_drain(&entry, 2.0), "Last sleep: -12% in 8h05m (1.5%/hour)");
+		assert_eq!(describe_sleep_drain(&entry, 1.0), "Last sleep: -12% in 8h05m (1.5%/hour) - high drain!");
+		assert!(!is_high_drain(&entry, 2.0));
+	}
+
+	#[test]
+	fn formats_durations_and_dates() {
+		assert_eq!(format_duration(Duration::from_secs(59)), "0m");
+		assert_eq!(format_duration(Duration::from_secs(45 * 60)), "45m");
+		assert_eq!(format_duration(Duration::from_secs(2 * 3600 + 5 * 60)), "2h05m");
+		assert_eq!(format_utc(UNIX_EPOCH), "1970-01-01 00:00 UTC");
+		assert_eq!(format_utc(UNIX_EPOCH + Duration::from_secs(1_700_000_000)), "2023-11-14 22:13 UTC");
+		assert_eq!(format_utc(UNIX_EPOCH + Duration::from_secs(951_782_400)), "2000-02-29 00:00 UTC");
+	}
+}
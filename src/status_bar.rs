@@ -0,0 +1,182 @@
+use std::io::Write;
+use serde_json::json;
+use crate::battery_monitor::{BatteryMonitor, BatteryInfo};
+use crate::config::{Config, NamedSetting};
+use crate::icon_builder::{IconBuilder, IconOverlays};
+use crate::sample_scheduler::SampleScheduler;
+use crate::tray_menu;
+
+// Half of the icon filled red, about two hours left at the default overlay scale
+const HIGH_DRAIN_FRACTION: f32 = 0.5;
+
+/// Output formats for status bars, for desktops without a tray
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BarFormat {
+	/// i3bar protocol, also read by swaybar
+	I3bar,
+	/// JSON for a Waybar custom module with `return-type` set to json
+	Waybar,
+	/// Text with polybar colour tags, for a script module with `tail = true`
+	Polybar,
+}
+
+impl NamedSetting for BarFormat {
+	const NAMES: &'static [(Self, &'static str)] = &[
+		(BarFormat::I3bar, "i3bar"),
+		(BarFormat::Waybar, "waybar"),
+		(BarFormat::Polybar, "polybar"),
+	];
+}
+
+/// Print a status line every time the battery is sampled, until the bar stops reading them
+pub fn run(format: BarFormat) -> Result<(), String> {
+	let config = Config::load();
+	let mut battery_monitor = BatteryMonitor::new()?;
+	battery_monitor.set_low_battery_percent(config.low_battery_percent);
+	let mut icon_builder = IconBuilder::new().map_err(|e| format!("Failed to load icon images: {:?}", e))?;
+	icon_builder.set_theme(config.theme);
+	icon_builder.set_overlay_mode(config.overlay_mode);
+	icon_builder.set_power_draw_ceiling_watts(config.power_draw_ceiling_watts);
+	let mut scheduler = SampleScheduler::new(false);
+	scheduler.set_fixed_interval(config.update_interval_seconds.map(std::time::Duration::from_secs));
+
+	let mut stdout = std::io::stdout().lock();
+	let mut write = |text: &str| stdout.write_all(text.as_bytes()).and_then(|_| stdout.flush());
+
+	if format == BarFormat::I3bar {
+		// The header, then an endless array with one array of blocks per update
+		write("{\"version\":1}\n[\n").map_err(|e| format!("{:?}", e))?;
+	}
+	let mut first = true;
+	loop {
+		let battery_info = battery_monitor.get_battery_info().ok();
+		let mut line = format_line(format, battery_info.as_ref(), &icon_builder, config.low_battery_percent);
+		if format == BarFormat::I3bar && !first {
+			line.insert(0, ',');
+		}
+		first = false;
+
+		// Stop quietly once the bar closes its end of the pipe
+		match write(&(line + "\n")) {
+			Err(e) if e.kind() == std::io::ErrorKind::BrokenPipe => return Ok(()),
+			Err(e) => return Err(format!("Failed to write status: {:?}", e)),
+			Ok(()) => {}
+		}
+		std::thread::sleep(scheduler.decide(battery_info.as_ref()).interval);
+	}
+}
+
+/// One update for the bar, or a placeholder when the battery couldn't be read
+pub fn format_line(format: BarFormat, battery_info: Option<&BatteryInfo>, icon_builder: &IconBuilder, low_battery_percent: i32) -> String {
+	let Some(info) = battery_info else {
+		return match format {
+			BarFormat::I3bar => json!([{ "name": "battery", "full_text": "--" }]).to_string(),
+			BarFormat::Waybar => json!({ "text": "--", "tooltip": "No battery", "class": ["unknown"] }).to_string(),
+			BarFormat::Polybar => "--".to_string(),
+		};
+	};
+
+	let overlays = icon_builder.overlays(info.discharge_rate_percent, info.power_draw_watts, info.is_charging);
+	let color = icon_builder.text_color(overlays);
+	let color = format!("#{:02x}{:02x}{:02x}", color[0], color[1], color[2]);
+	let text = format!("{}%", info.percentage);
+	let classes = classes(info, overlays, low_battery_percent);
+
+	match format {
+		BarFormat::I3bar => json!([{
+			"name": "battery",
+			"full_text": text,
+			"color": color,
+			"urgent": classes.contains(&"low"),
+		}]).to_string(),
+		BarFormat::Waybar => json!({
+			"text": text,
+			"tooltip": tray_menu::format_status(info),
+			"class": classes,
+			"percentage": info.percentage.clamp(0, 100),
+		}).to_string(),
+		BarFormat::Polybar => format!("%{{F{}}}{}%{{F-}}", color, text),
+	}
+}
+
+/// Style classes for a battery state, matching what the tray icon shows
+pub fn classes(info: &BatteryInfo, overlays: IconOverlays, low_battery_percent: i32) -> Vec<&'static str> {
+	let mut classes = vec![match (overlays.charging, overlays.weak_charger) {
+		(true, true) => "weak-charger",
+		(true, false) if info.percentage >= 100 => "full",
+		(true, false) => "charging",
+		(false, _) => "discharging",
+	}];
+	if !info.is_charging && info.percentage < low_battery_percent {
+		classes.push("low");
+	}
+	if overlays.drain_fraction >= HIGH_DRAIN_FRACTION {
+		classes.push("high-drain");
+	}
+	classes
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use serde_json::Value;
+	use starship_battery::State;
+
+	fn battery(percentage: i32, discharge_rate_percent: i32, is_charging: bool) -> BatteryInfo {
+		BatteryInfo {
+			percentage,
+			discharge_rate_percent,
+			power_draw_watts: 8.0,
+			instant_power_draw_watts: 8.0,
+			is_charging,
+			state: if is_charging { State::Charging } else { State::Discharging },
+			health_percent: 100,
+			cycle_count: None,
+		}
+	}
+
+	fn classes_for(info: &BatteryInfo, icon_builder: &IconBuilder) -> Vec<&'static str> {
+		let overlays = icon_builder.overlays(info.discharge_rate_percent, info.power_draw_watts, info.is_charging);
+		classes(info, overlays, 15)
+	}
+
+	#[test]
+	fn classes_follow_the_battery_state() {
+		let icon_builder = IconBuilder::new().unwrap();
+		assert_eq!(classes_for(&battery(50, 10, false), &icon_builder), ["discharging"]);
+		assert_eq!(classes_for(&battery(10, 60, false), &icon_builder), ["discharging", "low", "high-drain"]);
+		assert_eq!(classes_for(&battery(60, 0, true), &icon_builder), ["charging"]);
+		assert_eq!(classes_for(&battery(100, 0, true), &icon_builder), ["full"]);
+		// Low doesn't apply while plugged in, even to a weak charger
+		assert_eq!(classes_for(&battery(10, 5, true), &icon_builder), ["weak-charger"]);
+	}
+
+	#[test]
+	fn formats_a_line_for_each_bar() {
+		let icon_builder = IconBuilder::new().unwrap();
+		let low = battery(10, 0, false);
+
+		let i3bar: Value = serde_json::from_str(&format_line(BarFormat::I3bar, Some(&low), &icon_builder, 15)).unwrap();
+		assert_eq!(i3bar, json!([{ "name": "battery", "full_text": "10%", "color": "#ffffff", "urgent": true }]));
+
+		let waybar: Value = serde_json::from_str(&format_line(BarFormat::Waybar, Some(&low), &icon_builder, 15)).unwrap();
+		assert_eq!(waybar, json!({
+			"text": "10%",
+			"tooltip": "10% - discharging 0%/hour, 8.0W",
+			"class": ["discharging", "low"],
+			"percentage": 10,
+		}));
+
+		assert_eq!(format_line(BarFormat::Polybar, Some(&battery(60, 0, true)), &icon_builder, 15), "%{F#40ff40}60%%{F-}");
+	}
+
+	#[test]
+	fn formats_a_placeholder_without_a_battery() {
+		let icon_builder = IconBuilder::new().unwrap();
+		let i3bar: Value = serde_json::from_str(&format_line(BarFormat::I3bar, None, &icon_builder, 15)).unwrap();
+		assert_eq!(i3bar, json!([{ "name": "battery", "full_text": "--" }]));
+		let waybar: Value = serde_json::from_str(&format_line(BarFormat::Waybar, None, &icon_builder, 15)).unwrap();
+		assert_eq!(waybar["class"], json!(["unknown"]));
+		assert_eq!(format_line(BarFormat::Polybar, None, &icon_builder, 15), "--");
+	}
+}
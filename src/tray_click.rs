@@ -0,0 +1,193 @@
+use std::time::{Duration, Instant};
+use tray_icon::{MouseButton, MouseButtonState, TrayIconEvent};
+use winit::dpi::PhysicalPosition;
+use crate::config::NamedSetting;
+
+// Windows' default double-click time
+const DOUBLE_CLICK_TIME: Duration = Duration::from_millis(500);
+
+/// What a click on the tray icon does
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClickAction {
+	None,
+	ShowHistory,
+	CycleLayout,
+	PowerSettings,
+	/// Switch the icon between the percentage and the time remaining
+	ToggleTimeRemaining,
+	/// Sample the battery now
+	Refresh,
+}
+
+impl NamedSetting for ClickAction {
+	const NAMES: &'static [(Self, &'static str)] = &[
+		(ClickAction::None, "none"),
+		(ClickAction::ShowHistory, "show_history"),
+		(ClickAction::CycleLayout, "cycle_layout"),
+		(ClickAction::PowerSettings, "power_settings"),
+		(ClickAction::ToggleTimeRemaining, "toggle_time_remaining"),
+		(ClickAction::Refresh, "refresh"),
+	];
+}
+
+/// The kinds of click that can be given an action. Right click always opens the menu
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TrayClick {
+	Left,
+	Double,
+	Middle,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Click {
+	pub kind: TrayClick,
+	pub position: PhysicalPosition<f64>,
+}
+
+/// Turns tray icon events into clicks. While double clicks have an action, a single click is
+/// held back until it's too late to become a double click, so a double click doesn't act as two
+/// single clicks as well
+pub struct ClickTracker {
+	wait_for_double_click: bool,
+	pending: Option<(Instant, Click)>,
+	// Windows sends a release after the double click event, which isn't a click of its own
+	ignore_next_release: bool,
+}
+
+impl ClickTracker {
+	pub fn new() -> Self {
+		Self { wait_for_double_click: false, pending: None, ignore_next_release: false }
+	}
+
+	pub fn set_wait_for_double_click(&mut self, wait_for_double_click: bool) {
+		self.wait_for_double_click = wait_for_double_click;
+	}
+
+	/// The click an event completes, if any
+	pub fn handle_event(&mut self, event: &TrayIconEvent, now: Instant) -> Option<Click> {
+		match event {
+			TrayIconEvent::Click { button: MouseButton::Left, button_state: MouseButtonState::Up, position, .. } => {
+				let click = Click { kind: TrayClick::Left, position: PhysicalPosition::new(position.x, position.y) };
+				if std::mem::take(&mut self.ignore_next_release) {
+					None
+				}
+				else if self.wait_for_double_click {
+					self.pending = Some((now + DOUBLE_CLICK_TIME, click));
+					None
+				}
+				else {
+					Some(click)
+				}
+			}
+			TrayIconEvent::DoubleClick { button: MouseButton::Left, position, .. } => {
+				self.pending = None;
+				self.ignore_next_release = self.wait_for_double_click;
+				Some(Click { kind: TrayClick::Double, position: PhysicalPosition::new(position.x, position.y) })
+			}
+			TrayIconEvent::Click { button: MouseButton::Middle, button_state: MouseButtonState::Up, position, .. } => {
+				Some(Click { kind: TrayClick::Middle, position: PhysicalPosition::new(position.x, position.y) })
+			}
+			_ => None,
+		}
+	}
+
+	/// A held back single click, once no double click has come in time
+	pub fn take_due(&mut self, now: Instant) -> Option<Click> {
+		match self.pending {
+			Some((due, click)) if now >= due => {
+				self.pending = None;
+				Some(click)
+			}
+			_ => None,
+		}
+	}
+
+	/// When the held back click is due, if there is one
+	pub fn deadline(&self) -> Option<Instant> {
+		self.pending.map(|(due, _)| due)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use tray_icon::dpi::{PhysicalPosition as TrayPosition, PhysicalSize};
+	use tray_icon::{Rect, TrayIconId};
+
+	const POSITION: TrayPosition<f64> = TrayPosition::new(10.0, 20.0);
+
+	fn rect() -> Rect {
+		Rect { size: PhysicalSize::new(16, 16), position: TrayPosition::new(0.0, 0.0) }
+	}
+
+	fn release(button: MouseButton) -> TrayIconEvent {
+		TrayIconEvent::Click { id: TrayIconId::new("tray"), position: POSITION, rect: rect(), button, button_state: MouseButtonState::Up }
+	}
+
+	fn double_click() -> TrayIconEvent {
+		TrayIconEvent::DoubleClick { id: TrayIconId::new("tray"), position: POSITION, rect: rect(), button: MouseButton::Left }
+	}
+
+	fn kind(click: Option<Click>) -> Option<TrayClick> {
+		click.map(|click| click.kind)
+	}
+
+	#[test]
+	fn single_click_is_immediate_without_double_click_action() {
+		let now = Instant::now();
+		let mut tracker = ClickTracker::new();
+		tracker.set_wait_for_double_click(false);
+		let click = tracker.handle_event(&release(MouseButton::Left), now).unwrap();
+		assert_eq!(click.kind, TrayClick::Left);
+		assert_eq!(click.position, PhysicalPosition::new(10.0, 20.0));
+		assert_eq!(tracker.deadline(), None);
+
+		// The second release of a double click is a click of its own
+		assert_eq!(kind(tracker.handle_event(&double_click(), now)), Some(TrayClick::Double));
+		assert_eq!(kind(tracker.handle_event(&release(MouseButton::Left), now)), Some(TrayClick::Left));
+		assert_eq!(kind(tracker.handle_event(&release(MouseButton::Middle), now)), Some(TrayClick::Middle));
+		assert_eq!(kind(tracker.handle_event(&release(MouseButton::Right), now)), None);
+	}
+
+	#[test]
+	fn single_click_waits_out_the_double_click_time() {
+		let start = Instant::now();
+		let mut tracker = ClickTracker::new();
+		tracker.set_wait_for_double_click(true);
+		assert_eq!(kind(tracker.handle_event(&release(MouseButton::Left), start)), None);
+		assert_eq!(tracker.deadline(), Some(start + DOUBLE_CLICK_TIME));
+
+		assert_eq!(kind(tracker.take_due(start + DOUBLE_CLICK_TIME - Duration::from_millis(1))), None);
+		assert_eq!(kind(tracker.take_due(start + DOUBLE_CLICK_TIME)), Some(TrayClick::Left));
+		assert_eq!(tracker.deadline(), None);
+		assert_eq!(kind(tracker.take_due(start + DOUBLE_CLICK_TIME * 2)), None);
+	}
+
+	#[test]
+	fn double_click_inside_the_window_replaces_the_single_click() {
+		let start = Instant::now();
+		let mut tracker = ClickTracker::new();
+		tracker.set_wait_for_double_click(true);
+		tracker.handle_event(&release(MouseButton::Left), start);
+		let later = start + DOUBLE_CLICK_TIME / 2;
+		assert_eq!(kind(tracker.handle_event(&double_click(), later)), Some(TrayClick::Double));
+		assert_eq!(tracker.deadline(), None);
+
+		// The release that follows the double click is part of it
+		assert_eq!(kind(tracker.handle_event(&release(MouseButton::Left), later)), None);
+		assert_eq!(kind(tracker.take_due(start + DOUBLE_CLICK_TIME * 2)), None);
+	}
+
+	#[test]
+	fn clicks_outside_the_window_are_separate() {
+		let start = Instant::now();
+		let mut tracker = ClickTracker::new();
+		tracker.set_wait_for_double_click(true);
+		tracker.handle_event(&release(MouseButton::Left), start);
+		let later = start + DOUBLE_CLICK_TIME * 2;
+		assert_eq!(kind(tracker.take_due(later)), Some(TrayClick::Left));
+		tracker.handle_event(&release(MouseButton::Left), later);
+		assert_eq!(tracker.deadline(), Some(later + DOUBLE_CLICK_TIME));
+		assert_eq!(kind(tracker.take_due(later + DOUBLE_CLICK_TIME)), Some(TrayClick::Left));
+	}
+}
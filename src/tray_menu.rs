@@ -0,0 +1,282 @@
+use tray_icon::menu::{CheckMenuItem, ContextMenu, IsMenuItem, Menu, MenuItem, PredefinedMenuItem, Submenu};
+
+use crate::autostart;
+use crate::battery_monitor::BatteryInfo;
+use crate::config::{Config, NamedSetting};
+use crate::icon_builder::{IconDisplay, IconLayout, OutlineStyle, OverlayMode, Theme};
+
+const UPDATE_INTERVAL_CHOICES: [Option<u64>; 5] = [None, Some(5), Some(10), Some(30), Some(60)];
+
+/// Everything that can be picked from the tray menu. Menu item ids are generated from and
+/// parsed back into these, so the id is all that's needed to dispatch a menu event
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MenuAction {
+	SetTheme(Theme),
+	SetLayout(IconLayout),
+	SetDisplay(IconDisplay),
+	SetOutline(OutlineStyle),
+	SetOverlayMode(OverlayMode),
+	SetUpdateInterval(Option<u64>),
+	ToggleNotifications,
+	ToggleAnimations,
+	ToggleAutostart,
+	OpenLogFolder,
+	BatteryReport,
+	Quit,
+}
+
+impl MenuAction {
+	pub fn id(&self) -> String {
+		match self {
+			MenuAction::SetTheme(theme) => format!("theme:{}", theme.name()),
+			MenuAction::SetLayout(layout) => format!("layout:{}", layout.name()),
+			MenuAction::SetDisplay(display) => format!("display:{}", display.name()),
+			MenuAction::SetOutline(style) => format!("outline:{}", style.name()),
+			MenuAction::SetOverlayMode(mode) => format!("overlay:{}", mode.name()),
+			MenuAction::SetUpdateInterval(None) => "interval:auto".to_string(),
+			MenuAction::SetUpdateInterval(Some(seconds)) => format!("interval:{}", seconds),
+			MenuAction::ToggleNotifications => "notifications".to_string(),
+			MenuAction::ToggleAnimations => "animations".to_string(),
+			MenuAction::ToggleAutostart => "autostart".to_string(),
+			MenuAction::OpenLogFolder => "open_log_folder".to_string(),
+			MenuAction::BatteryReport => "battery_report".to_string(),
+			MenuAction::Quit => "quit".to_string(),
+		}
+	}
+
+	pub fn from_id(id: &str) -> Option<Self> {
+		match id.split_once(':') {
+			Some(("theme", name)) => Theme::from_name(name).map(MenuAction::SetTheme),
+			Some(("layout", name)) => IconLayout::from_name(name).map(MenuAction::SetLayout),
+			Some(("display", name)) => IconDisplay::from_name(name).map(MenuAction::SetDisplay),
+			Some(("outline", name)) => OutlineStyle::from_name(name).map(MenuAction::SetOutline),
+			Some(("overlay", name)) => OverlayMode::from_name(name).map(MenuAction::SetOverlayMode),
+			Some(("interval", "auto")) => Some(MenuAction::SetUpdateInterval(None)),
+			Some(("interval", seconds)) => seconds.parse().ok().map(|s| MenuAction::SetUpdateInterval(Some(s))),
+			Some(_) => None,
+			None => match id {
+				"notifications" => Some(MenuAction::ToggleNotifications),
+				"animations" => Some(MenuAction::ToggleAnimations),
+				"autostart" => Some(MenuAction::ToggleAutostart),
+				"open_log_folder" => Some(MenuAction::OpenLogFolder),
+				"battery_report" => Some(MenuAction::BatteryReport),
+				"quit" => Some(MenuAction::Quit),
+				_ => None,
+			},
+		}
+	}
+
+	/// Update the config for actions that change a setting. Returns false for other actions
+	pub fn apply(&self, config: &mut Config) -> bool {
+		match *self {
+			MenuAction::SetTheme(theme) => config.theme = theme,
+			MenuAction::SetLayout(layout) => config.layout = layout,
+			MenuAction::SetDisplay(display) => config.display = display,
+			MenuAction::SetOutline(style) => config.outline_style = style,
+			MenuAction::SetOverlayMode(mode) => config.overlay_mode = mode,
+			MenuAction::SetUpdateInterval(seconds) => config.update_interval_seconds = seconds,
+			MenuAction::ToggleNotifications => config.notifications = !config.notifications,
+			MenuAction::ToggleAnimations => config.animations = !config.animations,
+			MenuAction::ToggleAutostart | MenuAction::OpenLogFolder | MenuAction::BatteryReport | MenuAction::Quit => return false,
+		}
+		true
+	}
+
+	/// Whether the menu item for this action should show a check mark. Start at login is
+	/// registered with the OS rather than kept in the config, so it's checked separately
+	pub fn is_checked(&self, config: &Config) -> bool {
+		match *self {
+			MenuAction::SetTheme(theme) => config.theme == theme,
+			MenuAction::SetLayout(layout) => config.layout == layout,
+			MenuAction::SetDisplay(display) => config.display == display,
+			MenuAction::SetOutline(style) => config.outline_style == style,
+			MenuAction::SetOverlayMode(mode) => config.overlay_mode == mode,
+			MenuAction::SetUpdateInterval(seconds) => config.update_interval_seconds == seconds,
+			MenuAction::ToggleNotifications => config.notifications,
+			MenuAction::ToggleAnimations => config.animations,
+			MenuAction::ToggleAutostart | MenuAction::OpenLogFolder | MenuAction::BatteryReport | MenuAction::Quit => false,
+		}
+	}
+}
+
+/// One line summary of the battery for the menu header
+pub fn format_status(battery_info: &BatteryInfo) -> String {
+	let state = if !battery_info.is_charging {
+		format!("discharging {}%/hour", battery_info.discharge_rate_percent)
+	}
+	else if battery_info.discharge_rate_percent > 0 {
+		format!("plugged in, still discharging {}%/hour", battery_info.discharge_rate_percent)
+	}
+	else {
+		"charging".to_string()
+	};
+	format!("{}% - {}, {:.1}W", battery_info.percentage, state, battery_info.power_draw_watts.abs())
+}
+
+pub struct TrayMenu {
+	menu: Menu,
+	status_item: MenuItem,
+	sleep_drain_item: MenuItem,
+	check_items: Vec<(MenuAction, CheckMenuItem)>,
+	autostart_item: CheckMenuItem,
+}
+
+impl TrayMenu {
+	pub fn new(config: &Config) -> Result<Self, String> {
+		let mut check_items = Vec::new();
+		let mut check_item = |action: MenuAction, text: &str| {
+			let item = CheckMenuItem::with_id(action.id(), text, true, action.is_checked(config), None);
+			check_items.push((action, item.clone()));
+			item
+		};
+
+		let theme_items = [
+			check_item(MenuAction::SetTheme(Theme::LightDigits), "Light digits (dark taskbar)"),
+			check_item(MenuAction::SetTheme(Theme::DarkDigits), "Dark digits (light taskbar)"),
+		];
+		let layout_items = [
+			check_item(MenuAction::SetLayout(IconLayout::Stretched), "Stretched"),
+			check_item(MenuAction::SetLayout(IconLayout::Proportional), "Proportional"),
+		];
+		let display_items = [
+			check_item(MenuAction::SetDisplay(IconDisplay::Percentage), "Percentage"),
+			check_item(MenuAction::SetDisplay(IconDisplay::TimeRemaining), "Time remaining"),
+			check_item(MenuAction::SetDisplay(IconDisplay::Watts), "Power draw"),
+		];
+		let outline_items = [
+			check_item(MenuAction::SetOutline(OutlineStyle::Off), "Off"),
+			check_item(MenuAction::SetOutline(OutlineStyle::Outline), "Outline"),
+			check_item(MenuAction::SetOutline(OutlineStyle::Shadow), "Shadow"),
+		];
+		let interval_items = UPDATE_INTERVAL_CHOICES.map(|seconds| {
+			let text = match seconds {
+				None => "Automatic".to_string(),
+				Some(seconds) => format!("Every {} seconds", seconds),
+			};
+			check_item(MenuAction::SetUpdateInterval(seconds), &text)
+		});
+		let overlay_items = [
+			check_item(MenuAction::SetOverlayMode(OverlayMode::DischargeRate), "Discharge rate (%/hour)"),
+			check_item(MenuAction::SetOverlayMode(OverlayMode::PowerDraw), "Power draw (watts)"),
+		];
+		let notifications_item = check_item(MenuAction::ToggleNotifications, "Notifications");
+		let animations_item = check_item(MenuAction::ToggleAnimations, "Animations");
+		let autostart_item = CheckMenuItem::with_id(MenuAction::ToggleAutostart.id(), "Start at login", true, false, None);
+
+		let menu = Menu::new();
+		let status_item = MenuItem::with_id("status", "Reading battery...", false, None);
+		let sleep_drain_item = MenuItem::with_id("sleep_drain", "", false, None);
+		let open_log_folder_item = MenuItem::with_id(MenuAction::OpenLogFolder.id(), "Open log folder", true, None);
+		let battery_report_item = MenuItem::with_id(MenuAction::BatteryReport.id(), "Battery report", true, None);
+		let quit_item = MenuItem::with_id(MenuAction::Quit.id(), "Quit", true, None);
+
+		menu.append_items(&[
+			&status_item,
+			&sleep_drain_item,
+			&PredefinedMenuItem::separator(),
+			&submenu("Theme", &theme_items)?,
+			&submenu("Layout", &layout_items)?,
+			&submenu("Display", &display_items)?,
+			&submenu("Outline", &outline_items)?,
+			&submenu("Update interval", &interval_items)?,
+			&submenu("Overlay", &overlay_items)?,
+			&notifications_item,
+			&animations_item,
+			&autostart_item,
+			&PredefinedMenuItem::separator(),
+			&open_log_folder_item,
+			&battery_report_item,
+			&PredefinedMenuItem::separator(),
+			&quit_item,
+		]).map_err(|e| format!("Failed to add menu item: {:?}", e))?;
+
+		let tray_menu = Self { menu, status_item, sleep_drain_item, check_items, autostart_item };
+		tray_menu.sync_autostart();
+		Ok(tray_menu)
+	}
+
+	pub fn context_menu(&self) -> Box<dyn ContextMenu> {
+		Box::new(self.menu.clone())
+	}
+
+	pub fn set_status(&self, text: &str) {
+		self.status_item.set_text(text);
+	}
+
+	pub fn set_sleep_drain(&self, text: &str) {
+		self.sleep_drain_item.set_text(text);
+	}
+
+	/// Update check marks to match the config. Menus toggle check items themselves when
+	/// clicked, so this also puts back marks on choices that were clicked while already selected
+	pub fn sync_config(&self, config: &Config) {
+		for (action, item) in &self.check_items {
+			item.set_checked(action.is_checked(config));
+		}
+	}
+
+	/// Update the start at login check mark from what's registered with the OS
+	pub fn sync_autostart(&self) {
+		let enabled = autostart::platform_autostart().and_then(|a| a.is_enabled()).unwrap_or(false);
+		self.autostart_item.set_checked(enabled);
+	}
+}
+
+fn submenu(text: &str, items: &[CheckMenuItem]) -> Result<Submenu, String> {
+	let submenu = Submenu::new(text, true);
+	let items: Vec<&dyn IsMenuItem> = items.iter().map(|item| item as &dyn IsMenuItem).collect();
+	submenu.append_items(&items).map_err(|e| format!("Failed to add menu item: {:?}", e))?;
+	Ok(submenu)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn names<T: NamedSetting>() -> impl Iterator<Item = T> {
+		T::NAMES.iter().map(|(value, _)| *value)
+	}
+
+	fn all_actions() -> Vec<MenuAction> {
+		let mut actions: Vec<MenuAction> = Vec::new();
+		actions.extend(names().map(MenuAction::SetTheme));
+		actions.extend(names().map(MenuAction::SetLayout));
+		actions.extend(names().map(MenuAction::SetDisplay));
+		actions.extend(names().map(MenuAction::SetOutline));
+		actions.extend(names().map(MenuAction::SetOverlayMode));
+		actions.extend(UPDATE_INTERVAL_CHOICES.map(MenuAction::SetUpdateInterval));
+		actions.extend([
+			MenuAction::ToggleNotifications,
+			MenuAction::ToggleAnimations,
+			MenuAction::ToggleAutostart,
+			MenuAction::OpenLogFolder,
+			MenuAction::BatteryReport,
+			MenuAction::Quit,
+		]);
+		actions
+	}
+
+	#[test]
+	fn ids_round_trip() {
+		for action in all_actions() {
+			assert_eq!(MenuAction::from_id(&action.id()), Some(action), "{}", action.id());
+		}
+	}
+
+	#[test]
+	fn ids_are_unique() {
+		let ids: Vec<String> = all_actions().iter().map(MenuAction::id).collect();
+		for (i, id) in ids.iter().enumerate() {
+			assert!(!ids[i + 1..].contains(id), "{} is used twice", id);
+		}
+	}
+
+	#[test]
+	fn unknown_ids_are_ignored() {
+		assert_eq!(MenuAction::from_id("bogus"), None);
+		assert_eq!(MenuAction::from_id(""), None);
+		assert_eq!(MenuAction::from_id("interval:abc"), None);
+		assert_eq!(MenuAction::from_id("theme:bogus"), None);
+		assert_eq!(MenuAction::from_id("bogus:light_digits"), None);
+	}
+}
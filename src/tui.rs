@@ -0,0 +1,479 @@
+use std::io::Write;
+use std::time::{Duration, Instant, SystemTime};
+use crossterm::{cursor, event, execute, queue, style, terminal};
+use crossterm::event::{Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers};
+use starship_battery::State;
+use crate::battery_history::BatteryHistory;
+use crate::battery_monitor::{BatteryDetails, BatteryInfo, BatteryMonitor};
+use crate::config::Config;
+use crate::sample_scheduler::SampleScheduler;
+use crate::sleep_log;
+
+const SPARKLINE_LEVELS: [char; 8] = ['▁', '▂', '▃', '▄', '▅', '▆', '▇', '█'];
+
+// Time spans the history graphs can show, switched with + and -
+const GRAPH_RANGES: [(Duration, &str); 4] = [
+	(Duration::from_secs(60 * 60), "1 hour"),
+	(Duration::from_secs(3 * 60 * 60), "3 hours"),
+	(Duration::from_secs(6 * 60 * 60), "6 hours"),
+	(Duration::from_secs(24 * 60 * 60), "24 hours"),
+];
+
+const DEMO_SAMPLE_INTERVAL: Duration = Duration::from_millis(500);
+
+// Width of the labels in front of values and graphs
+const LABEL_WIDTH: usize = 15;
+
+/// Where the dashboard gets its readings from
+pub trait BatterySource {
+	/// The combined battery state and the time it was read
+	fn sample(&mut self) -> Result<(SystemTime, BatteryInfo), String>;
+	fn details(&mut self) -> Result<Vec<BatteryDetails>, String>;
+}
+
+impl BatterySource for BatteryMonitor {
+	fn sample(&mut self) -> Result<(SystemTime, BatteryInfo), String> {
+		Ok((SystemTime::now(), self.get_battery_info()?))
+	}
+
+	fn details(&mut self) -> Result<Vec<BatteryDetails>, String> {
+		self.battery_details()
+	}
+}
+
+/// A battery that runs down and charges back up on a fast clock, moving five minutes for every
+/// sample, for trying out the dashboard
+pub struct SimulatedBattery {
+	time: SystemTime,
+	soc: f32,
+	discharge_rate_percent: i32,
+}
+
+impl SimulatedBattery {
+	pub fn new(start: SystemTime) -> Self {
+		Self { time: start, soc: 0.8, discharge_rate_percent: 15 }
+	}
+
+	fn info(&self) -> BatteryInfo {
+		let is_charging = self.discharge_rate_percent <= 0;
+		let percentage = (self.soc * 100.0).round() as i32;
+		let power_draw_watts = self.discharge_rate_percent as f32 * 0.5;
+		BatteryInfo {
+			percentage,
+			discharge_rate_percent: self.discharge_rate_percent,
+			power_draw_watts,
+			instant_power_draw_watts: power_draw_watts,
+			is_charging,
+			state: match (is_charging, percentage) {
+				(true, 100) => State::Full,
+				(true, _) => State::Charging,
+				(false, _) => State::Discharging,
+			},
+			health_percent: 92,
+			cycle_count: Some(214),
+		}
+	}
+}
+
+impl BatterySource for SimulatedBattery {
+	fn sample(&mut self) -> Result<(SystemTime, BatteryInfo), String> {
+		let step = Duration::from_secs(5 * 60);
+		self.time += step;
+		self.soc = (self.soc - self.discharge_rate_percent as f32 / 100.0 * step.as_secs_f32() / 3600.0).clamp(0.0, 1.0);
+
+		// Plug in when low, and unplug once it has sat full for a while
+		if self.soc <= 0.1 {
+			self.discharge_rate_percent = -40;
+		}
+		else if self.soc >= 1.0 && self.discharge_rate_percent < 0 {
+			self.discharge_rate_percent = 0;
+		}
+		else if self.discharge_rate_percent == 0 {
+			self.discharge_rate_percent = 15;
+		}
+		Ok((self.time, self.info()))
+	}
+
+	fn details(&mut self) -> Result<Vec<BatteryDetails>, String> {
+		let info = self.info();
+		Ok(vec![BatteryDetails {
+			vendor: Some("Simulated".to_string()),
+			model: Some("Demo battery".to_string()),
+			technology: "lithium-ion".to_string(),
+			state: info.state,
+			percentage: info.percentage,
+			energy_wh: self.soc * 50.0,
+			energy_full_wh: 50.0,
+			energy_full_design_wh: 54.3,
+			energy_rate_watts: info.power_draw_watts.abs(),
+			voltage: 11.4 + self.soc,
+			temperature_celsius: Some(31.5),
+			health_percent: info.health_percent,
+			cycle_count: info.cycle_count,
+		}])
+	}
+}
+
+/// What a key press asks the dashboard loop to do
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyAction {
+	Quit,
+	/// Sample now instead of waiting for the next scheduled sample
+	Refresh,
+	/// Only the view changed
+	Redraw,
+	None,
+}
+
+/// The dashboard's state, kept apart from the terminal so it can be driven by any BatterySource
+pub struct Dashboard {
+	history: BatteryHistory,
+	latest: Option<(SystemTime, BatteryInfo)>,
+	details: Vec<BatteryDetails>,
+	error: Option<String>,
+	selected_battery: usize,
+	graph_range: usize,
+	paused: bool,
+}
+
+impl Dashboard {
+	pub fn new() -> Self {
+		Self {
+			history: BatteryHistory::new(),
+			latest: None,
+			details: Vec::new(),
+			error: None,
+			selected_battery: 0,
+			graph_range: 0,
+			paused: false,
+		}
+	}
+
+	pub fn is_paused(&self) -> bool {
+		self.paused
+	}
+
+	pub fn battery_info(&self) -> Option<&BatteryInfo> {
+		self.latest.as_ref().map(|(_, info)| info)
+	}
+
+	/// Take a new sample, keeping the previous readings if it fails
+	pub fn update(&mut self, source: &mut dyn BatterySource) {
+		let result = source.sample().and_then(|(time, info)| {
+			self.history.push(time, info.clone());
+			self.latest = Some((time, info));
+			source.details()
+		});
+		match result {
+			Ok(details) => {
+				self.selected_battery = self.selected_battery.min(details.len().saturating_sub(1));
+				self.details = details;
+				self.error = None;
+			}
+			Err(e) => self.error = Some(e),
+		}
+	}
+
+	pub fn handle_key(&mut self, key: KeyEvent) -> KeyAction {
+		match key.code {
+			KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::CONTROL) => KeyAction::Quit,
+			KeyCode::Char('q') | KeyCode::Esc => KeyAction::Quit,
+			KeyCode::Char('r') => KeyAction::Refresh,
+			KeyCode::Char('p') | KeyCode::Char(' ') => {
+				self.paused = !self.paused;
+				KeyAction::Redraw
+			}
+			KeyCode::Char('+') | KeyCode::Char('=') => {
+				self.graph_range = (self.graph_range + 1).min(GRAPH_RANGES.len() - 1);
+				KeyAction::Redraw
+			}
+			KeyCode::Char('-') => {
+				self.graph_range = self.graph_range.saturating_sub(1);
+				KeyAction::Redraw
+			}
+			KeyCode::Right | KeyCode::Tab | KeyCode::Char('n') => {
+				self.selected_battery = (self.selected_battery + 1) % self.details.len().max(1);
+				KeyAction::Redraw
+			}
+			KeyCode::Left | KeyCode::BackTab => {
+				self.selected_battery = self.selected_battery.checked_sub(1).unwrap_or(self.details.len().saturating_sub(1));
+				KeyAction::Redraw
+			}
+			_ => KeyAction::None,
+		}
+	}
+
+	/// The dashboard as lines of text, cut to fit the given size
+	pub fn render(&self, width: usize, height: usize) -> Vec<String> {
+		let mut lines = vec![
+			"Battery dashboard    q quit  r refresh  p pause  +/- graph range  ←/→ battery".to_string(),
+			String::new(),
+		];
+
+		match &self.latest {
+			None => lines.push(self.error.clone().unwrap_or_else(|| "Waiting for the first sample".to_string())),
+			Some((time, info)) => {
+				lines.push(field("Charge", &format!("{}%, {}", info.percentage, describe_power_state(info))));
+				lines.push(field("Rate", &format!("{}%/hour, {:.1}W ({:.1}W now)", info.discharge_rate_percent, info.power_draw_watts, info.instant_power_draw_watts)));
+				lines.push(field("Remaining", &describe_time_remaining(info)));
+				lines.push(field("Health", &match info.cycle_count {
+					Some(cycles) => format!("{}%, {} cycles", info.health_percent, cycles),
+					None => format!("{}%", info.health_percent),
+				}));
+				lines.push(String::new());
+
+				let (range, range_name) = GRAPH_RANGES[self.graph_range];
+				let columns = width.saturating_sub(LABEL_WIDTH).max(1);
+				let buckets = self.history.buckets(*time, range, columns);
+				lines.push(format!("History, last {}", range_name));
+				lines.push(field("Charge", &sparkline(&buckets, 100, |info| info.percentage)));
+				let peak_rate = buckets.iter().flatten().map(|info| info.discharge_rate_percent).max().unwrap_or(0).max(1);
+				lines.push(field("Drain", &sparkline(&buckets, peak_rate, |info| info.discharge_rate_percent)));
+				lines.push(field("Plugged in", &buckets.iter().map(|bucket| match bucket {
+					None => ' ',
+					Some(info) if info.is_charging => '+',
+					Some(_) => '-',
+				}).collect::<String>()));
+			}
+		}
+
+		if let Some(details) = self.details.get(self.selected_battery) {
+			lines.push(String::new());
+			lines.extend(render_details(details, self.selected_battery, self.details.len()));
+		}
+
+		lines.push(String::new());
+		let mut status = match &self.latest {
+			Some((time, _)) => format!("Updated {}", sleep_log::format_utc(*time)),
+			None => String::new(),
+		};
+		if self.paused {
+			status += " (paused)";
+		}
+		if let (Some(error), Some(_)) = (&self.error, &self.latest) {
+			status += &format!(", last sample failed: {}", error);
+		}
+		lines.push(status);
+
+		lines.truncate(height);
+		lines.into_iter().map(|line| line.chars().take(width).collect()).collect()
+	}
+}
+
+fn field(label: &str, value: &str) -> String {
+	format!("{:width$}{}", label, value, width = LABEL_WIDTH)
+}
+
+fn describe_power_state(info: &BatteryInfo) -> &'static str {
+	if !info.is_charging {
+		"discharging"
+	}
+	else if info.discharge_rate_percent > 0 {
+		"plugged in, still discharging"
+	}
+	else if info.percentage >= 100 {
+		"full"
+	}
+	else {
+		"charging"
+	}
+}
+
+fn describe_time_remaining(info: &BatteryInfo) -> String {
+	match info.time_remaining() {
+		None => "unknown".to_string(),
+		Some(time) if info.discharge_rate_percent > 0 => format!("{} until empty", sleep_log::format_duration(time)),
+		Some(time) => format!("{} until full", sleep_log::format_duration(time)),
+	}
+}
+
+fn render_details(details: &BatteryDetails, index: usize, count: usize) -> Vec<String> {
+	let name = [details.vendor.as_deref(), details.model.as_deref()].into_iter().flatten()
+		.filter(|s| !s.is_empty()).collect::<Vec<_>>().join(" ");
+	let mut lines = vec![
+		format!("Battery {} of {}: {} ({})", index + 1, count, if name.is_empty() { "unknown" } else { &name }, details.technology),
+		field("  State", &format!("{}, {}%", details.state, details.percentage)),
+		field("  Energy", &format!("{:.1} of {:.1} Wh, designed for {:.1} Wh", details.energy_wh, details.energy_full_wh, details.energy_full_design_wh)),
+		field("  Rate", &format!("{:.1} W", details.energy_rate_watts)),
+		field("  Voltage", &format!("{:.2} V", details.voltage)),
+		field("  Health", &format!("{}%", details.health_percent)),
+	];
+	if let Some(cycles) = details.cycle_count {
+		lines.push(field("  Cycles", &cycles.to_string()));
+	}
+	if let Some(temperature) = details.temperature_celsius {
+		lines.push(field("  Temperature", &format!("{:.1} °C", temperature)));
+	}
+	lines
+}
+
+/// One character per bucket scaled against `max`, blank where there's no sample or the value isn't positive
+pub fn sparkline(buckets: &[Option<BatteryInfo>], max: i32, value: impl Fn(&BatteryInfo) -> i32) -> String {
+	buckets.iter().map(|bucket| match bucket.as_ref().map(&value) {
+		Some(v) if v > 0 => {
+			let level = (v.min(max) as f32 / max as f32 * (SPARKLINE_LEVELS.len() - 1) as f32).round() as usize;
+			SPARKLINE_LEVELS[level]
+		}
+		_ => ' ',
+	}).collect()
+}
+
+// Puts the terminal in raw mode on the alternate screen, and restores it when dropped
+struct TerminalGuard;
+
+impl TerminalGuard {
+	fn enter() -> Result<Self, String> {
+		terminal::enable_raw_mode().map_err(|e| format!("Failed to set up the terminal: {:?}", e))?;
+		let guard = TerminalGuard;
+		execute!(std::io::stdout(), terminal::EnterAlternateScreen, cursor::Hide).map_err(|e| format!("Failed to set up the terminal: {:?}", e))?;
+		Ok(guard)
+	}
+}
+
+impl Drop for TerminalGuard {
+	fn drop(&mut self) {
+		let _ = execute!(std::io::stdout(), cursor::Show, terminal::LeaveAlternateScreen);
+		let _ = terminal::disable_raw_mode();
+	}
+}
+
+/// Show the dashboard until the user quits, reading the real battery or, for a demo, a simulated one
+pub fn run(demo: bool) -> Result<(), String> {
+	let config = Config::load();
+	let mut scheduler = SampleScheduler::new(false);
+	if demo {
+		scheduler.set_fixed_interval(Some(DEMO_SAMPLE_INTERVAL));
+		run_with(&mut SimulatedBattery::new(SystemTime::now()), scheduler)
+	}
+	else {
+		scheduler.set_fixed_interval(config.update_interval_seconds.map(Duration::from_secs));
+		run_with(&mut BatteryMonitor::new()?, scheduler)
+	}
+}
+
+fn run_with(source: &mut dyn BatterySource, mut scheduler: SampleScheduler) -> Result<(), String> {
+	let _guard = TerminalGuard::enter()?;
+	let mut dashboard = Dashboard::new();
+	let mut next_sample = Instant::now();
+
+	loop {
+		let now = Instant::now();
+		if now >= next_sample {
+			if !dashboard.is_paused() {
+				dashboard.update(source);
+			}
+			next_sample = now + scheduler.decide(dashboard.battery_info()).interval;
+			draw(&dashboard)?;
+		}
+
+		if !event::poll(next_sample.saturating_duration_since(now)).map_err(|e| format!("{:?}", e))? {
+			continue;
+		}
+		match event::read().map_err(|e| format!("{:?}", e))? {
+			// Windows reports key releases too
+			Event::Key(key) if key.kind != KeyEventKind::Release => match dashboard.handle_key(key) {
+				KeyAction::Quit => return Ok(()),
+				KeyAction::Refresh => {
+					dashboard.update(source);
+					draw(&dashboard)?;
+				}
+				KeyAction::Redraw => draw(&dashboard)?,
+				KeyAction::None => {}
+			},
+			Event::Resize(..) => draw(&dashboard)?,
+			_ => {}
+		}
+	}
+}
+
+fn draw(dashboard: &Dashboard) -> Result<(), String> {
+	let (width, height) = terminal::size().map_err(|e| format!("{:?}", e))?;
+	let mut stdout = std::io::stdout().lock();
+	queue!(stdout, terminal::Clear(terminal::ClearType::All)).map_err(|e| format!("{:?}", e))?;
+	for (row, line) in dashboard.render(width as usize, height as usize).iter().enumerate() {
+		queue!(stdout, cursor::MoveTo(0, row as u16), style::Print(line)).map_err(|e| format!("{:?}", e))?;
+	}
+	stdout.flush().map_err(|e| format!("{:?}", e))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn key(code: KeyCode) -> KeyEvent {
+		KeyEvent::new(code, KeyModifiers::NONE)
+	}
+
+	struct FailingSource;
+
+	impl BatterySource for FailingSource {
+		fn sample(&mut self) -> Result<(SystemTime, BatteryInfo), String> {
+			Err("No batteries found.".to_string())
+		}
+
+		fn details(&mut self) -> Result<Vec<BatteryDetails>, String> {
+			Ok(Vec::new())
+		}
+	}
+
+	#[test]
+	fn shows_the_simulated_battery() {
+		let mut source = SimulatedBattery::new(SystemTime::UNIX_EPOCH);
+		let mut dashboard = Dashboard::new();
+		assert!(dashboard.render(80, 40).contains(&"Waiting for the first sample".to_string()));
+
+		// Twelve hours of samples, enough to run down, plug in and charge
+		let mut plugged_in = false;
+		for _ in 0..144 {
+			dashboard.update(&mut source);
+			plugged_in |= dashboard.battery_info().is_some_and(|info| info.is_charging);
+		}
+		assert!(plugged_in);
+
+		let lines = dashboard.render(80, 40);
+		let info = dashboard.battery_info().unwrap();
+		assert!(lines.iter().any(|line| line.starts_with("Charge") && line.contains(&format!("{}%", info.percentage))));
+		assert!(lines.iter().any(|line| line.contains("Demo battery")));
+		assert!(lines.iter().all(|line| line.chars().count() <= 80));
+		assert_eq!(dashboard.render(80, 3).len(), 3);
+
+		// A failed sample keeps the last readings and says what went wrong
+		dashboard.update(&mut FailingSource);
+		let status = dashboard.render(80, 40).pop().unwrap();
+		assert!(status.contains("last sample failed: No batteries found."));
+		assert!(dashboard.battery_info().is_some());
+	}
+
+	#[test]
+	fn handles_keys() {
+		let mut dashboard = Dashboard::new();
+		dashboard.update(&mut SimulatedBattery::new(SystemTime::UNIX_EPOCH));
+
+		assert_eq!(dashboard.handle_key(key(KeyCode::Char('q'))), KeyAction::Quit);
+		assert_eq!(dashboard.handle_key(KeyEvent::new(KeyCode::Char('c'), KeyModifiers::CONTROL)), KeyAction::Quit);
+		assert_eq!(dashboard.handle_key(key(KeyCode::Char('r'))), KeyAction::Refresh);
+		assert_eq!(dashboard.handle_key(key(KeyCode::Char('x'))), KeyAction::None);
+
+		assert_eq!(dashboard.handle_key(key(KeyCode::Char('p'))), KeyAction::Redraw);
+		assert!(dashboard.is_paused());
+		assert!(dashboard.render(80, 40).last().unwrap().ends_with("(paused)"));
+		dashboard.handle_key(key(KeyCode::Char(' ')));
+		assert!(!dashboard.is_paused());
+
+		// The graph range stops at either end
+		for _ in 0..GRAPH_RANGES.len() + 1 {
+			dashboard.handle_key(key(KeyCode::Char('+')));
+		}
+		assert!(dashboard.render(80, 40).contains(&"History, last 24 hours".to_string()));
+		for _ in 0..GRAPH_RANGES.len() + 1 {
+			dashboard.handle_key(key(KeyCode::Char('-')));
+		}
+		assert!(dashboard.render(80, 40).contains(&"History, last 1 hour".to_string()));
+
+		// With one battery, switching batteries stays on it
+		dashboard.handle_key(key(KeyCode::Right));
+		dashboard.handle_key(key(KeyCode::Left));
+		dashboard.handle_key(key(KeyCode::Left));
+		assert!(dashboard.render(80, 40).iter().any(|line| line.contains("Demo battery")));
+	}
+}